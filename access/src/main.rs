@@ -1,5 +1,6 @@
 use macroquad::prelude::*;
 // use macroquad::input::KeyCode;
+use mappy::config::MappyConfig;
 use mappy::MappyState;
 use retro_rs::{Buttons, Emulator};
 use std::cell::RefCell;
@@ -27,9 +28,11 @@ fn replay(emu: &mut Emulator, mappy: &mut MappyState, inputs: &[[Buttons; 2]]) {
     let start = Instant::now();
     for (frames, inp) in inputs.iter().enumerate() {
         emu.run(*inp);
-        mappy.process_screen(emu, *inp);
+        mappy
+            .process_screen(emu, *inp)
+            .expect("Couldn't process frame");
         if frames % 300 == 0 {
-            println!("Scroll: {:?} : {:?}", mappy.splits, mappy.scroll);
+            println!("Scroll: {:?} : {:?}", mappy.bands, mappy.scroll);
             println!("Known tiles: {:?}", mappy.tiles.read().unwrap().gfx_count());
             println!(
                 "Net: {:} for {:} inputs, avg {:}",
@@ -54,6 +57,9 @@ struct Arguments {
     /// path to a Python filter module
     #[argh(option)]
     filter: Option<PathBuf>,
+    /// path to a TOML or JSON mappy config
+    #[argh(option)]
+    config: Option<PathBuf>,
 }
 
 #[macroquad::main(window_conf)]
@@ -71,6 +77,7 @@ async fn main() {
     // "mario3"
     let romname = romfile.file_stem().expect("No file name!");
 
+    let rom_hash = mappy::profile::RomHash::from_file(&romfile).ok();
     let emu = Rc::new(RefCell::new(Emulator::create(
         Path::new("cores/fceumm_libretro"),
         &romfile,
@@ -101,9 +108,17 @@ async fn main() {
     let speeds: [usize; 10] = [0, 1, 5, 15, 30, 60, 120, 240, 300, 360];
     let mut speed: usize = 5;
     let mut accum: f32 = 0.0;
-    let mappy = Rc::new(RefCell::new(MappyState::new(w, h)));
+    let config = args
+        .config
+        .as_deref()
+        .map_or_else(MappyConfig::default, |path| {
+            MappyConfig::load(path).expect("Couldn't load config")
+        });
+    let mappy = Rc::new(RefCell::new(
+        MappyState::builder(w, h).config(config).build(),
+    ));
     if let Some(replayfile) = args.replay {
-        mappy::read_fm2(&mut replay_inputs, &replayfile);
+        mappy::read_fm2(&mut replay_inputs, &replayfile).expect("Couldn't read replay file");
         replay(
             &mut emu.borrow_mut(),
             &mut mappy.borrow_mut(),
@@ -188,15 +203,24 @@ zxcvbnm,./ for debug displays"
                 n
             ));
             if shifted {
-                mappy::write_fm2(&inputs, &path);
-                println!("Dumped {n}");
+                match mappy::write_fm2(
+                    &inputs,
+                    romname.to_str().expect("rom name not a valid utf-8 string"),
+                    rom_hash.as_ref(),
+                    &path,
+                ) {
+                    Ok(()) => println!("Dumped {n}"),
+                    Err(e) => println!("Couldn't dump {n}: {e}"),
+                }
             } else {
                 assert!(emu.borrow_mut().load(&start_state));
                 mappy.borrow_mut().handle_reset();
                 frame_counter = 0;
                 inputs.clear();
                 replay_inputs.clear();
-                mappy::read_fm2(&mut replay_inputs, &path);
+                if let Err(e) = mappy::read_fm2(&mut replay_inputs, &path) {
+                    println!("Couldn't load {n}: {e}");
+                }
                 replay_index = 0;
             }
         }
@@ -254,7 +278,8 @@ zxcvbnm,./ for debug displays"
             // wait for sprite updates...
             mappy
                 .borrow_mut()
-                .process_screen(&mut emu.borrow_mut(), inputs.last().copied().unwrap());
+                .process_screen(&mut *emu.borrow_mut(), inputs.last().copied().unwrap())
+                .expect("Couldn't process frame");
             // then filter
             if accum < 2.0 {
                 if let Some(filter_mod) = &filter {
@@ -292,7 +317,7 @@ zxcvbnm,./ for debug displays"
             }
             frame_counter += 1;
             if frame_counter % 300 == 0 {
-                // println!("Scroll: {:?} : {:?}", mappy.splits, mappy.scroll);
+                // println!("Scroll: {:?} : {:?}", mappy.bands, mappy.scroll);
                 // println!("Known tiles: {:?}", mappy.tiles.gfx_count());
                 println!(
                     "Net: {:} for {:} inputs, avg {:}",
@@ -383,8 +408,17 @@ zxcvbnm,./ for debug displays"
         }
         next_frame().await;
     }
-    mappy.borrow_mut().finish();
     println!("{}", mappy.borrow().timers);
+    // The filter module may still hold mappy handles of its own
+    drop(filter);
+    let mappy = Rc::try_unwrap(mappy).map_or_else(
+        |shared| {
+            println!("mappy is still shared with the filter, finishing it in place");
+            shared.replace(MappyState::builder(w, h).build())
+        },
+        RefCell::into_inner,
+    );
+    let _map = mappy.finish();
 }
 
 #[allow(clippy::cast_possible_truncation)]
@@ -1,53 +1,296 @@
-use mappy::MappyState;
+use mappy::config::{ControlDetectorKind, MappyConfig};
+use mappy::control::{self, ControlDetector, ParallelProbeDetector, ProbeWorker};
+use mappy::map::SavedMap;
+use mappy::profile::{ProfileDB, RomHash};
+use mappy::trace::{TracePlayer, TraceRecorder};
+use mappy::{FinalizedMap, FrameSource, MappyState};
 use retro_rs::{Buttons, Emulator, FramebufferToImageBuffer};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
 use std::path::Path;
+use std::process::Command;
 use std::time::Instant;
-#[allow(clippy::cast_precision_loss)]
+const USAGE: &str = "usage:
+    batch rom.nes [--config cfg.toml] [--profiles profiles.toml] [--map in.map] [--save-map out.map] [--events out.log] [--probe-workers N] [--record out.trace | --jobs N] [--no-dump] inputs.fm2...
+    batch --trace in.trace [--config cfg.toml] [--profiles profiles.toml] [--map in.map] [--save-map out.map] [--events out.log] [--no-dump]";
+#[allow(clippy::cast_precision_loss, clippy::too_many_lines)]
 fn main() {
     use std::env;
-    let args: Vec<_> = env::args().collect();
-    let mut emu = Emulator::create(
-        Path::new("cores/fceumm_libretro"),
-        Path::new(args[1].as_str()),
+    let mut args: Vec<_> = env::args().collect();
+    if args.get(1).is_some_and(|a| a == "--probe-worker") {
+        // Started by --probe-workers below to probe on an emulator of its own
+        let Some(rom) = args.get(2) else {
+            eprintln!("--probe-worker needs a rom");
+            std::process::exit(2);
+        };
+        let mut emu = Emulator::create(Path::new("cores/fceumm_libretro"), Path::new(rom));
+        control::serve_probes(&mut emu, std::io::stdin().lock(), std::io::stdout().lock())
+            .expect("Probe worker failed");
+        return;
+    }
+    let mut take_option = |name: &str| {
+        let flag = args.iter().position(|a| a == name)?;
+        let Some(value) = args.get(flag + 1).cloned() else {
+            eprintln!("{name} needs a value\n{USAGE}");
+            std::process::exit(2);
+        };
+        args.drain(flag..=flag + 1);
+        Some(value)
+    };
+    let config_path = take_option("--config");
+    let mut config = config_path
+        .as_deref()
+        .map_or_else(MappyConfig::default, |path| {
+            MappyConfig::load(Path::new(path)).expect("Couldn't load config")
+        });
+    let profiles_path = take_option("--profiles").unwrap_or_else(|| "profiles.toml".to_string());
+    let profiles =
+        ProfileDB::load_if_present(Path::new(&profiles_path)).expect("Couldn't load profiles");
+    let record = take_option("--record");
+    let trace = take_option("--trace");
+    // Extend a map saved by an earlier run instead of starting from scratch
+    let resume_map = take_option("--map").map(|path| {
+        SavedMap::load(Path::new(&path))
+            .and_then(SavedMap::restore)
+            .expect("Couldn't load map")
+    });
+    let save_map = take_option("--save-map");
+    let events = take_option("--events");
+    // Control probes run on this many extra emulators; 0 probes on the main one
+    let probe_workers: usize =
+        take_option("--probe-workers").map_or(0, |n| n.parse().expect("Invalid --probe-workers"));
+    let jobs: usize = take_option("--jobs").map_or(1, |n| n.parse().expect("Invalid --jobs"));
+    assert!(
+        jobs <= 1 || record.is_none(),
+        "--record needs a single job, since it writes one trace"
     );
-    let mut start_state = vec![0; emu.save_size()];
-    assert!(emu.save(&mut start_state));
+    let dump = match args.iter().position(|a| a == "--no-dump") {
+        Some(flag) => {
+            args.remove(flag);
+            false
+        }
+        None => true,
+    };
 
-    // Have to run emu for one frame before we can get the framebuffer size
-    emu.run([Buttons::new(), Buttons::new()]);
-    let (w, h) = emu.framebuffer_size();
-    let mut mappy = MappyState::new(w, h);
     let start = Instant::now();
     let mut all_inputs = 0;
-    for (file_i, file) in args[2..].iter().enumerate() {
-        // So reset it afterwards
-        assert!(emu.load(&start_state));
-        mappy.handle_reset();
-        let mut inputs = vec![];
-        mappy::read_fm2(&mut inputs, Path::new(file.as_str()));
-        all_inputs += inputs.len();
-        for input_pair in &inputs {
-            emu.run(*input_pair);
-            mappy.process_screen(&mut emu, *input_pair);
+    let mappy = if let Some(trace) = trace {
+        // No emulator needed, everything mappy reads is in the trace
+        let file = File::open(&trace).expect("Couldn't open trace");
+        let mut player = TracePlayer::new(BufReader::new(file)).expect("Couldn't read trace");
+        if let Some(profile) = player.rom_hash().and_then(|hash| profiles.lookup(hash)) {
+            println!("Using profile {}", profile.name);
+            profile.apply(&mut config);
+            config.validate().expect("Invalid profile");
         }
-        let fb = emu.create_imagebuffer();
-        fb.unwrap().save(format!("out/out_{file_i}.png")).unwrap();
-    }
+        let (w, h) = player.framebuffer_size();
+        let mut mappy = build_mappy(w, h, config, resume_map, events.as_deref(), None);
+        all_inputs += player.replay(&mut mappy).expect("Couldn't replay trace");
+        mappy
+    } else {
+        let rom_hash = RomHash::from_file(Path::new(args[1].as_str())).expect("Couldn't read rom");
+        if let Some(profile) = profiles.lookup(&rom_hash) {
+            println!("Using profile {}", profile.name);
+            profile.apply(&mut config);
+            config.validate().expect("Invalid profile");
+        }
+        let mut emu = Emulator::create(
+            Path::new("cores/fceumm_libretro"),
+            Path::new(args[1].as_str()),
+        );
+        let mut start_state = vec![0; emu.save_size()];
+        assert!(emu.save(&mut start_state));
+
+        // Have to run emu for one frame before we can get the framebuffer size
+        emu.run([Buttons::new(), Buttons::new()]);
+        let (w, h) = emu.framebuffer_size();
+        let fm2s: Vec<_> = args[2..]
+            .iter()
+            .map(|file| {
+                let mut inputs = vec![];
+                mappy::read_fm2(&mut inputs, Path::new(file.as_str()))
+                    .expect("Couldn't read inputs");
+                all_inputs += inputs.len();
+                inputs
+            })
+            .collect();
+        if jobs > 1 {
+            // Map each input file in its own process, then combine the maps
+            let mut worker_args = vec!["--profiles".to_string(), profiles_path];
+            if let Some(path) = config_path {
+                worker_args.extend(["--config".to_string(), path]);
+            }
+            worker_args.extend(["--probe-workers".to_string(), probe_workers.to_string()]);
+            let map = resume_map
+                .into_iter()
+                .chain(map_in_parallel(
+                    &args[1],
+                    &args[2..],
+                    jobs,
+                    &worker_args,
+                    events.as_deref(),
+                ))
+                .reduce(|a, b| a.union(b, &config));
+            build_mappy(w, h, config, map, events.as_deref(), None)
+        } else {
+            let detector = (probe_workers > 0
+                && config.control_detector == ControlDetectorKind::Probe)
+                .then(|| {
+                    // Each probe emulator gets a process, since the core's state is global
+                    let exe = std::env::current_exe().expect("Couldn't find batch executable");
+                    let workers = (0..probe_workers)
+                        .map(|_| {
+                            let mut worker = Command::new(&exe);
+                            worker.arg("--probe-worker").arg(&args[1]);
+                            ProbeWorker::spawn(worker).expect("Couldn't start probe worker")
+                        })
+                        .collect();
+                    Box::new(ParallelProbeDetector::new(&config, workers))
+                        as Box<dyn ControlDetector>
+                });
+            let mut mappy = build_mappy(w, h, config, resume_map, events.as_deref(), detector);
+            if let Some(record) = record {
+                let out = BufWriter::new(File::create(record).expect("Couldn't create trace"));
+                let mut recorder = TraceRecorder::new(&mut emu, out, Some(&rom_hash))
+                    .expect("Couldn't write trace");
+                for (file_i, inputs) in fm2s.iter().enumerate() {
+                    // So reset it afterwards
+                    assert!(recorder.source_mut().load(&start_state));
+                    recorder.handle_reset(&mut mappy);
+                    for input_pair in inputs {
+                        recorder
+                            .step(&mut mappy, *input_pair)
+                            .expect("Couldn't write trace");
+                    }
+                    if dump {
+                        let fb = recorder.source().create_imagebuffer();
+                        fb.unwrap().save(format!("out/out_{file_i}.png")).unwrap();
+                    }
+                }
+                recorder.finish().expect("Couldn't finish trace");
+            } else {
+                for (file_i, inputs) in fm2s.iter().enumerate() {
+                    // So reset it afterwards
+                    assert!(emu.load(&start_state));
+                    mappy.handle_reset();
+                    for input_pair in inputs {
+                        emu.run(*input_pair);
+                        mappy
+                            .process_screen(&mut emu, *input_pair)
+                            .expect("Couldn't process frame");
+                    }
+                    if dump {
+                        let fb = emu.create_imagebuffer();
+                        fb.unwrap().save(format!("out/out_{file_i}.png")).unwrap();
+                    }
+                }
+            }
+            mappy
+        }
+    };
 
     // mappy.dump_current_room(Path::new("out/current_room.png"));
 
-    mappy.finish();
+    println!("{}", mappy.timers);
+    let min_margin = mappy.config.merge_min_margin;
+    let map = mappy.finish();
 
-    println!(
-        "Known tiles: {:?}",
-        mappy.tiles.read().unwrap().tile_stats()
-    );
+    println!("Known tiles: {:?}", map.tiles().tile_stats());
+    for (into, from, confidence) in map.close_calls(min_margin) {
+        println!("Close call merging {from:?} into {into:?}: {confidence:?}");
+    }
     println!("Emulation only: 7.62524203s for 34,514 inputs, avg 0.000220 per frame");
     println!(
         "Net: {:} for {all_inputs:} inputs, avg {:} per frame",
         start.elapsed().as_secs_f64(),
         start.elapsed().as_secs_f64() / (all_inputs as f64)
     );
-    println!("{}", mappy.timers);
-    mappy.dump_map(Path::new("out/"));
+    if dump {
+        map.dump_map(Path::new("out/")).expect("Couldn't dump map");
+    }
+    if let Some(path) = save_map {
+        SavedMap::from_map(&map)
+            .save(Path::new(&path))
+            .expect("Couldn't save map");
+    }
+}
+
+fn build_mappy(
+    w: usize,
+    h: usize,
+    config: MappyConfig,
+    map: Option<FinalizedMap>,
+    events: Option<&str>,
+    control_detector: Option<Box<dyn ControlDetector>>,
+) -> MappyState {
+    let mut builder = MappyState::builder(w, h).config(config);
+    if let Some(detector) = control_detector {
+        builder = builder.control_detector(detector);
+    }
+    let mut mappy = match map {
+        Some(map) => builder.resume(map),
+        None => builder,
+    }
+    .build();
+    if let Some(path) = events {
+        // One line per event, flushed when mappy is dropped
+        let mut out = BufWriter::new(File::create(path).expect("Couldn't create event log"));
+        mappy.subscribe(move |e| {
+            writeln!(out, "{} {:?}", *e.time, e.kind).expect("Couldn't write event log");
+        });
+    }
+    mappy
+}
+
+/// Runs one `batch` worker process per input file, at most `jobs` at a time,
+/// and returns the maps they saved in input file order.  With `events`, the
+/// worker for input file `i` logs its events to `events.i`.
+fn map_in_parallel(
+    rom: &str,
+    fm2s: &[String],
+    jobs: usize,
+    worker_args: &[String],
+    events: Option<&str>,
+) -> Vec<FinalizedMap> {
+    let exe = std::env::current_exe().expect("Couldn't find batch executable");
+    let mut maps = Vec::with_capacity(fm2s.len());
+    for (chunk_i, chunk) in fm2s.chunks(jobs).enumerate() {
+        let workers: Vec<_> = chunk
+            .iter()
+            .enumerate()
+            .map(|(i, fm2)| {
+                let part = std::env::temp_dir().join(format!(
+                    "mappy-batch-{}-{}.map",
+                    std::process::id(),
+                    chunk_i * jobs + i
+                ));
+                let mut worker = Command::new(&exe);
+                worker.arg(rom).args(worker_args);
+                if let Some(events) = events {
+                    worker
+                        .arg("--events")
+                        .arg(format!("{events}.{}", chunk_i * jobs + i));
+                }
+                let child = worker
+                    .arg("--no-dump")
+                    .arg("--save-map")
+                    .arg(&part)
+                    .arg(fm2)
+                    .spawn()
+                    .expect("Couldn't start batch worker");
+                (child, part)
+            })
+            .collect();
+        for (mut child, part) in workers {
+            let status = child.wait().expect("Couldn't wait for batch worker");
+            assert!(status.success(), "batch worker failed: {status}");
+            maps.push(
+                SavedMap::load(&part)
+                    .and_then(SavedMap::restore)
+                    .expect("Couldn't load worker's map"),
+            );
+            let _ = std::fs::remove_file(&part);
+        }
+    }
+    maps
 }
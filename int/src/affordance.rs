@@ -3,6 +3,7 @@ use imageproc::drawing::Canvas;
 use macroquad::prelude::*;
 use mappy::{
     sprites::{SpriteData, SpriteTrack},
+    tile::{TileDB, TileGfx, TILE_NUM_PX},
     MappyState, TILE_SIZE,
 };
 // use palette::{Darken, Hsv};
@@ -71,25 +72,32 @@ struct ModulateSettings {
     no_affordance_saturation_change: f32,
 }
 
+// Tiles are known by their exact rgb332 pixels
+type TilePixels = [u8; TILE_NUM_PX];
+
+// Version 0 files (which have no version) key tiles by the sum of their
+// pixels, which lots of different tiles share; version 1 keys them by their
+// pixels in hex, and keeps any version 0 keys it hasn't matched yet
+const AFFORDANCE_VERSION: u32 = 1;
+
 #[derive(serde::Serialize, serde::Deserialize)]
 struct AffordanceMaps {
-    tiles: HashMap<u128, Affordance>,
+    #[serde(default)]
+    version: u32,
+    tiles: HashMap<String, Affordance>,
     sprites: HashMap<u32, Affordance>,
-}
-impl AffordanceMaps {
-    #[allow(dead_code)]
-    fn new(tile: HashMap<u128, Affordance>, sprite: HashMap<u32, Affordance>) -> Self {
-        Self {
-            tiles: tile,
-            sprites: sprite,
-        }
-    }
+    #[serde(default)]
+    legacy_tiles: HashMap<u128, Affordance>,
 }
 //are the ratios a saturation? an importence? what is it a ratio to?
-#[derive(serde::Serialize, serde::Deserialize)]
 pub struct AffordanceTracker {
-    tiles: HashMap<u128, Affordance>, //can map a specific game tile to an affordance (?)
-    sprites: HashMap<u32, Affordance>, //can map a specific sprite to an affordance (?)
+    tiles: HashMap<TilePixels, Affordance>, //can map a specific game tile to an affordance (?)
+    sprites: HashMap<u32, Affordance>,      //can map a specific sprite to an affordance (?)
+    // Tiles from a version 0 file, by pixel sum, handed on to every tile
+    // with that sum as they turn up
+    legacy_tiles: HashMap<u128, Affordance>,
+    // How many of mappy's tiles have been checked against `legacy_tiles`
+    legacy_checked: usize,
 
     brush: AffordanceMask,
     settings: ModulateSettings,
@@ -99,6 +107,8 @@ impl AffordanceTracker {
         Self {
             tiles: HashMap::with_capacity(10_000),
             sprites: HashMap::with_capacity(10_000),
+            legacy_tiles: HashMap::new(),
+            legacy_checked: 0,
             brush: AffordanceMask::empty(),
             settings: ModulateSettings {
                 avatar_ratio: 1.0,
@@ -121,8 +131,41 @@ impl AffordanceTracker {
         let temp: AffordanceMaps =
             serde_json::from_str(&fs::read_to_string(path).expect("couldn't find affordance file"))
                 .unwrap();
+        assert!(
+            temp.version <= AFFORDANCE_VERSION,
+            "affordance file is from a newer version of int"
+        );
         self.sprites = temp.sprites;
-        self.tiles = temp.tiles;
+        self.legacy_tiles = temp.legacy_tiles;
+        self.legacy_checked = 0;
+        self.tiles.clear();
+        for (key, affordance) in temp.tiles {
+            if temp.version == 0 {
+                let sum = key.parse().expect("bad tile key in affordance file");
+                self.legacy_tiles.insert(sum, affordance);
+            } else {
+                let pixels = pixels_from_hex(&key).expect("bad tile key in affordance file");
+                self.tiles.insert(pixels, affordance);
+            }
+        }
+    }
+
+    /// Gives tiles mappy has found since the last call the affordances a
+    /// version 0 file had for their pixel sum.
+    fn adopt_legacy(&mut self, tiles: &TileDB) {
+        if self.legacy_tiles.is_empty() {
+            return;
+        }
+        if tiles.gfx_count() < self.legacy_checked {
+            // mappy started over
+            self.legacy_checked = 0;
+        }
+        for gfx in tiles.gfx_iter().skip(self.legacy_checked) {
+            if let Some(&affordance) = self.legacy_tiles.get(&legacy_hash(gfx)) {
+                self.tiles.entry(gfx.0).or_insert(affordance);
+            }
+        }
+        self.legacy_checked = tiles.gfx_count();
     }
 
     fn draw_brush_display(&self) {
@@ -245,8 +288,14 @@ impl AffordanceTracker {
         let file: File = File::create(path).expect("Couldn't create affordance save file!");
 
         let temp: AffordanceMaps = AffordanceMaps {
-            tiles: self.tiles.clone(),
+            version: AFFORDANCE_VERSION,
+            tiles: self
+                .tiles
+                .iter()
+                .map(|(pixels, &affordance)| (pixels_to_hex(pixels), affordance))
+                .collect(),
             sprites: self.sprites.clone(),
+            legacy_tiles: self.legacy_tiles.clone(),
         };
         let _ = serde_json::to_writer(file, &temp);
     }
@@ -257,6 +306,7 @@ impl AffordanceTracker {
         clippy::cast_sign_loss
     )]
     pub fn update(&mut self, mappy: &MappyState, _emu: &Emulator) {
+        self.adopt_legacy(&mappy.tiles.read().unwrap());
         self.update_brush();
         // left click to grant, right click to copy affordances
         // shift right click to cut and erase (propagating to guesses)
@@ -309,9 +359,7 @@ impl AffordanceTracker {
                     let change_data = tiles.get_change_by_id(change);
                     if let Some(cd) = change_data {
                         let to = cd.to;
-                        tiles
-                            .get_tile_by_id(to)
-                            .map(mappy::tile::TileGfx::perceptual_hash)
+                        tiles.get_tile_by_id(to).map(|gfx| gfx.0)
                     } else {
                         None
                     }
@@ -410,6 +458,7 @@ impl AffordanceTracker {
         use imageproc::drawing as d;
         //Rendering: for now, desaturate/reduce contrast of ones with no affordances, tint danger red, make solid high contrast, make avatar green, tint usable/breakable/portal/etc blue.
         let tiles = mappy.tiles.read().unwrap();
+        self.adopt_legacy(&tiles);
         let region = mappy.split_region();
         let sr = mappy.current_screen.region;
         let in_img: image::ImageBuffer<image::Rgba<u8>, &[u8]> = image::ImageBuffer::from_raw(
@@ -441,7 +490,7 @@ impl AffordanceTracker {
                     .and_then(|tile| tiles.get_change_by_id(tile))
                     .and_then(|change| tiles.get_tile_by_id(change.to))
                 {
-                    match self.tiles.get(&gfx.perceptual_hash()) {
+                    match self.tiles.get(&gfx.0) {
                         None => {
                             // todo, highlight un-known nature
                         }
@@ -812,7 +861,26 @@ fn emphasize_saturation<I: image::GenericImage<Pixel = image::Rgba<u8>>>(
     );
 }
 
-#[allow(clippy::cast_sign_loss)]
+fn pixels_to_hex(pixels: &TilePixels) -> String {
+    pixels.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn pixels_from_hex(hex: &str) -> Option<TilePixels> {
+    if hex.len() != TILE_NUM_PX * 2 {
+        return None;
+    }
+    let mut pixels = [0; TILE_NUM_PX];
+    for (i, px) in pixels.iter_mut().enumerate() {
+        *px = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(pixels)
+}
+
+// What version 0 files keyed tiles by
+fn legacy_hash(gfx: &TileGfx) -> u128 {
+    gfx.0.iter().map(|&b| u128::from(b)).sum()
+}
+
 fn sprite_guesses(mappy: &MappyState, track: &SpriteTrack) -> impl Iterator<Item = u32> {
     // all sprites on every track of every blob including track
     let mut set: HashSet<_> = track.positions.iter().map(|d| d.2.key()).collect();
@@ -1,5 +1,9 @@
 use macroquad::prelude::*;
-use mappy::{MappyState, TILE_SIZE};
+use mappy::config::{ControlDetectorKind, MappyConfig};
+use mappy::control::{ParallelProbeDetector, ProbeWorker};
+use mappy::map::SavedMap;
+use mappy::profile::{ProfileDB, RomHash};
+use mappy::{FinalizedMap, MappyState, TILE_SIZE};
 use retro_rs::{Buttons, Emulator};
 use std::io::{Read, Write};
 use std::path::Path;
@@ -32,7 +36,9 @@ fn replay(
 ) {
     for inp in inputs {
         emu.run(*inp);
-        mappy.process_screen(emu, *inp);
+        mappy
+            .process_screen(emu, *inp)
+            .expect("Couldn't process frame");
         if let Some(scroll) = scroll {
             scroll.update(mappy, emu);
         }
@@ -44,6 +50,18 @@ fn replay(
 struct Cli {
     rom: std::path::PathBuf,
     affordance: Option<std::path::PathBuf>,
+    /// TOML or JSON file overriding mappy's default heuristics
+    #[arg(long)]
+    config: Option<std::path::PathBuf>,
+    /// per-game profiles, looked up by the ROM's hash
+    #[arg(long, default_value = "profiles.toml")]
+    profiles: std::path::PathBuf,
+    /// saved map to keep extending; it's loaded if it exists and written back on exit
+    #[arg(long)]
+    map: Option<std::path::PathBuf>,
+    /// run control probes on this many extra emulators (each a batch --probe-worker process) instead of the one on screen
+    #[arg(long, default_value_t = 0)]
+    probe_workers: usize,
 }
 
 #[macroquad::main(window_conf)]
@@ -53,9 +71,7 @@ async fn main() {
         clippy::cast_possible_truncation,
         clippy::cast_precision_loss
     )]
-    use std::env;
     std::fs::create_dir_all("out").unwrap_or(());
-    let args: Vec<_> = env::args().collect();
 
     let file_args = Cli::parse();
     //let romfile = Path::new(args[1].as_str()); //gamefile
@@ -150,9 +166,50 @@ async fn main() {
 
     let mut playback = playback::Playback::new(); //does this just mean game play???
 
-    let mut mappy = MappyState::new(w, h);
-    if args.len() > 2 {
-        mappy::read_fm2(&mut playback.replay_inputs, Path::new(&args[2]));
+    let mut config = file_args
+        .config
+        .as_deref()
+        .map_or_else(MappyConfig::default, |path| {
+            MappyConfig::load(path).expect("Couldn't load config")
+        });
+    let rom_hash = RomHash::from_file(romfile).expect("Couldn't read rom");
+    let profiles = ProfileDB::load_if_present(&file_args.profiles).expect("Couldn't load profiles");
+    if let Some(profile) = profiles.lookup(&rom_hash) {
+        println!("Using profile {}", profile.name);
+        profile.apply(&mut config);
+        config.validate().expect("Invalid profile");
+    }
+    let probe_on_the_side =
+        file_args.probe_workers > 0 && config.control_detector == ControlDetectorKind::Probe;
+    let mut builder = MappyState::builder(w, h).config(config.clone());
+    if probe_on_the_side {
+        // Probe on other emulators so the one on screen never leaves its frame.  They
+        // run in batch worker processes, since the core's state is global and int
+        // can't start without opening a window.
+        let batch = std::env::current_exe()
+            .expect("Couldn't find int executable")
+            .with_file_name(format!("batch{}", std::env::consts::EXE_SUFFIX));
+        let workers = (0..file_args.probe_workers)
+            .map(|_| {
+                let mut worker = std::process::Command::new(&batch);
+                worker.arg("--probe-worker").arg(romfile);
+                ProbeWorker::spawn(worker).expect("Couldn't start batch --probe-worker")
+            })
+            .collect();
+        builder = builder.control_detector(Box::new(ParallelProbeDetector::new(&config, workers)));
+    }
+    if let Some(map) = file_args.map.as_deref().filter(|map| map.exists()) {
+        let map = SavedMap::load(map)
+            .and_then(SavedMap::restore)
+            .expect("Couldn't load map");
+        println!("Resuming map with {} rooms", map.rooms().len());
+        builder = builder.resume(map);
+    }
+    let mut mappy = builder.build();
+    // the second positional argument doubles as a replay file
+    if let Some(replay_file) = file_args.affordance.as_deref() {
+        mappy::read_fm2(&mut playback.replay_inputs, replay_file)
+            .expect("Couldn't read replay file");
         replay(
             &mut emu,
             &mut mappy,
@@ -189,7 +246,7 @@ zxcvbnm,./ for debug displays"
         //k: a (jump)
         playback.update_speed();
         if is_key_pressed(KeyCode::N) {
-            dump_mappy_map(romname.to_str().unwrap(), &mappy);
+            dump_mappy_map(romname.to_str().unwrap(), &mappy.snapshot());
         }
         // if is_key_pressed(KeyCode::M) {
         //      std::fs::remove_dir_all("out/rooms").unwrap_or(());
@@ -205,12 +262,19 @@ zxcvbnm,./ for debug displays"
                 n
             ));
             if shifted {
-                mappy::write_fm2(&playback.inputs, &path);
-                println!("Dumped {n}");
+                match mappy::write_fm2(
+                    &playback.inputs,
+                    romname.to_str().expect("rom name not a valid utf-8 string"),
+                    Some(&rom_hash),
+                    &path,
+                ) {
+                    Ok(()) => println!("Dumped {n}"),
+                    Err(e) => println!("Couldn't dump {n}: {e}"),
+                }
             } else {
                 // TODO clear mappy too?
                 if let Some(dump) = scroll_dumper.take() {
-                    dump.finish(&playback.inputs);
+                    dump.finish(&playback.inputs, Some(&rom_hash));
                 }
                 scroll_dumper = None; /*Some(scroll::ScrollDumper::new(
                                           Path::new("scroll_data/"),
@@ -270,19 +334,20 @@ zxcvbnm,./ for debug displays"
         // f/s * s = how many frames
         playback.step(get_frame_time(), |remaining_acc, input| {
             emu.run(input);
-            // must do this before mappy processes the screen,
-            // since mappy messes with the framebuffer/emulation state.
             // later, will need an early and late update?
             if let Some(dump) = scroll_dumper.as_mut() {
                 dump.update(&mappy, &emu);
             }
             if remaining_acc < 2.0 {
-                // must do this here since mappy causes saves and loads, and that messes with emu's framebuffer (not updated on a load)
+                // Detectors that probe on this emulator (rather than on the
+                // side) leave its framebuffer showing a probe frame
                 emu.copy_framebuffer_rgba8888(&mut fb)
                     .expect("Couldn't copy emulator framebuffer");
                 game_img.bytes.copy_from_slice(&fb);
             }
-            mappy.process_screen(&mut emu, input);
+            mappy
+                .process_screen(&mut emu, input)
+                .expect("Couldn't process frame");
         });
         affordances.update(&mappy, &emu); //affordances updated, this adds to the game record? or just checks for inputs?
 
@@ -310,10 +375,15 @@ zxcvbnm,./ for debug displays"
 
         next_frame().await;
     }
-    mappy.finish();
     println!("{}", mappy.timers);
+    let finished = mappy.finish();
+    if let Some(map) = file_args.map.as_deref() {
+        SavedMap::from_map(&finished)
+            .save(map)
+            .expect("Couldn't save map");
+    }
     if let Some(dump) = scroll_dumper.take() {
-        dump.finish(&playback.inputs);
+        dump.finish(&playback.inputs, Some(&rom_hash));
     }
     //mappy.dump_tiles(Path::new("out/"));
 }
@@ -330,8 +400,11 @@ fn tile_to_screen((x, y): (i32, i32), mappy: &MappyState) -> (f32, f32) {
     (x as f32 * SCALE, y as f32 * SCALE)
 }
 
-fn dump_mappy_map(romname: &str, mappy: &MappyState) {
-    mappy.dump_map(Path::new("out/"));
+fn dump_mappy_map(romname: &str, map: &FinalizedMap) {
+    if let Err(e) = map.dump_map(Path::new("out/")) {
+        println!("Couldn't dump map: {e}");
+        return;
+    }
     {
         use std::process::Command;
         let image = Command::new("dot")
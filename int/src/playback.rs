@@ -47,7 +47,9 @@ impl Playback {
         self.frame = 0;
         self.inputs.clear();
         self.replay_inputs.clear();
-        mappy::read_fm2(&mut self.replay_inputs, path);
+        if let Err(e) = mappy::read_fm2(&mut self.replay_inputs, path) {
+            println!("Couldn't load {}: {e}", path.display());
+        }
         self.replay_index = 0;
     }
     #[allow(clippy::cast_precision_loss)]
@@ -77,7 +79,7 @@ impl Playback {
             f(self.accum, *self.inputs.last().unwrap());
             self.frame += 1;
             if self.frame % 300 == 0 {
-                // println!("Scroll: {:?} : {:?}", mappy.splits, mappy.scroll);
+                // println!("Scroll: {:?} : {:?}", mappy.bands, mappy.scroll);
                 // println!("Known tiles: {:?}", mappy.tiles.gfx_count());
                 println!(
                     "Net: {:} for {:} inputs, avg {:}",
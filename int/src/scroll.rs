@@ -1,5 +1,6 @@
 #![allow(dead_code)]
 
+use mappy::profile::RomHash;
 use mappy::MappyState;
 use retro_rs::{Buttons, Emulator};
 #[cfg(feature = "ffmpeg")]
@@ -12,6 +13,7 @@ use std::{
 pub struct ScrollDumper {
     csv: std::fs::File,
     fm2_path: PathBuf,
+    rom_name: String,
     encoder: video_rs::Encoder,
     enc_time: video_rs::Time,
     fb: ndarray::Array3<u8>,
@@ -45,6 +47,7 @@ impl ScrollDumper {
         Self {
             csv,
             fm2_path,
+            rom_name: rom_name.to_string(),
             encoder,
             fb: ndarray::Array3::zeros((240, 256, 3)),
             enc_time: video_rs::Time::zero(),
@@ -76,15 +79,15 @@ impl ScrollDumper {
         self.scroll = mappy.scroll;
         //}
     }
-    pub fn finish(mut self, inputs: &[[Buttons; 2]]) {
+    pub fn finish(mut self, inputs: &[[Buttons; 2]], rom: Option<&RomHash>) {
         self.encoder.finish().unwrap();
         self.csv.flush().unwrap();
-        mappy::write_fm2(inputs, &self.fm2_path);
+        mappy::write_fm2(inputs, &self.rom_name, rom, &self.fm2_path).unwrap();
     }
 }
 
 #[cfg(not(feature = "ffmpeg"))]
 impl ScrollDumper {
     pub fn update(&mut self, _mappy: &MappyState, _emu: &Emulator) {}
-    pub fn finish(self, _inputs: &[[Buttons; 2]]) {}
+    pub fn finish(self, _inputs: &[[Buttons; 2]], _rom: Option<&RomHash>) {}
 }
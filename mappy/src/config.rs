@@ -0,0 +1,383 @@
+use crate::Rect;
+use crate::tile::{ChangeCosts, TILE_SIZE};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Tuning parameters for the mapping pipeline.
+///
+/// Every field has a default matching the values mappy has always used, so a
+/// config file only needs to mention the settings it wants to change.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MappyConfig {
+    // 45 frames of no control suggests a room change...
+    pub control_room_change_threshold: usize,
+    // and if this many tiles are different (out of 32*30 = 960) and only a small amount of scrolling happened...
+    pub screen_room_change_diff_moderate: f32,
+    // or if this many tiles are different regardless of scrolling...
+    pub screen_room_change_diff_big: f32,
+    // Which detectors can decide that we've moved to a new room...
+    pub room_transitions: Vec<TransitionDetectorKind>,
+    // and how sure one of them has to be
+    pub room_transition_threshold: f32,
+    // We are "in" the room this many frames after we regain control.
+    // This is meant to help with situations where the room does some fade-in or something and we get spurious tiles
+    pub control_room_enter_duration: usize,
+
+    // should be long enough to fight momentum
+    pub control_check_k: usize,
+    // should be odd
+    pub control_check_interval: usize,
+
+    pub create_cost: u32,
+    pub distance_max: u32,
+    pub destroy_coast: usize,
+
+    pub blob_threshold: f32,
+    pub blob_lookback: usize,
+
+    // This is just an arbitrary value, not sure what a good one is!
+    pub room_merge_threshold: f32,
+    // Threads in this mapper's room merge pool; 0 means one per core
+    pub merge_threads: usize,
+    // Where to look for a room's place in each metaroom
+    pub merge_search: MergeSearch,
+    // How many of the best placements in each metaroom to keep...
+    pub merge_candidates: usize,
+    // and how much worse per tile compared the runner-up in the same
+    // metaroom has to fit for the best to be trusted; rooms with closer calls
+    // wait for the metarooms they fit to change.  0 never holds rooms back
+    pub merge_min_margin: f32,
+    // Count frames of one tile animation as the same tile when fitting rooms
+    pub merge_animations: bool,
+    // Charge half for two tiles when a cell has been seen to go from one to
+    // the other, like a door before and after it opened
+    pub merge_reachable: bool,
+
+    pub screen_safe: ScreenSafe,
+
+    // Use this playfield instead of guessing it from the scroll splits
+    pub split_override: Option<SplitOverride>,
+    // Bands at the top or bottom of the screen at most this many scanlines
+    // tall are taken to be status bars and never mapped
+    pub hud_max_height: u8,
+    // Follow scrolls through the nametable select bits (from PPUCTRL and
+    // $2006), so jumps of more than half a screen come out right; off, the
+    // scroll wraps at 256 by 240 pixels as it always has
+    pub nametable_scroll: bool,
+    // How to tell whether the player is in control
+    pub control_detector: ControlDetectorKind,
+    // Buttons held during the two control probes, as fm2 bitstrings (RLDUTSBA)
+    pub control_probes: Option<[String; 2]>,
+    // More probe pairs; each control check uses the next pair in turn
+    pub control_probe_sets: Vec<[String; 2]>,
+    // How the buttons of a probe are pressed over its frames
+    pub probe_presses: ProbePresses,
+    // Named RAM addresses for tools built on mappy; unused by mappy itself
+    pub ram_addresses: BTreeMap<String, u16>,
+    // Screen-space rectangles (in pixels) whose tiles are never mapped
+    pub menu_regions: Vec<Rect>,
+}
+
+/// Fixes the playfield to the scanlines `top..bottom`, scrolled by whichever
+/// split is active at `top`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SplitOverride {
+    pub top: u8,
+    pub bottom: u8,
+}
+
+/// Which [`crate::control::ControlDetector`] a mapper uses.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ControlDetectorKind {
+    /// Rewinds the game, plays two contrasting probe inputs, and checks
+    /// whether sprites or scrolling came out differently.
+    #[default]
+    Probe,
+    /// Checks whether the sprite track recognized as the avatar moves when
+    /// the player presses buttons; no rewinding needed.
+    Avatar,
+    /// Like `Probe`, but compares RAM at these addresses instead (or at the
+    /// config's named RAM addresses if this is empty).
+    RamDiff {
+        #[serde(default)]
+        addresses: Vec<u16>,
+    },
+}
+
+/// The [`crate::transition::RoomTransitionDetector`]s a mapper can use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransitionDetectorKind {
+    /// Control came back after a long gap or a big scroll jump, and the
+    /// screen changed enough; the rule mappy has always used.
+    ControlGap,
+    /// The screen faded (to black or just darker) and came back different.
+    Fade,
+    /// A band of solid color swept over the screen and revealed a different one.
+    Wipe,
+    /// Most of the screen changed at once without scrolling, and stayed changed.
+    HardCut,
+}
+
+/// How a probe's buttons are held over the `control_check_k` frames it runs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbePresses {
+    /// All at once, every frame.
+    #[default]
+    Together,
+    /// One at a time, taking turns frame by frame; for games that ignore
+    /// some simultaneous presses.
+    Alternate,
+    /// A random nonempty subset each frame.
+    Random,
+}
+
+/// Which offsets [`crate::merge_cost`] tries when fitting a room into a
+/// metaroom.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeSearch {
+    /// Skips metarooms without enough changes in common with the room, then
+    /// tries only the offsets where enough tiles line up for the room to
+    /// fit.  Finds the same placements as `exhaustive`, much faster.
+    #[default]
+    Sparse,
+    /// Every offset where the room overlaps the metaroom.
+    Exhaustive,
+}
+
+/// Margins (in pixels) around the edge of the screen which are never mapped,
+/// since many games draw garbage there while scrolling.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScreenSafe {
+    pub left: u32,
+    pub right: u32,
+    pub top: u32,
+    pub bottom: u32,
+}
+
+impl Default for ScreenSafe {
+    fn default() -> Self {
+        Self {
+            left: 8,
+            right: 8,
+            top: 8,
+            bottom: 8,
+        }
+    }
+}
+
+impl Default for MappyConfig {
+    fn default() -> Self {
+        Self {
+            control_room_change_threshold: 45,
+            screen_room_change_diff_moderate: 170.0,
+            screen_room_change_diff_big: 700.0,
+            room_transitions: vec![TransitionDetectorKind::ControlGap],
+            room_transition_threshold: 0.5,
+            control_room_enter_duration: 60,
+            control_check_k: 17,
+            control_check_interval: 7,
+            create_cost: 20,
+            distance_max: 12,
+            destroy_coast: 5,
+            blob_threshold: 5.0,
+            blob_lookback: 30,
+            room_merge_threshold: 16.0,
+            merge_threads: 0,
+            merge_search: MergeSearch::Sparse,
+            merge_candidates: 2,
+            merge_min_margin: 0.01,
+            merge_animations: false,
+            merge_reachable: false,
+            screen_safe: ScreenSafe::default(),
+            split_override: None,
+            hud_max_height: 56,
+            nametable_scroll: false,
+            control_detector: ControlDetectorKind::Probe,
+            control_probes: None,
+            control_probe_sets: vec![],
+            probe_presses: ProbePresses::Together,
+            ram_addresses: BTreeMap::new(),
+            menu_regions: vec![],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Toml(toml::de::Error),
+    Json(serde_json::Error),
+    /// The config parsed, but asks for something mappy can't do.
+    Invalid(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "couldn't read config: {e}"),
+            Self::Toml(e) => write!(f, "invalid TOML config: {e}"),
+            Self::Json(e) => write!(f, "invalid JSON config: {e}"),
+            Self::Invalid(why) => write!(f, "invalid config: {why}"),
+        }
+    }
+}
+impl std::error::Error for ConfigError {}
+
+impl MappyConfig {
+    /// Loads a config from a `.json` file, or from TOML for any other extension.
+    /// # Errors
+    /// Returns an error if the file can't be read, doesn't parse, or isn't
+    /// [valid](MappyConfig::validate)
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+        let config = if path.extension().is_some_and(|ext| ext == "json") {
+            Self::from_json_str(&text)
+        } else {
+            Self::from_toml_str(&text)
+        }?;
+        config.validate()?;
+        Ok(config)
+    }
+    /// Checks for settings mappy would trip over partway through a run.
+    /// Screen margins are checked against the NES's 256x240 screen.
+    /// # Errors
+    /// Returns [`ConfigError::Invalid`] saying what's wrong
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |why: &str| Err(ConfigError::Invalid(why.to_string()));
+        if self.control_check_interval == 0 {
+            return invalid("control_check_interval must be at least 1");
+        }
+        let safe = &self.screen_safe;
+        // Scrolling can push the first whole tile most of a tile further in
+        let tile = TILE_SIZE as u32;
+        if safe
+            .left
+            .saturating_add(safe.right)
+            .saturating_add(2 * tile)
+            > 256
+        {
+            return invalid("screen_safe left and right leave no columns of tiles");
+        }
+        if safe
+            .top
+            .saturating_add(safe.bottom)
+            .saturating_add(2 * tile)
+            > 240
+        {
+            return invalid("screen_safe top and bottom leave no rows of tiles");
+        }
+        if let Some(bad) = self
+            .control_probes
+            .iter()
+            .chain(&self.control_probe_sets)
+            .flatten()
+            .find(|probe| crate::parse_bitstring(probe).is_none())
+        {
+            return Err(ConfigError::Invalid(format!(
+                "control probe {bad:?} isn't an 8-button bitstring (RLDUTSBA)"
+            )));
+        }
+        if let Some(split) = self.split_override
+            && split.top >= split.bottom
+        {
+            return invalid("split_override top must be above its bottom");
+        }
+        Ok(())
+    }
+    /// The looser tile matches this config allows when fitting rooms.
+    #[must_use]
+    pub fn change_costs(&self) -> ChangeCosts {
+        ChangeCosts {
+            animations: self.merge_animations,
+            reachable: self.merge_reachable,
+        }
+    }
+    /// # Errors
+    /// Returns an error if the text isn't a valid TOML config
+    pub fn from_toml_str(text: &str) -> Result<Self, ConfigError> {
+        toml::from_str(text).map_err(ConfigError::Toml)
+    }
+    /// # Errors
+    /// Returns an error if the text isn't a valid JSON config
+    pub fn from_json_str(text: &str) -> Result<Self, ConfigError> {
+        serde_json::from_str(text).map_err(ConfigError::Json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_partial_configs() {
+        let cfg =
+            MappyConfig::from_toml_str("room_merge_threshold = 8.0\n[screen_safe]\ntop = 16\n")
+                .unwrap();
+        assert_eq!(cfg.room_merge_threshold, 8.0);
+        assert_eq!(cfg.screen_safe.top, 16);
+        assert_eq!(cfg.screen_safe.bottom, 8);
+        assert_eq!(cfg.control_check_k, 17);
+        let cfg = MappyConfig::from_json_str(r#"{"control_check_interval": 9}"#).unwrap();
+        assert_eq!(cfg.control_check_interval, 9);
+        assert_eq!(cfg.room_merge_threshold, 16.0);
+        assert!(MappyConfig::from_toml_str("room_merge_threshold = \"x\"").is_err());
+        let cfg = MappyConfig::from_toml_str(
+            "probe_presses = \"alternate\"\n[control_detector]\nkind = \"ram_diff\"\naddresses = [0x86]\n",
+        )
+        .unwrap();
+        assert_eq!(cfg.probe_presses, ProbePresses::Alternate);
+        // Misspelled settings aren't just ignored
+        assert!(MappyConfig::from_toml_str("room_merge_treshold = 8.0").is_err());
+        assert!(MappyConfig::from_toml_str("[screen_safe]\ntopp = 16\n").is_err());
+        assert_eq!(
+            cfg.control_detector,
+            ControlDetectorKind::RamDiff {
+                addresses: vec![0x86]
+            }
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(MappyConfig::default().validate().is_ok());
+        let invalid = |cfg: MappyConfig| matches!(cfg.validate(), Err(ConfigError::Invalid(_)));
+        assert!(invalid(MappyConfig {
+            control_check_interval: 0,
+            ..MappyConfig::default()
+        }));
+        assert!(invalid(MappyConfig {
+            screen_safe: ScreenSafe {
+                left: 200,
+                right: 50,
+                ..ScreenSafe::default()
+            },
+            ..MappyConfig::default()
+        }));
+        assert!(invalid(MappyConfig {
+            screen_safe: ScreenSafe {
+                bottom: 300,
+                ..ScreenSafe::default()
+            },
+            ..MappyConfig::default()
+        }));
+        assert!(invalid(MappyConfig {
+            control_probe_sets: vec![[".LD...B.".to_string(), "..DL..B".to_string()]],
+            ..MappyConfig::default()
+        }));
+        assert!(invalid(MappyConfig {
+            split_override: Some(SplitOverride {
+                top: 100,
+                bottom: 40
+            }),
+            ..MappyConfig::default()
+        }));
+    }
+}
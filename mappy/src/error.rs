@@ -0,0 +1,67 @@
+use crate::config::ConfigError;
+use crate::map::MapError;
+use std::io;
+
+/// Anything that can go wrong while mapping or saving results, for callers
+/// that would rather recover than abort.
+#[derive(Debug)]
+pub enum MappyError {
+    Io(io::Error),
+    Image(image::ImageError),
+    /// The emulator core doesn't export a function mappy needs, which
+    /// usually means it isn't our instrumented fceumm.
+    MissingSymbol(&'static str),
+    /// The emulator failed at something besides a missing symbol, like
+    /// reading memory or saving and loading state.
+    Emulator(String),
+    /// A file mappy reads or writes (an fm2 movie, a dot graph) is malformed.
+    Format(String),
+    Config(ConfigError),
+    Map(MapError),
+}
+
+impl std::fmt::Display for MappyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "I/O error: {e}"),
+            Self::Image(e) => write!(f, "couldn't write image: {e}"),
+            Self::MissingSymbol(sym) => write!(f, "emulator core doesn't export {sym}"),
+            Self::Emulator(why) => write!(f, "emulator error: {why}"),
+            Self::Format(why) => write!(f, "bad format: {why}"),
+            Self::Config(e) => e.fmt(f),
+            Self::Map(e) => e.fmt(f),
+        }
+    }
+}
+impl std::error::Error for MappyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Image(e) => Some(e),
+            Self::Config(e) => Some(e),
+            Self::Map(e) => Some(e),
+            Self::MissingSymbol(_) | Self::Emulator(_) | Self::Format(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for MappyError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+impl From<image::ImageError> for MappyError {
+    fn from(e: image::ImageError) -> Self {
+        Self::Image(e)
+    }
+}
+impl From<ConfigError> for MappyError {
+    fn from(e: ConfigError) -> Self {
+        Self::Config(e)
+    }
+}
+impl From<MapError> for MappyError {
+    fn from(e: MapError) -> Self {
+        Self::Map(e)
+    }
+}
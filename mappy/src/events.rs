@@ -0,0 +1,52 @@
+use crate::Time;
+use crate::metaroom::MetaroomID;
+use crate::sprites::{BlobID, TrackID};
+
+/// Something mappy decided, stamped with the frame it happened on.
+///
+/// Subscribe with [`crate::MappyState::subscribe`] to hear about these as
+/// they happen instead of polling `MappyState`'s fields.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MappyEvent {
+    pub time: Time,
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind {
+    ControlGained,
+    ControlLost,
+    /// Mapping started a new room with this id.
+    RoomEntered(usize),
+    /// The room with this id is done and has been handed off for merging.
+    RoomFinalized(usize),
+    /// A room transition detector (named by its
+    /// [`crate::transition::RoomTransitionDetector::name`]) decided we've
+    /// moved to a new room; a `RoomEntered` follows.
+    RoomTransition {
+        detector: &'static str,
+        confidence: f32,
+    },
+    /// Room `id` fit about as well in more than one place in some
+    /// metaroom, so it waits to be merged until more rooms are (or the
+    /// session finishes).
+    MergeDeferred(usize),
+    /// `room` was placed in new metaroom `into`, which absorbed the
+    /// metarooms in `merged` (if any).
+    MetaroomMerged {
+        room: usize,
+        into: MetaroomID,
+        merged: Vec<MetaroomID>,
+    },
+    /// The game was reset, ending the room in progress (if any).
+    Reset {
+        room: Option<usize>,
+    },
+    TrackCreated(TrackID),
+    TrackKilled(TrackID),
+    BlobCreated(BlobID),
+    BlobKilled(BlobID),
+}
+
+/// Called with every event, in the order mappy decided them.
+pub type Subscriber = Box<dyn FnMut(&MappyEvent) + Send>;
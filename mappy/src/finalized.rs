@@ -0,0 +1,443 @@
+use crate::config::MappyConfig;
+use crate::metaroom::{MergeConfidence, Merges, Metaroom, MetaroomID, Undecided, best_placements};
+use crate::room::Room;
+use crate::tile::{TILE_SIZE, TileChange, TileDB, TileGfx};
+use crate::{MappyError, merge_cost};
+use image::{ImageBuffer, Rgb};
+use rayon::prelude::*;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::RwLock;
+
+/// A finished map: the tiles, rooms and metaroom graph a [`crate::MappyState`]
+/// built, owned outright so it can be queried without taking any locks.
+///
+/// Get one from [`crate::MappyState::finish`], or by restoring a
+/// [`crate::map::SavedMap`].  Room ids are indices into [`FinalizedMap::rooms`].
+#[derive(Clone)]
+pub struct FinalizedMap {
+    tiles: TileDB,
+    rooms: Vec<Room>,
+    metarooms: Merges,
+    resets: Vec<usize>,
+}
+
+impl FinalizedMap {
+    pub(crate) fn new(
+        tiles: TileDB,
+        rooms: Vec<Room>,
+        metarooms: Merges,
+        resets: Vec<usize>,
+    ) -> Self {
+        Self {
+            tiles,
+            rooms,
+            metarooms,
+            resets,
+        }
+    }
+    pub(crate) fn into_parts(self) -> (TileDB, Vec<Room>, Merges, Vec<usize>) {
+        (self.tiles, self.rooms, self.metarooms, self.resets)
+    }
+    #[must_use]
+    pub fn tiles(&self) -> &TileDB {
+        &self.tiles
+    }
+    #[must_use]
+    pub fn rooms(&self) -> &[Room] {
+        &self.rooms
+    }
+    #[must_use]
+    pub fn room(&self, id: usize) -> Option<&Room> {
+        self.rooms.get(id)
+    }
+    /// Rooms entered right after a reset rather than by moving from the
+    /// previous room.
+    #[must_use]
+    pub fn resets(&self) -> &[usize] {
+        &self.resets
+    }
+    #[must_use]
+    pub fn merges(&self) -> &Merges {
+        &self.metarooms
+    }
+    /// The metarooms that haven't been merged into others.
+    pub fn metarooms(&self) -> impl Iterator<Item = &Metaroom> {
+        self.metarooms.metarooms()
+    }
+    /// Every metaroom, including ones since merged into others.
+    pub fn all_metarooms(&self) -> impl Iterator<Item = &Metaroom> {
+        self.metarooms.all_metarooms()
+    }
+    #[must_use]
+    pub fn metaroom(&self, id: MetaroomID) -> Option<&Metaroom> {
+        self.all_metarooms().find(|mr| mr.id == id)
+    }
+    /// Every merge where an alternative came within `min_margin` of where
+    /// some metaroom was placed, as (the metaroom the merge made, the
+    /// metaroom it took in, how sure it was).
+    #[must_use]
+    pub fn close_calls(&self, min_margin: f32) -> Vec<(MetaroomID, MetaroomID, MergeConfidence)> {
+        self.all_metarooms()
+            .flat_map(|mr| {
+                mr.confidences
+                    .iter()
+                    .filter(|(_, c)| c.is_close_call(min_margin))
+                    .map(|(from, c)| (mr.id, *from, *c))
+            })
+            .collect()
+    }
+    /// The unmerged metaroom room `room` ended up in.
+    #[must_use]
+    pub fn metaroom_for_room(&self, room: usize) -> Option<&Metaroom> {
+        self.metarooms()
+            .find(|mr| mr.registrations.iter().any(|(rid, _)| *rid == room))
+    }
+    /// Metarooms the player went to straight from `mr`.
+    #[must_use]
+    pub fn metaroom_exits(&self, mr: &Metaroom) -> Vec<MetaroomID> {
+        let mut out_to = vec![];
+        for (rid, _pos) in &mr.registrations {
+            if self.resets.contains(rid) {
+                continue;
+            }
+            if let Some(mr2) = self.metaroom_for_room(rid + 1)
+                && !out_to.contains(&mr2.id)
+            {
+                out_to.push(mr2.id);
+            }
+        }
+        out_to
+    }
+    /// The change at world pixel position `wx`,`wy` of `mr`, taken from the
+    /// last of its rooms covering that spot (the one drawn on top by
+    /// [`FinalizedMap::render_metaroom`]).
+    #[must_use]
+    #[allow(clippy::cast_possible_wrap, clippy::cast_possible_truncation)]
+    pub fn tile_at(&self, mr: &Metaroom, wx: i32, wy: i32) -> Option<TileChange> {
+        let tx = wx.div_euclid(TILE_SIZE as i32);
+        let ty = wy.div_euclid(TILE_SIZE as i32);
+        mr.registrations.iter().rev().find_map(|(rid, pos)| {
+            let room = self.room(*rid)?;
+            let region = room.region();
+            let (x, y) = (tx - pos.0 + region.x, ty - pos.1 + region.y);
+            // Screens can extend past the room's own region
+            if region.contains(x, y) {
+                room.get(x, y)
+            } else {
+                None
+            }
+        })
+    }
+    /// The graphics last seen at world pixel position `wx`,`wy` of `mr`.
+    #[must_use]
+    pub fn gfx_at(&self, mr: &Metaroom, wx: i32, wy: i32) -> Option<&TileGfx> {
+        let change = self.tile_at(mr, wx, wy)?;
+        self.tiles
+            .get_tile_by_id(self.tiles.get_change_by_id(change)?.to)
+    }
+    /// The graphics last seen at tile position `x`,`y` of room `room`.
+    #[must_use]
+    pub fn room_gfx_at(&self, room: usize, x: i32, y: i32) -> Option<&TileGfx> {
+        let change = self.room(room)?.get(x, y)?;
+        let to = self.tiles.get_change_by_id(change)?.to;
+        self.tiles.get_tile_by_id(to)
+    }
+    /// Draws every room of `mr` where it was registered, later rooms on top.
+    /// # Panics
+    /// Panics if `mr` refers to rooms this map doesn't have
+    #[must_use]
+    #[allow(clippy::cast_sign_loss, clippy::cast_possible_truncation)]
+    pub fn render_metaroom(&self, mr: &Metaroom) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        // Offset every registration so that the toppiest leftiest is at 0,0
+        let region = mr.region(&self.rooms);
+        let mut buf =
+            vec![0_u8; TILE_SIZE * (region.w as usize) * TILE_SIZE * (region.h as usize) * 3];
+        for (room_i, pos) in &mr.registrations {
+            assert!(pos.0 - region.x >= 0);
+            assert!(pos.1 - region.y >= 0);
+            let new_pos = ((pos.0 - region.x) as u32, (pos.1 - region.y) as u32);
+            draw_room(
+                &self.tiles,
+                &self.rooms[*room_i],
+                new_pos,
+                region.w,
+                &mut buf,
+            );
+        }
+        ImageBuffer::from_raw(
+            region.w * TILE_SIZE as u32,
+            region.h * TILE_SIZE as u32,
+            buf,
+        )
+        .expect("Couldn't create image buffer")
+    }
+    /// # Errors
+    /// Returns an error if the image can't be written
+    pub fn dump_metaroom(&self, mr: &Metaroom, path: &Path) -> Result<(), MappyError> {
+        self.render_metaroom(mr).save(path)?;
+        Ok(())
+    }
+    /// Writes an image of every metaroom and a `graph.dot` connecting them.
+    /// # Errors
+    /// Returns an error if a file can't be written
+    pub fn dump_map(&self, dotfolder: &Path) -> Result<(), MappyError> {
+        use std::collections::BTreeMap;
+        use std::fs;
+        use tabbycat::attributes::{Shape, image, shape, xlabel};
+        use tabbycat::{AttrList, Edge, GraphBuilder, GraphType, Identity, StmtList};
+        let gname = "map".to_string();
+        let node_image_paths: BTreeMap<usize, String> = self
+            .all_metarooms()
+            .map(|mr| (mr.id.0, format!("mr_{}.png", mr.id.0)))
+            .collect();
+        let node_labels: BTreeMap<usize, String> = self
+            .metarooms()
+            .map(|mr| {
+                let r = mr.region(&self.rooms);
+                (
+                    mr.id.0,
+                    format!("{},{}<>{},{}\n", r.x, r.y, r.w, r.h)
+                        + &mr
+                            .registrations
+                            .iter()
+                            .map(|(ri, pos)| format!("{}@{},{}", ri, pos.0, pos.1))
+                            .chain(mr.confidences.iter().map(|(from, c)| {
+                                // How well each metaroom fit, and by how much
+                                match c.margin {
+                                    Some(m) => format!(
+                                        "m{}: {:.3} +{:.3}",
+                                        from.0, c.cost_per_comparison, m
+                                    ),
+                                    None => format!("m{}: {:.3}", from.0, c.cost_per_comparison),
+                                }
+                            }))
+                            .collect::<Vec<_>>()
+                            .join("\n"),
+                )
+            })
+            .collect();
+        let mut all_stmts = StmtList::new();
+        for mr in self.all_metarooms() {
+            self.dump_metaroom(
+                mr,
+                &dotfolder.join(Path::new(&node_image_paths[&mr.id.0].clone())),
+            )?;
+        }
+        for mr in self.metarooms() {
+            let mut stmts = StmtList::new();
+            let mr_ident = Identity::from(mr.id.0);
+            let mut attrs = AttrList::new()
+                .add_pair(xlabel(&node_labels[&mr.id.0]))
+                .add_pair(image(&node_image_paths[&mr.id.0]));
+            if mr
+                .registrations
+                .iter()
+                .any(|(rid, _pos)| *rid == 0 || self.resets.contains(rid))
+            {
+                attrs = attrs.add_pair(shape(Shape::Box));
+            } else {
+                attrs = attrs.add_pair(shape(Shape::Plain));
+            }
+            stmts = stmts.add_node(mr_ident.clone(), None, Some(attrs));
+            for mr2_id in self.metaroom_exits(mr) {
+                stmts = stmts.add_edge(
+                    Edge::head_node(mr_ident.clone(), None)
+                        .arrow_to_node(Identity::from(mr2_id.0), None),
+                );
+            }
+            all_stmts = all_stmts.extend(stmts);
+        }
+        let graph = GraphBuilder::default()
+            .graph_type(GraphType::DiGraph)
+            .strict(false)
+            .id(Identity::id(&gname).map_err(|e| MappyError::Format(e.to_string()))?)
+            .stmts(all_stmts)
+            .build()
+            .map_err(|e| MappyError::Format(e.to_string()))?;
+        fs::write(dotfolder.join(Path::new("graph.dot")), graph.to_string())?;
+        Ok(())
+    }
+    /// # Errors
+    /// Returns an error if an image can't be written
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn dump_tiles(&self, root: &Path) -> Result<(), MappyError> {
+        let mut buf = vec![0_u8; TILE_SIZE * TILE_SIZE * 3];
+        for (ti, tile) in self.tiles.gfx_ids().enumerate() {
+            self.tiles.write_rgb888(tile, &mut buf);
+            let img: ImageBuffer<Rgb<u8>, _> =
+                ImageBuffer::from_raw(TILE_SIZE as u32, TILE_SIZE as u32, &buf[..])
+                    .expect("Couldn't create image buffer");
+            img.save(root.join(format!("t{ti:}.png")))?;
+        }
+        Ok(())
+    }
+    /// Combines two maps built independently, say from different input files.
+    ///
+    /// `other`'s tiles are matched to this map's by their graphics and its
+    /// rooms and resets are renumbered to follow this map's.  Each of its
+    /// rooms is then merged into the combined metarooms with [`merge_cost`]
+    /// (using `config`'s merge settings), as if it had been mapped after
+    /// this map's rooms,
+    /// so areas both maps saw collapse into the same metarooms.  Close calls
+    /// wait for the metarooms they fit to change, as in a session, and are
+    /// merged at the end if they never do.
+    /// # Panics
+    /// Panics if `other` refers to tiles or changes it doesn't contain
+    #[must_use]
+    pub fn union(self, other: FinalizedMap, config: &MappyConfig) -> FinalizedMap {
+        let FinalizedMap {
+            mut tiles,
+            mut rooms,
+            mut metarooms,
+            mut resets,
+        } = self;
+        let (_gfx_map, change_map) = tiles.absorb(&other.tiles);
+        tiles.set_change_costs(config.change_costs());
+        let offset = rooms.len();
+        rooms.extend(other.rooms.into_iter().map(|mut room| {
+            room.id += offset;
+            room.remap(|tc| change_map[tc.index() as usize]);
+            room
+        }));
+        resets.extend(other.resets.iter().map(|rid| rid + offset));
+        let room_count = rooms.len();
+        let rooms = RwLock::new(rooms);
+        let tiles = RwLock::new(tiles);
+        let mut todo: VecDeque<usize> = (offset..room_count).collect();
+        let mut undecided = Undecided::default();
+        loop {
+            let (rid, settle) = match todo.pop_front() {
+                Some(rid) => (rid, false),
+                None => match undecided.take_first() {
+                    Some(rid) => (rid, true),
+                    None => break,
+                },
+            };
+            let room = rooms.read().unwrap()[rid].clone();
+            let candidates: Vec<_> = metarooms
+                .metarooms()
+                .collect::<Vec<_>>()
+                .into_par_iter()
+                .filter_map(|mr| {
+                    let placements = merge_cost(
+                        &room,
+                        mr.id,
+                        &mr.registrations,
+                        &rooms,
+                        &tiles,
+                        config.room_merge_threshold,
+                        config.merge_search,
+                        config.merge_candidates,
+                    );
+                    (!placements.is_empty()).then_some((mr.id, placements))
+                })
+                .collect();
+            let metas = best_placements(&candidates);
+            if !settle
+                && metas
+                    .iter()
+                    .any(|(_, _, c)| c.is_close_call(config.merge_min_margin))
+            {
+                undecided.defer(rid, &candidates);
+                continue;
+            }
+            metarooms.merge_new_room(rid, &metas);
+            let merged: Vec<_> = metas.iter().map(|(mid, _, _)| *mid).collect();
+            for rid in undecided.take_affected(&merged).into_iter().rev() {
+                todo.push_front(rid);
+            }
+        }
+        FinalizedMap {
+            tiles: tiles.into_inner().unwrap(),
+            rooms: rooms.into_inner().unwrap(),
+            metarooms,
+            resets,
+        }
+    }
+}
+
+/// Draws `room` into `buf`, an rgb888 image `tiles_wide` tiles across, with
+/// its top left tile at tile position `at`, in true colors where known.
+#[allow(
+    clippy::cast_possible_wrap,
+    clippy::cast_sign_loss,
+    clippy::cast_possible_truncation
+)]
+pub(crate) fn draw_room(
+    tiles: &TileDB,
+    room: &Room,
+    at: (u32, u32),
+    tiles_wide: u32,
+    buf: &mut [u8],
+) {
+    let region = room.region();
+    for y in region.y..(region.y + region.h as i32) {
+        for x in region.x..(region.x + region.w as i32) {
+            let tile = room.get(x, y).unwrap_or_else(|| tiles.get_initial_change());
+            // Animated tiles are always drawn on the same frame
+            let to_tile_gfx_id = tiles.canonical(tiles.get_change_by_id(tile).unwrap().to);
+            tiles.write_rgb888_at(
+                to_tile_gfx_id,
+                ((x + at.0 as i32 - region.x) * (TILE_SIZE as i32)) as usize,
+                ((y + at.1 as i32 - region.y) * (TILE_SIZE as i32)) as usize,
+                buf,
+                tiles_wide as usize * TILE_SIZE,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Rect;
+    use crate::screen::Screen;
+    use crate::tile::TILE_NUM_PX;
+
+    #[test]
+    fn test_queries() {
+        let mut db = TileDB::new();
+        let t1 = db.get_tile(TileGfx([1; TILE_NUM_PX]));
+        let t2 = db.get_tile(TileGfx([2; TILE_NUM_PX]));
+        let mut merges = Merges::new();
+        let mut rooms = vec![];
+        for (id, t) in [t1, t2, t2].into_iter().enumerate() {
+            let room = Room::new(id, &Screen::new(Rect::new(3, 2, 4, 4), t), &mut db);
+            rooms.push(room.finalize());
+        }
+        let first = merges.merge_new_room(0, &[]);
+        let second = merges.merge_new_room(1, &[]);
+        // Room 0 lands two tiles left of room 2, overlapping it
+        let confidence = MergeConfidence {
+            cost_per_comparison: 0.25,
+            margin: Some(0.5),
+        };
+        merges.merge_new_room(2, &[(first, (2, 0), confidence)]);
+        let map = FinalizedMap::new(db, rooms, merges, vec![]);
+        let merged = map.metaroom_for_room(2).unwrap();
+        assert_eq!(merged.registrations.len(), 2);
+        assert_eq!(merged.confidences, [(first, confidence)]);
+        assert_eq!(map.close_calls(0.5), []);
+        assert_eq!(map.close_calls(0.6), [(merged.id, first, confidence)]);
+        assert_eq!(map.metaroom_for_room(0).unwrap().id, merged.id);
+        assert_eq!(map.metarooms().count(), 2);
+        assert_eq!(map.metaroom_exits(merged), [second]);
+        let gfx_at = |tx: i32| map.gfx_at(merged, tx * TILE_SIZE as i32 + 1, TILE_SIZE as i32);
+        assert_eq!(gfx_at(-2), Some(&TileGfx([1; TILE_NUM_PX])));
+        // Room 0 comes after room 2 in the registrations, so it's on top where they overlap
+        assert_eq!(gfx_at(1), Some(&TileGfx([1; TILE_NUM_PX])));
+        assert_eq!(gfx_at(3), Some(&TileGfx([2; TILE_NUM_PX])));
+        assert!(gfx_at(-3).is_none());
+        assert!(gfx_at(4).is_none());
+        let img = map.render_metaroom(merged);
+        assert_eq!(img.width(), 6 * TILE_SIZE as u32);
+        assert_eq!(img.height(), 4 * TILE_SIZE as u32);
+        // Tiles seen in full color are drawn in those colors
+        let mut map = map;
+        map.tiles.set_colors(t2, [7; TILE_NUM_PX * 3]);
+        let img = map.render_metaroom(map.metaroom_for_room(2).unwrap());
+        assert_ne!(img.get_pixel(0, 0), &Rgb([7, 7, 7]));
+        assert_eq!(img.get_pixel(5 * TILE_SIZE as u32, 0), &Rgb([7, 7, 7]));
+    }
+}
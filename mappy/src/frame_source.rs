@@ -0,0 +1,281 @@
+use crate::MappyError;
+use crate::mappy::scrolling::ScrollChange;
+use retro_rs::{Buttons, Emulator, Symbol};
+
+/// Everything mappy needs from a running game: the picture, the PPU's scroll
+/// activity and sprite memory, and the ability to step and rewind for control
+/// checks.
+///
+/// This is implemented for [`Emulator`] (with our instrumented fceumm core),
+/// but recorded traces or synthetic fixtures can implement it too.  Reads
+/// that depend on the core return a [`MappyError`] when it can't provide them.
+pub trait FrameSource {
+    fn run(&mut self, inputs: [Buttons; 2]);
+    fn framebuffer_size(&self) -> (usize, usize);
+    /// Copies the current frame into `buf` as rgb332, one byte per pixel.
+    /// # Errors
+    /// Returns an error if the frame can't be read
+    fn copy_framebuffer_rgb332(&self, buf: &mut [u8]) -> Result<(), MappyError>;
+    /// Copies the current frame into `buf` as rgb888, three bytes per pixel,
+    /// for drawing tiles in their true colors.
+    /// # Errors
+    /// Returns an error if this source can't read full color frames
+    fn copy_framebuffer_rgb888(&self, _buf: &mut [u8]) -> Result<(), MappyError> {
+        Err(MappyError::Emulator(
+            "this source can't read rgb888 frames".to_string(),
+        ))
+    }
+    /// The back sprite, background, and front sprite layers of the current
+    /// frame, each `framebuffer_size` bytes of rgb332.  Transparent pixels
+    /// are 191.
+    /// # Errors
+    /// Returns an error if the layers can't be read
+    fn layers(&self) -> Result<[&[u8]; 3], MappyError>;
+    /// Replaces the contents of `changes` with the scroll-related register
+    /// accesses made during the last frame.
+    /// # Errors
+    /// Returns an error if the scroll changes can't be read
+    fn scroll_changes(&self, changes: &mut Vec<ScrollChange>) -> Result<(), MappyError>;
+    /// The 256 bytes of sprite attribute memory for the current frame.
+    fn oam(&self) -> &[u8];
+    /// # Errors
+    /// Returns an error if the PPU control register can't be read
+    fn ppuctrl(&self) -> Result<u8, MappyError>;
+    /// The console's 2KB of work RAM, for control detectors that watch it.
+    /// # Errors
+    /// Returns an error if this source can't read RAM
+    fn ram(&self) -> Result<&[u8], MappyError> {
+        Err(MappyError::Emulator("this source can't read RAM".to_string()))
+    }
+    fn save_size(&self) -> usize;
+    fn save(&self, buf: &mut [u8]) -> bool;
+    fn load(&mut self, buf: &[u8]) -> bool;
+    /// Sources that can't save and load (like recorded traces) may already
+    /// know whether the game responded to this frame's control probes; if so
+    /// mappy uses that instead of probing.
+    fn recorded_probe(&self) -> Option<bool> {
+        None
+    }
+    /// Called with the outcome whenever mappy actually runs control probes.
+    fn report_probe(&mut self, _responded: bool) {}
+}
+
+impl<S: FrameSource + ?Sized> FrameSource for &mut S {
+    fn run(&mut self, inputs: [Buttons; 2]) {
+        (**self).run(inputs);
+    }
+    fn framebuffer_size(&self) -> (usize, usize) {
+        (**self).framebuffer_size()
+    }
+    fn copy_framebuffer_rgb332(&self, buf: &mut [u8]) -> Result<(), MappyError> {
+        (**self).copy_framebuffer_rgb332(buf)
+    }
+    fn copy_framebuffer_rgb888(&self, buf: &mut [u8]) -> Result<(), MappyError> {
+        (**self).copy_framebuffer_rgb888(buf)
+    }
+    fn layers(&self) -> Result<[&[u8]; 3], MappyError> {
+        (**self).layers()
+    }
+    fn scroll_changes(&self, changes: &mut Vec<ScrollChange>) -> Result<(), MappyError> {
+        (**self).scroll_changes(changes)
+    }
+    fn oam(&self) -> &[u8] {
+        (**self).oam()
+    }
+    fn ppuctrl(&self) -> Result<u8, MappyError> {
+        (**self).ppuctrl()
+    }
+    fn ram(&self) -> Result<&[u8], MappyError> {
+        (**self).ram()
+    }
+    fn save_size(&self) -> usize {
+        (**self).save_size()
+    }
+    fn save(&self, buf: &mut [u8]) -> bool {
+        (**self).save(buf)
+    }
+    fn load(&mut self, buf: &[u8]) -> bool {
+        (**self).load(buf)
+    }
+    fn recorded_probe(&self) -> Option<bool> {
+        (**self).recorded_probe()
+    }
+    fn report_probe(&mut self, responded: bool) {
+        (**self).report_probe(responded);
+    }
+}
+
+impl FrameSource for Emulator {
+    fn run(&mut self, inputs: [Buttons; 2]) {
+        Emulator::run(self, inputs);
+    }
+    fn framebuffer_size(&self) -> (usize, usize) {
+        Emulator::framebuffer_size(self)
+    }
+    fn copy_framebuffer_rgb332(&self, buf: &mut [u8]) -> Result<(), MappyError> {
+        // TODO: make fb.fb work on u64s for 8 pixel spans?  measure!
+        Emulator::copy_framebuffer_rgb332(self, buf)
+            .map_err(|e| MappyError::Emulator(format!("couldn't get framebuffer: {e:?}")))
+    }
+    fn copy_framebuffer_rgb888(&self, buf: &mut [u8]) -> Result<(), MappyError> {
+        Emulator::copy_framebuffer_rgb888(self, buf)
+            .map_err(|e| MappyError::Emulator(format!("couldn't get framebuffer: {e:?}")))
+    }
+    #[allow(clippy::similar_names)]
+    fn layers(&self) -> Result<[&[u8]; 3], MappyError> {
+        let get_layer_fn: Symbol<unsafe extern "C" fn(i32) -> *const u8> = self
+            .get_symbol(b"retro_layer")
+            .ok_or(MappyError::MissingSymbol("retro_layer"))?;
+        // safety: the layers live inside the core and are rewritten when it
+        // runs, but running needs `&mut self` so these borrows can't outlive that.
+        unsafe {
+            let sz = 256 * 240;
+            let sp_bg = get_layer_fn(0);
+            let bg = get_layer_fn(1);
+            let sp_fg = get_layer_fn(2);
+            Ok([
+                std::slice::from_raw_parts(sp_bg, sz),
+                std::slice::from_raw_parts(bg, sz),
+                std::slice::from_raw_parts(sp_fg, sz),
+            ])
+        }
+    }
+    fn scroll_changes(&self, changes: &mut Vec<ScrollChange>) -> Result<(), MappyError> {
+        let get_changes_fn: Symbol<unsafe extern "C" fn(*mut ScrollChange, u32) -> u32> = self
+            .get_symbol(b"retro_count_scroll_changes")
+            .ok_or(MappyError::MissingSymbol("retro_count_scroll_changes"))?;
+        unsafe {
+            let change_count = get_changes_fn(std::ptr::null_mut(), 0);
+            changes.resize_with(change_count as usize, Default::default);
+            get_changes_fn(changes.as_mut_ptr(), change_count);
+        }
+        Ok(())
+    }
+    fn oam(&self) -> &[u8] {
+        &self.system_ram_ref()[0x0200..0x0300]
+    }
+    fn ppuctrl(&self) -> Result<u8, MappyError> {
+        self.memory_ref(0x2000)
+            .map(|mem| mem[0])
+            .map_err(|e| MappyError::Emulator(format!("couldn't read PPUCTRL: {e:?}")))
+    }
+    fn ram(&self) -> Result<&[u8], MappyError> {
+        Ok(self.system_ram_ref())
+    }
+    fn save_size(&self) -> usize {
+        Emulator::save_size(self)
+    }
+    fn save(&self, buf: &mut [u8]) -> bool {
+        Emulator::save(self, buf)
+    }
+    fn load(&mut self, buf: &[u8]) -> bool {
+        Emulator::load(self, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MappyState;
+
+    /// A game where nothing ever happens.
+    struct Blank {
+        fb: Vec<u8>,
+        oam: [u8; 256],
+        can_save: bool,
+    }
+    impl FrameSource for Blank {
+        fn run(&mut self, _inputs: [Buttons; 2]) {}
+        fn framebuffer_size(&self) -> (usize, usize) {
+            (256, 240)
+        }
+        fn copy_framebuffer_rgb332(&self, buf: &mut [u8]) -> Result<(), MappyError> {
+            buf.copy_from_slice(&self.fb);
+            Ok(())
+        }
+        fn layers(&self) -> Result<[&[u8]; 3], MappyError> {
+            Ok([&self.fb, &self.fb, &self.fb])
+        }
+        fn scroll_changes(&self, changes: &mut Vec<ScrollChange>) -> Result<(), MappyError> {
+            changes.clear();
+            Ok(())
+        }
+        fn oam(&self) -> &[u8] {
+            &self.oam
+        }
+        fn ppuctrl(&self) -> Result<u8, MappyError> {
+            Ok(0)
+        }
+        fn save_size(&self) -> usize {
+            0
+        }
+        fn save(&self, _buf: &mut [u8]) -> bool {
+            self.can_save
+        }
+        fn load(&mut self, _buf: &[u8]) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_synthetic_source() {
+        let mut src = Blank {
+            fb: vec![191; 256 * 240],
+            oam: [0xFF; 256],
+            can_save: true,
+        };
+        let mut mappy = MappyState::new(256, 240);
+        for _ in 0..30 {
+            src.run([Buttons::new(), Buttons::new()]);
+            mappy
+                .process_screen(&mut src, [Buttons::new(), Buttons::new()])
+                .unwrap();
+        }
+        assert!(!mappy.has_control);
+        assert_eq!(mappy.scroll, (0, 0));
+        assert_eq!(*mappy.now, 30);
+    }
+
+    #[test]
+    fn test_failed_probe() {
+        let mut src = Blank {
+            fb: vec![191; 256 * 240],
+            oam: [0xFF; 256],
+            can_save: false,
+        };
+        let mut mappy = MappyState::new(256, 240);
+        // Control is checked on the very first frame
+        let result = mappy.process_screen(&mut src, [Buttons::new(), Buttons::new()]);
+        assert!(matches!(result, Err(MappyError::Emulator(_))));
+    }
+
+    #[test]
+    fn test_events() {
+        use crate::events::{EventKind, MappyEvent};
+        use std::sync::{Arc, Mutex};
+        let mut src = Blank {
+            fb: vec![191; 256 * 240],
+            oam: [0xFF; 256],
+            can_save: true,
+        };
+        let mut mappy = MappyState::new(256, 240);
+        let events = Arc::new(Mutex::new(vec![]));
+        let log = Arc::clone(&events);
+        mappy.subscribe(move |e: &MappyEvent| log.lock().unwrap().push(e.clone()));
+        for _ in 0..30 {
+            src.run([Buttons::new(), Buttons::new()]);
+            mappy
+                .process_screen(&mut src, [Buttons::new(), Buttons::new()])
+                .unwrap();
+        }
+        mappy.handle_reset();
+        let events = events.lock().unwrap();
+        assert_eq!(
+            events.last(),
+            Some(&MappyEvent {
+                time: crate::Time(30),
+                kind: EventKind::Reset { room: None }
+            })
+        );
+    }
+}
@@ -1,7 +1,9 @@
-use retro_rs::Emulator;
+use crate::{FrameSource, MappyError};
 
 pub struct Framebuffer {
     pub fb: Vec<u8>,
+    // The same frame as rgb888, or empty if the source can't provide it
+    pub rgb: Vec<u8>,
     pub w: usize,
     pub h: usize,
 }
@@ -9,13 +11,17 @@ impl Framebuffer {
     pub fn new(w: usize, h: usize) -> Self {
         Framebuffer {
             fb: vec![0; w * h],
+            rgb: vec![0; w * h * 3],
             w,
             h,
         }
     }
-    pub fn read_from(&mut self, emu: &Emulator) {
-        // TODO: make fb.fb work on u64s for 8 pixel spans?  measure!
-        emu.copy_framebuffer_rgb332(&mut self.fb)
-            .expect("Couldn't get FB");
+    pub fn read_from<S: FrameSource + ?Sized>(&mut self, emu: &S) -> Result<(), MappyError> {
+        emu.copy_framebuffer_rgb332(&mut self.fb)?;
+        self.rgb.resize(self.w * self.h * 3, 0);
+        if emu.copy_framebuffer_rgb888(&mut self.rgb).is_err() {
+            self.rgb.clear();
+        }
+        Ok(())
     }
 }
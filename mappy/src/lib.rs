@@ -1,16 +1,29 @@
 #![allow(clippy::many_single_char_names)]
+pub mod config;
+mod error;
+pub mod events;
+pub mod finalized;
+mod frame_source;
 mod framebuffer;
+pub mod map;
 mod mappy;
 pub mod metaroom;
+pub mod profile;
 mod ringbuffer;
 pub mod room;
 mod screen;
 pub mod sprites;
 pub mod tile;
 pub mod time;
+pub mod trace;
 pub use crate::mappy::*;
+pub use error::MappyError;
+pub use finalized::FinalizedMap;
+pub use frame_source::FrameSource;
 
+use profile::RomHash;
 use retro_rs::Buttons;
+use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::path::Path;
 pub use tile::TILE_SIZE;
@@ -50,7 +63,7 @@ impl std::ops::Deref for Time {
     }
 }
 
-#[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Debug, Serialize, Deserialize)]
 pub struct Rect {
     pub x: i32,
     pub y: i32,
@@ -143,29 +156,40 @@ fn to_bitstring(b: Buttons) -> String {
     )
 }
 
-/// # Panics
-/// Panics if the file write fails
-pub fn write_fm2(inputs: &[[Buttons; 2]], path: &Path) {
+/// Writes `inputs` as an fm2 movie for the ROM called `rom_name`; if `rom` is given its MD5
+/// fills in the `romChecksum`.
+/// # Errors
+/// Returns an error if the file can't be written
+pub fn write_fm2(
+    inputs: &[[Buttons; 2]],
+    rom_name: &str,
+    rom: Option<&RomHash>,
+    path: &Path,
+) -> Result<(), MappyError> {
     use std::io::Write;
     use uuid::Uuid;
-    let mut file = File::create(path).expect("Couldn't dump file");
-    writeln!(file, "version 3").unwrap();
-    writeln!(file, "palFlag 0").unwrap();
-    writeln!(file, "NewPPU 1").unwrap();
-    writeln!(file, "FDS 0").unwrap();
-    writeln!(file, "fourscore 0").unwrap();
-    writeln!(file, "port0 1").unwrap();
-    writeln!(file, "port1 1").unwrap();
-    writeln!(file, "binary 0").unwrap();
-    writeln!(file, "length {}", inputs.len()).unwrap();
-    writeln!(file, "romFilename Super Mario Bros.").unwrap();
+    let mut file = std::io::BufWriter::new(File::create(path)?);
+    writeln!(file, "version 3")?;
+    writeln!(file, "palFlag 0")?;
+    writeln!(file, "NewPPU 1")?;
+    writeln!(file, "FDS 0")?;
+    writeln!(file, "fourscore 0")?;
+    writeln!(file, "port0 1")?;
+    writeln!(file, "port1 1")?;
+    writeln!(file, "binary 0")?;
+    writeln!(file, "length {}", inputs.len())?;
+    writeln!(file, "romFilename {rom_name}")?;
     let guid = Uuid::new_v4();
-    writeln!(file, "guid {guid}").unwrap();
-    // TODO fixme
-    writeln!(file, "romChecksum 0").unwrap();
+    writeln!(file, "guid {guid}")?;
+    match rom {
+        Some(hash) => writeln!(file, "romChecksum {}", hash.fm2_checksum())?,
+        None => writeln!(file, "romChecksum 0")?,
+    }
     for &[b1, b2] in inputs {
-        writeln!(file, "||{}|{}|", to_bitstring(b1), to_bitstring(b2)).unwrap();
+        writeln!(file, "||{}|{}|", to_bitstring(b1), to_bitstring(b2))?;
     }
+    file.flush()?;
+    Ok(())
 }
 
 #[must_use]
@@ -181,11 +205,28 @@ pub fn from_bitstring(bs: [bool; 8]) -> Buttons {
         .a(bs[7])
 }
 
-/// # Panics
-/// Panics if the file read fails
-pub fn read_fm2(inputs: &mut Vec<[Buttons; 2]>, path: &Path) {
+/// Parses an fm2-style bitstring in RLDUTSBA order, like `".LD...B."` for
+/// left, down and B; any character besides `.` or space presses that
+/// button.  `None` unless there are exactly 8 characters.
+#[must_use]
+pub fn parse_bitstring(s: &str) -> Option<Buttons> {
+    if s.chars().count() != 8 {
+        return None;
+    }
+    let mut bs = [false; 8];
+    for (b, c) in bs.iter_mut().zip(s.chars()) {
+        *b = !(c == '.' || c == ' ');
+    }
+    Some(from_bitstring(bs))
+}
+
+/// Appends the inputs of the fm2 movie at `path` to `inputs`.
+/// # Errors
+/// Returns an error if the file can't be opened or an input line has too
+/// many buttons
+pub fn read_fm2(inputs: &mut Vec<[Buttons; 2]>, path: &Path) -> Result<(), MappyError> {
     use std::io::{BufRead, BufReader};
-    let file = File::open(path).expect("Couldn't open file");
+    let file = File::open(path)?;
     let reader = BufReader::new(file);
     for line in reader.lines().map_while(Result::ok) {
         // scan ahead to second |
@@ -205,7 +246,12 @@ pub fn read_fm2(inputs: &mut Vec<[Buttons; 2]>, path: &Path) {
                     break;
                 }
             } else if pipenum >= 2 {
-                assert!(bitstr_idx < 8);
+                if bitstr_idx >= 8 {
+                    return Err(MappyError::Format(format!(
+                        "{}: too many buttons in {line:?}",
+                        path.display()
+                    )));
+                }
                 bitstr[bitstr_idx] = !(c == '.' || c == ' ');
                 bitstr_idx += 1;
             }
@@ -220,4 +266,5 @@ pub fn read_fm2(inputs: &mut Vec<[Buttons; 2]>, path: &Path) {
             _ => (),
         }
     }
+    Ok(())
 }
@@ -0,0 +1,521 @@
+use crate::metaroom::{MergeConfidence, Merges, Metaroom, MetaroomID};
+use crate::room::Room;
+use crate::screen::Screen;
+use crate::tile::{
+    TILE_NUM_PX, TileChange, TileChangeData, TileColors, TileDB, TileGfx, TileGfxId,
+};
+use crate::{FinalizedMap, MappyState, Rect};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+const MAP_MAGIC: &[u8; 8] = b"MAPPYMAP";
+pub const MAP_VERSION: u16 = 6;
+
+#[derive(Debug)]
+pub enum MapError {
+    Io(io::Error),
+    Bincode(bincode::Error),
+    Json(serde_json::Error),
+    Version(u16),
+    Invalid(String),
+}
+
+impl std::fmt::Display for MapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "couldn't read or write map: {e}"),
+            Self::Bincode(e) => write!(f, "invalid binary map: {e}"),
+            Self::Json(e) => write!(f, "invalid JSON map: {e}"),
+            Self::Version(v) => write!(
+                f,
+                "unsupported map version {v} (this build reads {MAP_VERSION})"
+            ),
+            Self::Invalid(why) => write!(f, "inconsistent map: {why}"),
+        }
+    }
+}
+impl std::error::Error for MapError {}
+
+/// A complete map (tiles, rooms, metarooms and resets) in a form that doesn't
+/// depend on how mappy stores it in memory.
+///
+/// Ids are plain indices: tile `i` is the `i`th entry of `tiles.gfx`, change
+/// `i` the `i`th of `tiles.changes`, and room `i` the `i`th of `rooms`.
+/// Binary files are an 8-byte magic and little-endian version followed by
+/// the bincode encoding of this struct; JSON files are just this struct.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedMap {
+    pub version: u16,
+    pub tiles: SavedTiles,
+    pub rooms: Vec<SavedRoom>,
+    pub metarooms: Vec<SavedMetaroom>,
+    pub resets: Vec<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedTiles {
+    // TILE_NUM_PX rgb332 pixels each; entry 0 is the blank initial tile
+    pub gfx: Vec<Vec<u8>>,
+    // entry 0 is the initial -> initial change
+    pub changes: Vec<SavedChange>,
+    // (tile id, TILE_NUM_PX rgb888 pixels) for tiles seen in full color
+    pub colors: Vec<(u16, Vec<u8>)>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedChange {
+    pub from: u16,
+    pub to: u16,
+    pub successors: Vec<(u16, usize)>,
+    pub count: usize,
+    // (frames between repeats, how often), for finding animations
+    pub intervals: Vec<(u32, usize)>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedRoom {
+    pub id: usize,
+    pub top_left: (i32, i32),
+    pub bottom_right: (i32, i32),
+    pub screens: Vec<SavedScreen>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedScreen {
+    pub region: Rect,
+    // change ids in row-major order
+    pub changes: Vec<u32>,
+}
+
+/// Metarooms are listed in [`Merges::all_metarooms`] order.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedMetaroom {
+    pub id: usize,
+    pub registrations: Vec<(usize, (i32, i32))>,
+    pub merged_into: Vec<usize>,
+    // (metaroom taken in, cost per comparison, margin over the runner-up)
+    // for the merge that made this metaroom
+    pub confidences: Vec<(usize, f32, Option<f32>)>,
+}
+
+impl SavedMap {
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn new(tiles: &TileDB, rooms: &[Room], metarooms: &Merges, resets: &[usize]) -> Self {
+        Self {
+            version: MAP_VERSION,
+            tiles: SavedTiles {
+                gfx: tiles.gfx_iter().map(|tg| tg.0.to_vec()).collect(),
+                changes: tiles
+                    .change_iter()
+                    .map(|tc| SavedChange {
+                        from: tc.from.index(),
+                        to: tc.to.index(),
+                        successors: tc
+                            .successors()
+                            .iter()
+                            .map(|(to, n)| (to.index(), *n))
+                            .collect(),
+                        count: tc.count(),
+                        intervals: tc.intervals().to_vec(),
+                    })
+                    .collect(),
+                colors: tiles
+                    .all_colors()
+                    .into_iter()
+                    .map(|(id, rgb)| (id.index(), rgb.to_vec()))
+                    .collect(),
+            },
+            rooms: rooms
+                .iter()
+                .map(|r| SavedRoom {
+                    id: r.id,
+                    top_left: r.top_left,
+                    bottom_right: r.bottom_right,
+                    screens: r
+                        .chunks()
+                        .into_iter()
+                        .map(|(region, changes)| SavedScreen {
+                            region,
+                            changes: changes.iter().map(TileChange::index).collect(),
+                        })
+                        .collect(),
+                })
+                .collect(),
+            metarooms: metarooms
+                .all_metarooms()
+                .map(|mr| SavedMetaroom {
+                    id: mr.id.0,
+                    registrations: mr.registrations.clone(),
+                    merged_into: mr.merged_into.iter().map(|m| m.0).collect(),
+                    confidences: mr
+                        .confidences
+                        .iter()
+                        .map(|(from, c)| (from.0, c.cost_per_comparison, c.margin))
+                        .collect(),
+                })
+                .collect(),
+            resets: resets.to_vec(),
+        }
+    }
+    #[must_use]
+    pub fn from_map(map: &FinalizedMap) -> Self {
+        Self::new(map.tiles(), map.rooms(), map.merges(), map.resets())
+    }
+    /// Saves everything `mappy` has mapped so far.  The current room is only
+    /// included once it's finalized, so save the map [`MappyState::finish`]
+    /// returns (with [`SavedMap::from_map`]) to save a whole session.
+    /// # Panics
+    /// Panics if the tile or room locks are poisoned
+    #[must_use]
+    pub fn from_state(mappy: &MappyState) -> Self {
+        Self::new(
+            &mappy.tiles.read().unwrap(),
+            &mappy.rooms.read().unwrap(),
+            &mappy.metarooms,
+            &mappy.resets,
+        )
+    }
+    /// # Errors
+    /// Returns an error if writing fails
+    pub fn write_binary<W: Write>(&self, mut out: W) -> Result<(), MapError> {
+        out.write_all(MAP_MAGIC).map_err(MapError::Io)?;
+        out.write_all(&self.version.to_le_bytes())
+            .map_err(MapError::Io)?;
+        bincode::serialize_into(out, self).map_err(MapError::Bincode)
+    }
+    /// # Errors
+    /// Returns an error if writing fails
+    pub fn write_json<W: Write>(&self, out: W) -> Result<(), MapError> {
+        serde_json::to_writer(out, self).map_err(MapError::Json)
+    }
+    /// Saves as JSON to a `.json` file, or in the binary format for any
+    /// other extension.
+    /// # Errors
+    /// Returns an error if the file can't be written
+    pub fn save(&self, path: &Path) -> Result<(), MapError> {
+        let out = io::BufWriter::new(std::fs::File::create(path).map_err(MapError::Io)?);
+        if path.extension().is_some_and(|ext| ext == "json") {
+            self.write_json(out)
+        } else {
+            self.write_binary(out)
+        }
+    }
+    /// Reads a map in either format.
+    /// # Errors
+    /// Returns an error if the data can't be read, isn't a map, or is a
+    /// version this build doesn't understand
+    pub fn read<R: Read>(mut input: R) -> Result<Self, MapError> {
+        let mut bytes = vec![];
+        input.read_to_end(&mut bytes).map_err(MapError::Io)?;
+        if bytes.starts_with(MAP_MAGIC) && bytes.len() >= MAP_MAGIC.len() + 2 {
+            let version = u16::from_le_bytes([bytes[8], bytes[9]]);
+            if version != MAP_VERSION {
+                return Err(MapError::Version(version));
+            }
+            bincode::deserialize(&bytes[10..]).map_err(MapError::Bincode)
+        } else {
+            // Check the version before committing to a shape
+            let value: serde_json::Value =
+                serde_json::from_slice(&bytes).map_err(MapError::Json)?;
+            let version = value
+                .get("version")
+                .and_then(serde_json::Value::as_u64)
+                .ok_or_else(|| MapError::Invalid("no version".to_string()))?;
+            if version != u64::from(MAP_VERSION) {
+                return Err(MapError::Version(
+                    u16::try_from(version).unwrap_or(u16::MAX),
+                ));
+            }
+            serde_json::from_value(value).map_err(MapError::Json)
+        }
+    }
+    /// # Errors
+    /// Returns an error if the file can't be read or isn't a supported map
+    pub fn load(path: &Path) -> Result<Self, MapError> {
+        Self::read(io::BufReader::new(
+            std::fs::File::open(path).map_err(MapError::Io)?,
+        ))
+    }
+    /// Rebuilds the tile database, rooms and metaroom graph.
+    /// # Errors
+    /// Returns an error if the map refers to tiles, changes or rooms it
+    /// doesn't contain
+    pub fn restore(self) -> Result<FinalizedMap, MapError> {
+        let invalid = |why: String| Err(MapError::Invalid(why));
+        let gfx_count = self.tiles.gfx.len();
+        let change_count = self.tiles.changes.len();
+        let room_count = self.rooms.len();
+        let mut gfx = Vec::with_capacity(gfx_count);
+        for (i, px) in self.tiles.gfx.into_iter().enumerate() {
+            let Ok(px) = <[u8; TILE_NUM_PX]>::try_from(px) else {
+                return invalid(format!("tile {i} has the wrong number of pixels"));
+            };
+            gfx.push(TileGfx(px));
+        }
+        if gfx.first() != Some(&TileGfx::new()) {
+            return invalid("tile 0 isn't the initial tile".to_string());
+        }
+        let gfx_id = |i: u16| {
+            if usize::from(i) < gfx_count {
+                Ok(TileGfxId::from_index(i))
+            } else {
+                Err(MapError::Invalid(format!("no tile {i}")))
+            }
+        };
+        let mut changes = Vec::with_capacity(change_count);
+        for tc in self.tiles.changes {
+            let successors = tc
+                .successors
+                .iter()
+                .map(|&(to, n)| Ok((gfx_id(to)?, n)))
+                .collect::<Result<_, MapError>>()?;
+            changes.push(TileChangeData::new(
+                gfx_id(tc.from)?,
+                gfx_id(tc.to)?,
+                successors,
+                tc.count,
+                tc.intervals,
+            ));
+        }
+        let mut colors = Vec::with_capacity(self.tiles.colors.len());
+        for (id, rgb) in self.tiles.colors {
+            let Ok(rgb) = <TileColors>::try_from(rgb) else {
+                return invalid(format!("tile {id} has the wrong number of colors"));
+            };
+            colors.push((gfx_id(id)?, rgb));
+        }
+        if changes
+            .first()
+            .is_none_or(|tc| tc.from.index() != 0 || tc.to.index() != 0)
+        {
+            return invalid("change 0 isn't the initial change".to_string());
+        }
+        let mut rooms = Vec::with_capacity(room_count);
+        for (i, room) in self.rooms.into_iter().enumerate() {
+            if room.id != i {
+                return invalid(format!("room {i} has id {}", room.id));
+            }
+            if room.screens.is_empty() {
+                return invalid(format!("room {i} has no screens"));
+            }
+            let mut screens = Vec::with_capacity(room.screens.len());
+            for s in room.screens {
+                if s.changes.len() != s.region.area() as usize {
+                    return invalid(format!("room {i} has a screen of the wrong size"));
+                }
+                if let Some(c) = s.changes.iter().find(|&&c| c as usize >= change_count) {
+                    return invalid(format!("room {i} refers to missing change {c}"));
+                }
+                screens.push(Screen::from_tiles(
+                    s.region,
+                    s.changes.into_iter().map(TileChange::from_index).collect(),
+                ));
+            }
+            rooms.push(Room::from_screens(
+                room.id,
+                room.top_left,
+                room.bottom_right,
+                &screens,
+                TileChange::from_index(0),
+            ));
+        }
+        let mut metarooms = Vec::with_capacity(self.metarooms.len());
+        for mr in self.metarooms {
+            if let Some((rid, _)) = mr.registrations.iter().find(|(rid, _)| *rid >= room_count) {
+                return invalid(format!("metaroom {} refers to missing room {rid}", mr.id));
+            }
+            if mr.registrations.is_empty() {
+                return invalid(format!("metaroom {} has no rooms", mr.id));
+            }
+            metarooms.push(Metaroom {
+                id: MetaroomID(mr.id),
+                registrations: mr.registrations,
+                merged_into: mr.merged_into.into_iter().map(MetaroomID).collect(),
+                confidences: mr
+                    .confidences
+                    .into_iter()
+                    .map(|(from, cost_per_comparison, margin)| {
+                        (
+                            MetaroomID(from),
+                            MergeConfidence {
+                                cost_per_comparison,
+                                margin,
+                            },
+                        )
+                    })
+                    .collect(),
+            });
+        }
+        Ok(FinalizedMap::new(
+            TileDB::from_parts(gfx, changes, colors),
+            rooms,
+            Merges::from_metarooms(metarooms),
+            self.resets,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MappyConfig;
+
+    fn sample_map() -> SavedMap {
+        sample_map_with(&[1, 2])
+    }
+    // The first two entries of `extra_gfx` are the two rooms' graphics; any
+    // more just take up tile ids
+    fn sample_map_with(extra_gfx: &[u8]) -> SavedMap {
+        let mut db = TileDB::new();
+        let ids: Vec<_> = extra_gfx
+            .iter()
+            .map(|&c| db.get_tile(TileGfx([c; TILE_NUM_PX])))
+            .collect();
+        let (t1, t2) = (ids[0], ids[1]);
+        db.set_colors(t1, [7; TILE_NUM_PX * 3]);
+        let mut rooms = vec![];
+        let mut merges = Merges::new();
+        for (id, t) in [t1, t2].into_iter().enumerate() {
+            let mut room = Room::new(id, &Screen::new(Rect::new(3, 2, 32, 30), t1), &mut db);
+            room.register_screen(&Screen::new(Rect::new(3, 10, 32, 30), t), &mut db);
+            rooms.push(room.finalize());
+        }
+        let first = merges.merge_new_room(0, &[]);
+        let confidence = MergeConfidence {
+            cost_per_comparison: 0.0,
+            margin: None,
+        };
+        merges.merge_new_room(1, &[(first, (0, 8), confidence)]);
+        SavedMap::new(&db, &rooms, &merges, &[1])
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let saved = sample_map();
+        for json in [false, true] {
+            let mut bytes = vec![];
+            if json {
+                saved.write_json(&mut bytes).unwrap();
+            } else {
+                saved.write_binary(&mut bytes).unwrap();
+            }
+            let loaded = SavedMap::read(&bytes[..]).unwrap();
+            assert_eq!(loaded, saved);
+            let map = loaded.restore().unwrap();
+            assert_eq!(map.room_gfx_at(1, 5, 20), Some(&TileGfx([2; TILE_NUM_PX])));
+            assert_eq!(map.room_gfx_at(1, 5, 1), Some(&TileGfx([1; TILE_NUM_PX])));
+            assert_eq!(map.metarooms().count(), 1);
+            assert_eq!(SavedMap::from_map(&map), saved);
+        }
+        let mut bytes = vec![];
+        SavedMap {
+            version: MAP_VERSION + 1,
+            ..saved.clone()
+        }
+        .write_binary(&mut bytes)
+        .unwrap();
+        assert!(matches!(
+            SavedMap::read(&bytes[..]),
+            Err(MapError::Version(v)) if v == MAP_VERSION + 1
+        ));
+        let mut broken = saved;
+        broken.rooms[0].screens[0].changes[0] = 9999;
+        assert!(matches!(broken.restore(), Err(MapError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_union() {
+        let a = sample_map().restore().unwrap();
+        // b's second room shows 7s instead of 2s, and b numbers its tiles differently
+        let b = sample_map_with(&[1, 7, 2]).restore().unwrap();
+        assert_eq!(b.room_gfx_at(1, 5, 20), Some(&TileGfx([7; TILE_NUM_PX])));
+        let map = a.union(b, &MappyConfig::default());
+        assert_eq!(map.rooms().len(), 4);
+        assert_eq!(map.rooms()[3].id, 3);
+        assert_eq!(map.resets(), [1, 3]);
+        // 7 is the only tile a didn't have
+        assert_eq!(map.tiles().gfx_count(), 3);
+        assert_eq!(map.room_gfx_at(1, 5, 20), Some(&TileGfx([2; TILE_NUM_PX])));
+        assert_eq!(map.room_gfx_at(2, 5, 20), Some(&TileGfx([1; TILE_NUM_PX])));
+        assert_eq!(map.room_gfx_at(3, 5, 20), Some(&TileGfx([7; TILE_NUM_PX])));
+        // b's first room is identical to a's, so it joins a's metaroom
+        assert!(map.metarooms().any(|mr| {
+            mr.registrations.iter().any(|(rid, _)| *rid == 0)
+                && mr.registrations.iter().any(|(rid, _)| *rid == 2)
+        }));
+        // Holding back close calls still merges every room, and only once
+        let config = MappyConfig {
+            merge_min_margin: 1.0,
+            ..MappyConfig::default()
+        };
+        let a = sample_map().restore().unwrap();
+        let b = sample_map_with(&[1, 7, 2]).restore().unwrap();
+        let held = a.union(b, &config);
+        assert!(held.all_metarooms().all(|mr| mr.merged_into.len() <= 1));
+        for rid in 0..4 {
+            assert!(held.metaroom_for_room(rid).is_some());
+        }
+    }
+
+    #[test]
+    fn test_resume() {
+        let saved = sample_map();
+        let mappy = MappyState::from_saved_map(256, 240, saved.clone()).unwrap();
+        assert_eq!(SavedMap::from_state(&mappy), saved);
+        {
+            let mut tiles = mappy.tiles.write().unwrap();
+            assert_eq!(tiles.get_tile(TileGfx([2; TILE_NUM_PX])).index(), 2);
+            assert_eq!(tiles.get_tile(TileGfx([3; TILE_NUM_PX])).index(), 3);
+        }
+        // Finishing a session that never entered a room leaves the map as it was
+        let resumed = SavedMap::from_map(&mappy.finish());
+        assert_eq!(resumed.rooms, saved.rooms);
+        assert_eq!(resumed.metarooms, saved.metarooms);
+        assert_eq!(resumed.tiles.gfx.len(), saved.tiles.gfx.len() + 1);
+    }
+
+    #[test]
+    fn test_concurrent_sessions() {
+        let saved = sample_map();
+        let config = MappyConfig {
+            merge_threads: 1,
+            ..MappyConfig::default()
+        };
+        let maps: Vec<_> = std::thread::scope(|s| {
+            let sessions: Vec<_> = [1, 2]
+                .into_iter()
+                .map(|c| {
+                    let mut mappy = MappyState::builder(256, 240)
+                        .config(config.clone())
+                        .resume(saved.clone().restore().unwrap())
+                        .build();
+                    s.spawn(move || {
+                        let gfx = mappy
+                            .tiles
+                            .write()
+                            .unwrap()
+                            .get_tile(TileGfx([c; TILE_NUM_PX]));
+                        let screen = Screen::new(Rect::new(3, 2, 32, 30), gfx);
+                        let room = Room::new(2, &screen, &mut mappy.tiles.write().unwrap());
+                        mappy.current_room = Some(room);
+                        // Finalizes the room and sends it off to be merged
+                        mappy.handle_reset();
+                        mappy.finish()
+                    })
+                })
+                .collect();
+            sessions.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        for map in maps {
+            // Each session waited for its own merge, and only its own
+            assert_eq!(map.rooms().len(), 3);
+            assert_eq!(map.resets(), [1, 2]);
+            assert!(
+                map.metarooms()
+                    .any(|mr| mr.registrations.iter().any(|(rid, _)| *rid == 2))
+            );
+        }
+    }
+}
@@ -1,38 +1,45 @@
+use crate::config::{MappyConfig, MergeSearch};
+use crate::events::{EventKind, MappyEvent, Subscriber};
+use crate::finalized::{self, FinalizedMap};
 use crate::framebuffer::Framebuffer;
-use crate::metaroom::{Merges, Metaroom, MetaroomID};
+use crate::map::{MapError, SavedMap};
+use crate::metaroom::{Merges, MetaroomID, Placement, Undecided, best_placements};
 use crate::ringbuffer::RingBuffer;
 use crate::room::Room;
 use crate::screen::Screen;
 use crate::sprites::{self, SPRITE_COUNT, SpriteBlob, SpriteData, SpriteTrack};
-use crate::tile::{TILE_SIZE, TileDB, TileGfx, TileGfxId};
+use crate::tile::{TILE_SIZE, TileDB, TileGfx, TileGfxId, read_colors};
 use crate::time::Timers;
-use crate::{Rect, Time};
+use crate::{FrameSource, MappyError, Rect, Time};
 use image::{ImageBuffer, Rgb};
-use retro_rs::{Buttons, Emulator, Symbol};
+use retro_rs::Buttons;
 use std::path::Path;
-mod scrolling;
+pub mod control;
+use control::{ControlContext, ControlDetector};
+pub mod scrolling;
+pub mod transition;
 use scrolling::{ScrollChange, ScrollLatch};
-mod splits;
-use splits::Split;
+use transition::{RoomTransitionDetector, TransitionContext};
+pub mod splits;
+use splits::{Band, BandKind, BandScroll, Split};
 mod matching;
+mod merge_search;
 
 use crossbeam::channel::{Receiver, Sender, unbounded};
-use rayon::{prelude::*, spawn_fifo};
-use std::sync::{
-    Arc, RwLock,
-    atomic::{AtomicUsize, Ordering},
-};
+use rayon::{ThreadPool, ThreadPoolBuilder, prelude::*};
+use std::sync::{Arc, RwLock};
 
 const DO_TEMP_MERGE_CHECKS: bool = false;
 
-static THREADS_WAITING: AtomicUsize = AtomicUsize::new(0);
-
-// Merge room ID into metarooms with given scores
-struct DoMerge(MergePhase, usize, Vec<(MetaroomID, (i32, i32), f32)>);
+// Merge room ID into metarooms given its best placements in each
+struct DoMerge(MergePhase, usize, Vec<(MetaroomID, Vec<Placement>)>);
 
 enum MergePhase {
     Intermediate,
     Finalize,
+    // Merge a room left undecided even if it's still a close call, since
+    // no more rooms are coming
+    Settle,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
@@ -56,7 +63,19 @@ pub struct MappyState {
     pub grid_align: (u8, u8),
     pub scroll: (i32, i32),
     pub has_control: bool,
-    pub splits: [(Split, Split); 1],
+    // Every band of scanlines between this frame's scroll splits, top to bottom
+    pub bands: Vec<Band>,
+    // Index in `bands` of the playfield rooms are mapped from
+    pub main_band: usize,
+    // The other playfield bands, which scroll independently; their scrolls
+    // are followed, but only the main band is read and mapped
+    pub other_bands: Vec<BandScroll>,
+    // The main band's top split as of the last scroll update
+    scroll_split: Split,
+    // Whether the game has been seen selecting nametables in X and in Y,
+    // if `config.nametable_scroll` is set; kept across resets, since it's a
+    // property of the game
+    nametables: (bool, bool),
     pub live_sprites: [SpriteData; SPRITE_COUNT],
     pub prev_sprites: [SpriteData; SPRITE_COUNT],
     pub live_tracks: Vec<SpriteTrack>,
@@ -66,14 +85,20 @@ pub struct MappyState {
     pub current_screen: Screen<TileGfxId>,
     last_control_screen: Screen<TileGfxId>,
     fb: Framebuffer,
-    state_buffer: Vec<u8>,
     changes: Vec<ScrollChange>,
-    change_count: u32,
     pub current_room: Option<Room>,
     pub rooms: Arc<RwLock<Vec<Room>>>,
     pub metarooms: Merges,
-    room_merge_tx: Arc<Sender<DoMerge>>,
+    room_merge_tx: Sender<DoMerge>,
     room_merge_rx: Receiver<DoMerge>,
+    // Merge costs are computed here; its threads exit once it's dropped and
+    // any queued jobs are done
+    merge_pool: ThreadPool,
+    // Jobs kicked off whose DoMerge hasn't been received yet
+    merges_pending: usize,
+    // Finished rooms whose placements were too close to call, waiting for
+    // the metarooms they fit to change before they're merged
+    undecided: Undecided,
     pub now: Time,
     maybe_control: bool,
     maybe_control_change_time: Time,
@@ -86,58 +111,134 @@ pub struct MappyState {
     // which rooms were terminated by resets?
     pub resets: Vec<usize>,
     pub button_inputs: RingBuffer<Buttons>,
+    pub config: MappyConfig,
+    control_detector: Box<dyn ControlDetector>,
+    transition_detectors: Vec<Box<dyn RoomTransitionDetector>>,
+    // The surest transition seen since we last started a room, if any
+    pending_transition: Option<(f32, &'static str)>,
+    subscribers: Vec<Subscriber>,
 }
 
-impl MappyState {
-    // 45 frames of no control suggests a room change...
-    const CONTROL_ROOM_CHANGE_THRESHOLD: usize = 45;
-    // and if this many tiles are different (out of 32*30 = 960) and only a small amount of scrolling happened...
-    const SCREEN_ROOM_CHANGE_DIFF_MODERATE: f32 = 170.0;
-    // or if this many tiles are different regardless of scrolling...
-    const SCREEN_ROOM_CHANGE_DIFF_BIG: f32 = 700.0;
-    // We are "in" the room this many frames after we regain control.
-    // This is meant to help with situations where the room does some fade-in or something and we get spurious tiles
-    const CONTROL_ROOM_ENTER_DURATION: usize = 60;
-
-    const CREATE_COST: u32 = 20;
-    const DISTANCE_MAX: u32 = 12;
-    const DESTROY_COAST: usize = 5;
+/// Builds a [`MappyState`], optionally with a non-default [`MappyConfig`]
+/// or a previously saved map to extend.
+pub struct MappyStateBuilder {
+    w: usize,
+    h: usize,
+    config: MappyConfig,
+    map: Option<FinalizedMap>,
+    control_detector: Option<Box<dyn ControlDetector>>,
+    transition_detectors: Option<Vec<Box<dyn RoomTransitionDetector>>>,
+}
 
-    const BLOB_THRESHOLD: f32 = 5.0;
-    const BLOB_LOOKBACK: usize = 30;
+impl MappyStateBuilder {
+    #[must_use]
+    pub fn config(mut self, config: MappyConfig) -> Self {
+        self.config = config;
+        self
+    }
+    /// Continues mapping on top of `map`: its tile and change ids are kept,
+    /// new rooms are numbered after its rooms, and they are merged against
+    /// its metarooms.
+    #[must_use]
+    pub fn resume(mut self, map: FinalizedMap) -> Self {
+        self.map = Some(map);
+        self
+    }
+    /// Uses `detector` instead of the one the config asks for.
+    #[must_use]
+    pub fn control_detector(mut self, detector: Box<dyn ControlDetector>) -> Self {
+        self.control_detector = Some(detector);
+        self
+    }
+    /// Uses `detectors` instead of the ones the config asks for.
+    #[must_use]
+    pub fn room_transition_detectors(
+        mut self,
+        detectors: Vec<Box<dyn RoomTransitionDetector>>,
+    ) -> Self {
+        self.transition_detectors = Some(detectors);
+        self
+    }
+    /// # Panics
+    /// Panics if the merge thread pool can't be started
+    #[must_use]
+    pub fn build(self) -> MappyState {
+        let control_detector = self
+            .control_detector
+            .unwrap_or_else(|| control::from_config(&self.config));
+        let transition_detectors = self
+            .transition_detectors
+            .unwrap_or_else(|| transition::from_config(&self.config));
+        MappyState::with_config(
+            self.w,
+            self.h,
+            self.config,
+            self.map,
+            control_detector,
+            transition_detectors,
+        )
+    }
+}
 
+impl MappyState {
     const BUTTON_HISTORY: usize = 60;
 
-    // This is just an arbitrary value, not sure what a good one is!
-    pub const ROOM_MERGE_THRESHOLD: f32 = 16.0;
-
     #[must_use]
     pub fn new(w: usize, h: usize) -> Self {
-        let db = TileDB::new();
+        Self::builder(w, h).build()
+    }
+    #[must_use]
+    pub fn builder(w: usize, h: usize) -> MappyStateBuilder {
+        MappyStateBuilder {
+            w,
+            h,
+            config: MappyConfig::default(),
+            map: None,
+            control_detector: None,
+            transition_detectors: None,
+        }
+    }
+    /// Starts a new session that extends a saved map; use
+    /// [`MappyStateBuilder::resume`] to also pick a config.
+    /// # Errors
+    /// Returns an error if the saved map is inconsistent
+    pub fn from_saved_map(w: usize, h: usize, map: SavedMap) -> Result<Self, MapError> {
+        Ok(Self::builder(w, h).resume(map.restore()?).build())
+    }
+
+    fn with_config(
+        w: usize,
+        h: usize,
+        config: MappyConfig,
+        map: Option<FinalizedMap>,
+        control_detector: Box<dyn ControlDetector>,
+        transition_detectors: Vec<Box<dyn RoomTransitionDetector>>,
+    ) -> Self {
+        let (mut db, rooms, metarooms, resets) = map.map_or_else(
+            || (TileDB::new(), vec![], Merges::new(), vec![]),
+            FinalizedMap::into_parts,
+        );
+        db.set_change_costs(config.change_costs());
         let t0 = db.get_initial_tile();
         let s0 = Screen::new(Rect::new(0, 0, 0, 0), t0);
         let (room_merge_tx, room_merge_rx) = unbounded();
-        let room_merge_tx = Arc::new(room_merge_tx);
+        let merge_pool = ThreadPoolBuilder::new()
+            .num_threads(config.merge_threads)
+            .thread_name(|i| format!("mappy-merge-{i}"))
+            .build()
+            .expect("Couldn't start merge threads");
         MappyState {
             latch: ScrollLatch::default(),
             tiles: Arc::new(RwLock::new(db)),
             grid_align: (0, 0),
             scroll: (0, 0),
             has_control: false,
-            splits: [(
-                Split {
-                    scanline: 0,
-                    scroll_x: 0,
-                    scroll_y: 0,
-                },
-                Split {
-                    scanline: 240,
-                    scroll_x: 0,
-                    scroll_y: 0,
-                },
-            )],
+            bands: vec![Band::whole_screen()],
+            main_band: 0,
+            other_bands: vec![],
+            scroll_split: Band::whole_screen().top,
+            nametables: (false, false),
             now: Time(0),
-            state_buffer: Vec::new(),
             last_control: Time(0),
             maybe_control: false,
             maybe_control_change_time: Time(0),
@@ -153,43 +254,66 @@ impl MappyState {
             // last_inputs: [Buttons::new(); INPUT_MEM],
             fb: Framebuffer::new(w, h),
             changes: Vec::with_capacity(32000),
-            change_count: 0,
             current_screen: s0.clone(),
             last_control_screen: s0,
             current_room: None,
-            rooms: Arc::new(RwLock::new(vec![])),
-            metarooms: Merges::new(),
+            rooms: Arc::new(RwLock::new(rooms)),
+            metarooms,
             room_merge_rx,
             room_merge_tx,
+            merge_pool,
+            merges_pending: 0,
+            undecided: Undecided::default(),
             timers: Timers::new(),
             mapping: false,
-            resets: vec![],
+            resets,
             button_inputs: RingBuffer::new(Buttons::new(), Self::BUTTON_HISTORY),
+            config,
+            control_detector,
+            transition_detectors,
+            pending_transition: None,
+            subscribers: vec![],
+        }
+    }
+
+    /// Calls `subscriber` with every [`MappyEvent`] from now on.
+    pub fn subscribe(&mut self, subscriber: impl FnMut(&MappyEvent) + Send + 'static) {
+        self.subscribers.push(Box::new(subscriber));
+    }
+    fn emit(&mut self, kind: EventKind) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+        let event = MappyEvent {
+            time: self.now,
+            kind,
+        };
+        for subscriber in &mut self.subscribers {
+            subscriber(&event);
         }
     }
 
     #[allow(clippy::missing_panics_doc)]
     pub fn handle_reset(&mut self) {
-        if let Some(cr) = self.current_room.as_ref() {
-            self.resets.push(cr.id);
+        let interrupted = self.current_room.as_ref().map(|cr| cr.id);
+        if let Some(id) = interrupted {
+            self.resets.push(id);
         }
         self.finalize_current_room(false);
+        self.control_detector.reset();
+        self.pending_transition = None;
+        for detector in &mut self.transition_detectors {
+            detector.clear();
+        }
+        self.emit(EventKind::Reset { room: interrupted });
         self.latch = ScrollLatch::default();
         self.grid_align = (0, 0);
         self.scroll = (0, 0);
         self.has_control = false;
-        self.splits = [(
-            Split {
-                scanline: 0,
-                scroll_x: 0,
-                scroll_y: 0,
-            },
-            Split {
-                scanline: 240,
-                scroll_x: 0,
-                scroll_y: 0,
-            },
-        )];
+        self.bands = vec![Band::whole_screen()];
+        self.main_band = 0;
+        self.other_bands.clear();
+        self.scroll_split = Band::whole_screen().top;
         self.now = Time(0);
         self.last_control = Time(0);
         self.maybe_control = false;
@@ -206,7 +330,6 @@ impl MappyState {
         self.live_blobs.clear();
         self.dead_blobs.clear();
         self.changes.clear();
-        self.change_count = 0;
         let s0 = Screen::new(
             Rect::new(0, 0, 0, 0),
             self.tiles.read().unwrap().get_initial_tile(),
@@ -214,104 +337,138 @@ impl MappyState {
         self.current_screen = s0.clone();
         self.last_control_screen = s0;
     }
-    // TODO return a "finalized mappy"
-    pub fn finish(&mut self) {
+    /// Ends the session: finalizes the current room, waits for every
+    /// pending merge, and hands over the finished map.
+    /// # Panics
+    /// Panics if the tile or room locks are poisoned
+    pub fn finish(mut self) -> FinalizedMap {
         self.finalize_current_room(false);
-        self.process_merges();
-        while THREADS_WAITING.load(Ordering::SeqCst) != 0 {
-            std::thread::sleep(std::time::Duration::from_millis(250));
-            self.process_merges();
+        self.wait_for_merges();
+        // One at a time, since each might change where the next one goes
+        while let Some(rid) = self.undecided.take_first() {
+            let room = self.rooms.read().unwrap()[rid].clone();
+            self.kickoff_merge_calc(room, MergePhase::Settle);
+            self.wait_for_merges();
         }
+        // A merge thread may not have dropped its handles quite yet
+        let tiles = Arc::try_unwrap(self.tiles).map_or_else(
+            |tiles| tiles.read().unwrap().clone(),
+            |tiles| tiles.into_inner().unwrap(),
+        );
+        let rooms = Arc::try_unwrap(self.rooms).map_or_else(
+            |rooms| rooms.read().unwrap().clone(),
+            |rooms| rooms.into_inner().unwrap(),
+        );
+        FinalizedMap::new(tiles, rooms, self.metarooms, self.resets)
+    }
+    /// A copy of the map built so far, without the room currently being
+    /// mapped or any merges still in progress.
+    /// # Panics
+    /// Panics if the tile or room locks are poisoned
+    #[must_use]
+    pub fn snapshot(&self) -> FinalizedMap {
+        FinalizedMap::new(
+            self.tiles.read().unwrap().clone(),
+            self.rooms.read().unwrap().clone(),
+            self.metarooms.clone(),
+            self.resets.clone(),
+        )
     }
 
+    /// Reads one frame from `emu` and updates tracking, control and mapping.
+    /// # Errors
+    /// Returns an error if `emu` can't provide the frame's data or can't
+    /// save and restore its state for control probes
     #[allow(clippy::similar_names, clippy::missing_panics_doc)]
-    pub fn process_screen(&mut self, emu: &mut Emulator, input: [Buttons; 2]) {
+    pub fn process_screen<S: FrameSource + ?Sized>(
+        &mut self,
+        emu: &mut S,
+        input: [Buttons; 2],
+    ) -> Result<(), MappyError> {
         // Read new data from emulator
         let t = self.timers.timer(Timing::FBRead).start();
-        self.fb.read_from(emu);
+        self.fb.read_from(emu)?;
         t.stop();
         let t = self.timers.timer(Timing::Scroll).start();
-        self.get_changes(emu);
+        self.get_changes(emu)?;
 
         // What can we learn from hardware screen splitting operations?
-        if !self.changes.is_empty() || self.splits.is_empty() {
-            let (lo, hi, latch) = splits::get_main_split(&self.changes, self.latch, &self.fb);
+        if !self.changes.is_empty() || self.bands.is_empty() {
+            let nametable = emu.ppuctrl()?;
+            let (bands, main, latch) = match self.config.split_override {
+                Some(fixed) => splits::get_fixed_bands(&self.changes, self.latch, nametable, fixed),
+                None => splits::get_bands(
+                    &self.changes,
+                    self.latch,
+                    nametable,
+                    &self.fb,
+                    self.config.hud_max_height,
+                ),
+            };
             self.latch = latch;
-            self.splits = [(lo, hi)];
+            if self.config.nametable_scroll {
+                for band in &bands {
+                    self.nametables.0 |= band.top.nametable & 0b01 != 0;
+                    self.nametables.1 |= band.top.nametable & 0b10 != 0;
+                }
+            }
+            let lo = bands[main].top;
+            self.bands = bands;
+            self.main_band = main;
+            self.follow_bands();
 
             // Update grid alignment and scrolling
-            let old_align = self.grid_align;
             self.grid_align = (lo.scroll_x, lo.scroll_y);
             if self.has_control {
                 self.last_controlled_scroll = self.scroll;
             }
-            // update scroll based on grid align change
-            // dbg!(old_align.1, self.grid_align.1, scrolling::find_offset(old_align.1, self.grid_align.1, 240));
-            self.scroll = (
-                self.scroll.0
-                    + i32::from(scrolling::find_offset(old_align.0, self.grid_align.0, 256)),
-                self.scroll.1
-                    + i32::from(scrolling::find_offset(old_align.1, self.grid_align.1, 240)),
-            );
+            // update scroll based on the change in PPU scroll
+            let (dx, dy) = splits::scroll_offset(self.scroll_split, lo, self.nametables);
+            self.scroll = (self.scroll.0 + dx, self.scroll.1 + dy);
+            self.scroll_split = lo;
         }
         t.stop();
         let t = self.timers.timer(Timing::ReadScreen).start();
         // Update current screen tile grid;
         // can't do it on moment 0 since we don't have sprites yet
         if self.now.0 > 0 {
-            self.read_current_screen(emu);
+            self.read_current_screen(emu)?;
         }
         t.stop();
 
         // avatar identification related:
         self.button_inputs.push(input[0]);
 
+        // Read sprite data for next frame now, since control probing leaves
+        // the emulator's layers showing one of the probe frames
+        let mut next_sprites = [SpriteData::default(); SPRITE_COUNT];
+        sprites::get_sprites(emu, &mut next_sprites)?;
+
         // Do we have control?
         let had_control = self.has_control;
         let last_control_time = self.last_control;
-        self.determine_control(emu);
+        self.determine_control(emu)?;
         self.mapping = false;
-        let Rect { w: sw, h: sh, .. } = self.current_screen.region;
+        // Did we just move to a new room?
+        self.detect_transition(last_control_time);
         if self.has_control {
-            let sdiff = scroll_diff(self.scroll, self.last_controlled_scroll);
-            if !had_control {
-                // println!(
-                //     "{:?}: Regained control after {:?}; scrolldiff {:?}",
-                //     self.now.0,
-                //     self.now.0 - last_control_time.0,
-                //     sdiff
-                // );
-            }
-            if self.now.0 - last_control_time.0 > Self::CONTROL_ROOM_CHANGE_THRESHOLD
-                || sdiff.0.unsigned_abs() >= (sw * 3) / 4
-                || sdiff.1.unsigned_abs() >= (sh * 3) / 4
-            {
-                let diff = self.current_screen.difference(&self.last_control_screen);
-                // if !had_control {
-                // println!(
-                //     "{:?}: Regained control after {:?}; diff {:?}, scrolldiff {:?}",
-                //     self.now.0,
-                //     self.now.0 - last_control_time.0,
-                //     diff,
-                //     scroll_diff(self.scroll, self.last_controlled_scroll)
-                // );
-                // }
-                let moderate_difference = diff > Self::SCREEN_ROOM_CHANGE_DIFF_MODERATE;
-                let big_difference = diff > Self::SCREEN_ROOM_CHANGE_DIFF_BIG;
-                let (sdx, sdy) = scroll_diff(self.scroll, self.last_controlled_scroll);
-                let small_scroll = (sdx != 0 || sdy != 0) && (sdx.abs() < 150 && sdy.abs() < 150);
-                if ((moderate_difference && !small_scroll) || big_difference)
-                    || self.current_room.is_none()
-                {
-                    self.finalize_current_room(true);
+            if let Some((confidence, detector)) = self.pending_transition.take() {
+                self.emit(EventKind::RoomTransition {
+                    detector,
+                    confidence,
+                });
+                self.finalize_current_room(true);
+                for detector in &mut self.transition_detectors {
+                    detector.clear();
                 }
             }
-            if self.control_duration > Self::CONTROL_ROOM_ENTER_DURATION {
+            if self.control_duration > self.config.control_room_enter_duration {
                 if let Some(current_room) = self.current_room.as_mut() {
                     self.mapping = true;
                     let t = self.timers.timer(Timing::Register).start();
-                    current_room
-                        .register_screen(&self.current_screen, &mut self.tiles.write().unwrap());
+                    let mut tiles = self.tiles.write().unwrap();
+                    tiles.set_time(self.now);
+                    current_room.register_screen(&self.current_screen, &mut tiles);
                     t.stop();
                 }
             }
@@ -327,7 +484,7 @@ impl MappyState {
         if DO_TEMP_MERGE_CHECKS
             && self.current_room.is_some()
             && self.now.0 % 300 == 0
-            && THREADS_WAITING.load(Ordering::SeqCst) == 0
+            && self.merges_pending == 0
         {
             //spawn room merge thing with self.room_merge_tx
             self.kickoff_merge_calc(
@@ -339,7 +496,7 @@ impl MappyState {
 
         let t = self.timers.timer(Timing::Track).start();
         // Relate current sprites to previous sprites
-        self.track_sprites(emu);
+        self.track_sprites();
         for track in &mut self.live_tracks {
             track.determine_avatar(self.now, &self.button_inputs);
         }
@@ -353,38 +510,125 @@ impl MappyState {
         // Update `now`
         self.now.0 += 1;
 
-        // Read sprite data for next frame
         self.prev_sprites.copy_from_slice(&self.live_sprites);
-        sprites::get_sprites(emu, &mut self.live_sprites);
+        self.live_sprites = next_sprites;
+        Ok(())
+    }
+    fn follow_bands(&mut self) {
+        let others: Vec<Band> = self
+            .bands
+            .iter()
+            .enumerate()
+            .filter(|&(i, band)| i != self.main_band && band.kind == BandKind::Playfield)
+            .map(|(_, band)| *band)
+            .collect();
+        if others.len() == self.other_bands.len() {
+            for (bs, band) in self.other_bands.iter_mut().zip(others) {
+                bs.follow(band, self.nametables);
+            }
+        } else {
+            // The layout changed, so the old scrolls don't carry over
+            self.other_bands = others
+                .into_iter()
+                .map(|band| BandScroll::new(band, self.nametables))
+                .collect();
+        }
+    }
+    fn detect_transition(&mut self, last_control: Time) {
+        let ctx = TransitionContext {
+            now: self.now,
+            fb: &self.fb,
+            scroll: self.scroll,
+            has_control: self.has_control,
+            last_control,
+            last_controlled_scroll: self.last_controlled_scroll,
+            current_screen: &self.current_screen,
+            last_control_screen: &self.last_control_screen,
+            in_room: self.current_room.is_some(),
+            config: &self.config,
+        };
+        let threshold = self.config.room_transition_threshold;
+        for detector in &mut self.transition_detectors {
+            let confidence = detector.observe(&ctx);
+            if confidence >= threshold
+                && self
+                    .pending_transition
+                    .is_none_or(|(surest, _)| confidence > surest)
+            {
+                self.pending_transition = Some((confidence, detector.name()));
+            }
+        }
+    }
+    fn wait_for_merges(&mut self) {
+        while self.merges_pending > 0 {
+            // We hold a sender, so this only fails if a merge job panicked
+            let merge = self
+                .room_merge_rx
+                .recv()
+                .expect("Couldn't receive merge message");
+            self.handle_merge(merge);
+        }
     }
     fn process_merges(&mut self) {
-        if !self.room_merge_rx.is_empty() {
-            //let mut metarooms = self.metarooms.write().unwrap();
-            while let Ok(DoMerge(phase, room_id, metas)) = self.room_merge_rx.try_recv() {
-                match phase {
-                    MergePhase::Intermediate => {
-                        // for (metaroom, posn, cost) in metas {
-                        //metarooms[meta].merge_room(room_id, posn, cost);
-                        // println!(
-                        //     "Temp merge {} with {:?}: {}@{:?}",
-                        //     room_id, metaroom, cost, posn
-                        // );
-                        // println!(
-                        //     "RR:{:?}\nMRR:{:?}",
-                        //     self.current_room.as_ref().unwrap().region(),
-                        //     self.metarooms
-                        //         .metaroom(metaroom.0)
-                        //         .region(&(*self.rooms.read().unwrap()))
-                        // )
-                        // }
-                    }
-                    MergePhase::Finalize => {
-                        //let room_meta = self.metarooms.insert(room_id);
-                        let t = self.timers.timer(Timing::FinishMerge).start();
-                        self.metarooms.merge_new_room(room_id, &metas);
-                        t.stop();
-                    }
+        while let Ok(merge) = self.room_merge_rx.try_recv() {
+            self.handle_merge(merge);
+        }
+    }
+    fn handle_merge(&mut self, DoMerge(phase, room_id, candidates): DoMerge) {
+        self.merges_pending -= 1;
+        match phase {
+            MergePhase::Intermediate => {
+                // for (metaroom, posn, cost) in metas {
+                //metarooms[meta].merge_room(room_id, posn, cost);
+                // println!(
+                //     "Temp merge {} with {:?}: {}@{:?}",
+                //     room_id, metaroom, cost, posn
+                // );
+                // println!(
+                //     "RR:{:?}\nMRR:{:?}",
+                //     self.current_room.as_ref().unwrap().region(),
+                //     self.metarooms
+                //         .metaroom(metaroom.0)
+                //         .region(&(*self.rooms.read().unwrap()))
+                // )
+                // }
+            }
+            MergePhase::Finalize | MergePhase::Settle => {
+                // The job worked on a copy of the metarooms; if another merge
+                // has taken some of them in since, place the room again
+                if candidates
+                    .iter()
+                    .any(|(mid, _)| !self.metarooms.is_unmerged(*mid))
+                {
+                    let room = self.rooms.read().unwrap()[room_id].clone();
+                    self.kickoff_merge_calc(room, phase);
+                    return;
+                }
+                let metas = best_placements(&candidates);
+                let min_margin = self.config.merge_min_margin;
+                if matches!(phase, MergePhase::Finalize)
+                    && metas.iter().any(|(_, _, c)| c.is_close_call(min_margin))
+                {
+                    self.undecided.defer(room_id, &candidates);
+                    self.emit(EventKind::MergeDeferred(room_id));
+                    return;
                 }
+                //let room_meta = self.metarooms.insert(room_id);
+                let t = self.timers.timer(Timing::FinishMerge).start();
+                let into = self.metarooms.merge_new_room(room_id, &metas);
+                t.stop();
+                let merged: Vec<_> = metas.iter().map(|(mid, _, _)| *mid).collect();
+                // Rooms waiting on the metarooms just taken in might be
+                // settled now
+                for rid in self.undecided.take_affected(&merged) {
+                    let room = self.rooms.read().unwrap()[rid].clone();
+                    self.kickoff_merge_calc(room, MergePhase::Finalize);
+                }
+                self.emit(EventKind::MetaroomMerged {
+                    room: room_id,
+                    into,
+                    merged,
+                });
             }
         }
     }
@@ -409,10 +653,14 @@ impl MappyState {
                 self.current_room.take().unwrap()
                 // println!("Room end {}: {:?}", old_room.id, old_room.region());
             };
-            old_room = old_room.finalize(self.tiles.read().unwrap().get_initial_change());
+            old_room = old_room.finalize();
+            // Merging compares animated tiles by their animations
+            self.tiles.write().unwrap().detect_animations();
             // dbg!(old_room.region());
+            let old_id = old_room.id;
             self.kickoff_merge_calc(old_room.clone(), MergePhase::Finalize);
             self.rooms.write().unwrap().push(old_room);
+            self.emit(EventKind::RoomFinalized(old_id));
         } else if start_new {
             let id = self.rooms.read().unwrap().len();
             // println!("Room refresh {}", id);
@@ -422,42 +670,47 @@ impl MappyState {
                 &mut self.tiles.write().unwrap(),
             ));
         }
+        if start_new && let Some(id) = self.current_room.as_ref().map(|cr| cr.id) {
+            self.emit(EventKind::RoomEntered(id));
+        }
         t.stop();
     }
-    fn kickoff_merge_calc(&self, room: Room, phase: MergePhase) {
+    fn kickoff_merge_calc(&mut self, room: Room, phase: MergePhase) {
         let tiles = Arc::clone(&self.tiles);
         let rooms = Arc::clone(&self.rooms);
         let mrs = self.metarooms.clone();
-        let tx = Arc::clone(&self.room_merge_tx);
+        let tx = self.room_merge_tx.clone();
         let timer = self.timers.timer(Timing::MergeCalc);
-        THREADS_WAITING.fetch_add(1, Ordering::SeqCst);
+        let threshold = self.config.room_merge_threshold;
+        let search = self.config.merge_search;
+        let candidates = self.config.merge_candidates;
+        self.merges_pending += 1;
         // TODO only do this if the current room histogram is different from last merge-checked room histogram
-        spawn_fifo(move || {
+        // The parallel iterator below runs on merge_pool too, since that's where the job is
+        self.merge_pool.spawn_fifo(move || {
             let timer = timer.start();
             let merges = mrs
                 .metarooms()
                 .collect::<Vec<_>>()
                 .into_par_iter()
                 .filter_map(|metaroom| {
-                    // TODO make sure room has significant histogram overlap with at least one room in metaroom
-                    if let Some((p, c)) = merge_cost(
+                    let placements = merge_cost(
                         &room,
                         metaroom.id,
                         &metaroom.registrations,
                         &rooms,
                         &tiles,
-                        Self::ROOM_MERGE_THRESHOLD,
-                    ) {
-                        Some((metaroom.id, p, c))
-                    } else {
-                        None
-                    }
+                        threshold,
+                        search,
+                        candidates,
+                    );
+                    (!placements.is_empty()).then_some((metaroom.id, placements))
                 })
                 .collect();
             timer.stop();
-            tx.send(DoMerge(phase, room.id, merges))
-                .expect("Couldn't send merge message");
-            THREADS_WAITING.fetch_sub(1, Ordering::SeqCst);
+            // Fails only if the MappyState was dropped without finishing,
+            // in which case nobody wants the result
+            let _ = tx.send(DoMerge(phase, room.id, merges));
         });
     }
 
@@ -466,7 +719,10 @@ impl MappyState {
         clippy::cast_possible_wrap,
         clippy::cast_sign_loss
     )]
-    fn read_current_screen(&mut self, emulator: &Emulator) {
+    fn read_current_screen<S: FrameSource + ?Sized>(
+        &mut self,
+        emulator: &S,
+    ) -> Result<(), MappyError> {
         // if a clear sprite is overlapping a tile, then just place that tile
         // overlapping sprite check. See if it's a tile that's already been seen
 
@@ -474,104 +730,116 @@ impl MappyState {
         // the magic token for "empty" is 191.
 
         let mut tiles = self.tiles.write().unwrap();
+        let layers = emulator.layers()?;
         let region = self.split_region();
-        self.current_screen = Screen::new(
+        let new_ts;
+        (self.current_screen, new_ts) = Self::read_screen(
+            &mut tiles,
+            layers,
+            &self.fb,
+            region,
+            self.scroll,
+            &self.config.menu_regions,
+        );
+        if new_ts > 10 {
+            // println!("{:?} new tiles", new_ts);
+            MappyState::dump_tiles_single(
+                &Path::new("out").join(format!("tiles_{}.png", self.now.0)),
+                &tiles,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Reads the tiles of screen-space `region` into a screen at `scroll`,
+    /// and counts the tiles that weren't in `tiles` yet.  Tiles get their
+    /// true colors from the first full color frame where no sprite covers
+    /// them.
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_possible_wrap,
+        clippy::cast_sign_loss
+    )]
+    fn read_screen(
+        tiles: &mut TileDB,
+        [bg_sp, bg, fg_sp]: [&[u8]; 3],
+        fb: &Framebuffer,
+        region: Rect,
+        scroll: (i32, i32),
+        menu_regions: &[Rect],
+    ) -> (Screen<TileGfxId>, usize) {
+        let mut screen = Screen::new(
             Rect::new(
-                (self.scroll.0 + region.x) / (TILE_SIZE as i32),
-                (self.scroll.1 + region.y) / (TILE_SIZE as i32),
+                (scroll.0 + region.x) / (TILE_SIZE as i32),
+                (scroll.1 + region.y) / (TILE_SIZE as i32),
                 region.w / (TILE_SIZE as u32),
                 region.h / (TILE_SIZE as u32),
             ),
             tiles.get_initial_tile(),
         );
         let mut new_ts = 0;
-        unsafe {
-            // safety: bg_sp, bg, fg_sp may not leak out and we can't run the emulator while they're live
-            let [_bg_sp, bg, _fg_sp] = Self::get_layers(emulator);
-            for y in (region.y..(region.y + region.h as i32)).step_by(TILE_SIZE) {
-                for x in (region.x..(region.x + region.w as i32)).step_by(TILE_SIZE) {
-                    let tile =
-                        TileGfx::read_slice(bg, self.fb.w, self.fb.h, x as usize, y as usize);
-                    if !tiles.contains(&tile) {
-                        new_ts += 1;
-                        // println!("Unaccounted-for tile, {},{} hash {}", (x-region.x)/(TILE_SIZE as i32), (y-region.y)/(TILE_SIZE as i32), tile.perceptual_hash());
-                    }
-                    self.current_screen.set(
-                        tiles.get_tile(tile),
-                        (self.scroll.0 + x) / (TILE_SIZE as i32),
-                        (self.scroll.1 + y) / (TILE_SIZE as i32),
-                    );
+        for y in (region.y..(region.y + region.h as i32)).step_by(TILE_SIZE) {
+            for x in (region.x..(region.x + region.w as i32)).step_by(TILE_SIZE) {
+                let tile_rect = Rect::new(x, y, TILE_SIZE as u32, TILE_SIZE as u32);
+                if menu_regions.iter().any(|menu| menu.overlaps(&tile_rect)) {
+                    continue;
                 }
+                let tile = TileGfx::read_slice(bg, fb.w, fb.h, x as usize, y as usize);
+                if !tiles.contains(&tile) {
+                    new_ts += 1;
+                    // println!("Unaccounted-for tile, {},{} hash {}", (x-region.x)/(TILE_SIZE as i32), (y-region.y)/(TILE_SIZE as i32), tile.perceptual_hash());
+                }
+                let id = tiles.get_tile(tile);
+                if !fb.rgb.is_empty()
+                    && tiles.colors(id).is_none()
+                    && [bg_sp, fg_sp].into_iter().all(|layer| {
+                        TileGfx::read_slice(layer, fb.w, fb.h, x as usize, y as usize)
+                            .0
+                            .iter()
+                            .all(|&px| px == 191)
+                    })
+                {
+                    tiles.set_colors(id, read_colors(&fb.rgb, fb.w, x as usize, y as usize));
+                }
+                screen.set(
+                    id,
+                    (scroll.0 + x) / (TILE_SIZE as i32),
+                    (scroll.1 + y) / (TILE_SIZE as i32),
+                );
             }
         }
-        if new_ts > 10 {
-            // println!("{:?} new tiles", new_ts);
-            MappyState::dump_tiles_single(
-                &Path::new("out").join(format!("tiles_{}.png", self.now.0)),
-                &tiles,
-            );
-        }
+        (screen, new_ts)
     }
 
-    fn determine_control(&mut self, emu: &mut Emulator) {
-        // should be long enough to fight momentum
-        const CONTROL_CHECK_K: usize = 17;
-        // should be odd
-        const CONTROL_CHECK_INTERVAL: usize = 7;
-        if self.now.0 % CONTROL_CHECK_INTERVAL != 0 {
-            return;
+    fn determine_control<S: FrameSource + ?Sized>(
+        &mut self,
+        emu: &mut S,
+    ) -> Result<(), MappyError> {
+        let control_check_k = self.config.control_check_k;
+        if self.now.0 % self.config.control_check_interval != 0 {
+            return Ok(());
         }
         let t = self.timers.timer(Timing::Control).start();
         // every A frames...
-        // We'll start with the expensive version and later try the cheaper version if that's too slow.
-        // Expensive version:
-        // Save state S.
-        if self.state_buffer.is_empty() {
-            self.state_buffer = vec![0; emu.save_size()];
-        }
-        if !emu.save(&mut self.state_buffer) {
-            println!("Failed to save state");
-            return;
-        }
-        // Apply down-left and b input for K frames
-        // TODO: in mario 3 on the level select screen simultaneous presses sometimes cause no movement.  Consider random or alternating down and left and b presses?
-        let down_left = Buttons::new()
-            .down(true)
-            .left(true)
-            .b(self.now.0 % 2 == 0)
-            .a(self.now.0 % 2 == 1);
-        for _ in 0..CONTROL_CHECK_K {
-            emu.run([down_left, Buttons::default()]);
-        }
-        // What can we learn from hardware screen splitting operations?
-        self.get_changes(emu);
-        let latch = self.latch;
-        let (dl_splits, _latch) = splits::get_splits(&self.changes, latch);
-        // Store positions of all sprites P1
-        let mut sprites_dlb = [SpriteData::default(); SPRITE_COUNT];
-        sprites::get_sprites(emu, &mut sprites_dlb);
-        // Load state S.
-        if !emu.load(&self.state_buffer) {
-            println!("failed to load state, ss {} vs state size {}", emu.save_size(), self.state_buffer.len());
-            return;
-        }
-        // Apply up-right and a input for K frames
-        let up_right = Buttons::new()
-            .up(true)
-            .right(true)
-            .a(self.now.0 % 2 == 0)
-            .b(self.now.0 % 2 == 1);
-        for _ in 0..CONTROL_CHECK_K {
-            emu.run([up_right, Buttons::default()]);
-        }
-        self.get_changes(emu);
-        let latch = self.latch;
-        let (ur_splits, _latch) = splits::get_splits(&self.changes, latch);
-        // Store positions of all sprites P2
-        let mut sprites_ura = [SpriteData::default(); SPRITE_COUNT];
-        sprites::get_sprites(emu, &mut sprites_ura);
-        // If P1 != P2 or scroll different, we have control; otherwise we do not
-        if !(sprites_dlb == sprites_ura) || dl_splits != ur_splits {
+        // Ask the detector (unless the source already knows the outcome):
+        let responded = match emu.recorded_probe() {
+            Some(responded) => responded,
+            None => {
+                let ctx = ControlContext {
+                    now: self.now,
+                    latch: self.latch,
+                    inputs: &self.button_inputs,
+                    live_tracks: &self.live_tracks,
+                    config: &self.config,
+                };
+                let mut emu = &mut *emu;
+                let responded = self.control_detector.responded(&ctx, &mut emu)?;
+                emu.report_probe(responded);
+                responded
+            }
+        };
+        let had_control = self.has_control;
+        if responded {
             if !self.maybe_control {
                 self.maybe_control_change_time = self.now;
             }
@@ -581,11 +849,13 @@ impl MappyState {
         }
         self.has_control = self.maybe_control
             && (self.has_control
-                || (self.now.0 - self.maybe_control_change_time.0 > CONTROL_CHECK_K));
-        // Load state S.
-        if !emu.load(&self.state_buffer) {
-            println!("failed to load state");
-            return;
+                || (self.now.0 - self.maybe_control_change_time.0 > control_check_k));
+        if self.has_control != had_control {
+            self.emit(if self.has_control {
+                EventKind::ControlGained
+            } else {
+                EventKind::ControlLost
+            });
         }
 
         // Cheaper version:
@@ -612,6 +882,7 @@ impl MappyState {
         }
 
         t.stop();
+        Ok(())
     }
 
     // TODO: increase cost if this would alter blobbing?
@@ -636,7 +907,7 @@ impl MappyState {
         clippy::cast_possible_truncation,
         clippy::cast_sign_loss
     )]
-    fn track_sprites(&mut self, _emu: &Emulator) {
+    fn track_sprites(&mut self) {
         use matching::{Match, MatchTo, Target, bnb_match};
         // break up the candidates vec into separate vecs with options that overlap on any index
         fn connected_components(candidates: Vec<MatchTo>) -> Vec<Vec<MatchTo>> {
@@ -682,12 +953,15 @@ impl MappyState {
             components
         }
         let now = self.now;
+        let destroy_coast = self.config.destroy_coast;
         let dead_tracks = &mut self.dead_tracks;
         let live_blobs = &mut self.live_blobs;
+        let mut dead_track_ids = vec![];
         let mut dead_blob_ids = vec![];
         self.live_tracks.retain(|t| {
-            if now.0 - t.last_observation_time().0 > Self::DESTROY_COAST {
+            if now.0 - t.last_observation_time().0 > destroy_coast {
                 let id = t.id;
+                dead_track_ids.push(id);
                 // println!("{:?} kill {:?}",now,id);
                 // TODO this clone shouldn't be necessary
                 dead_tracks.push(t.clone());
@@ -715,6 +989,12 @@ impl MappyState {
                 true
             }
         });
+        for id in dead_track_ids {
+            self.emit(EventKind::TrackKilled(id));
+        }
+        for id in dead_blob_ids {
+            self.emit(EventKind::BlobKilled(id));
+        }
         // find minimal matching of sprites
         // local search is okay
         // vec<vec> is worrisome
@@ -725,6 +1005,8 @@ impl MappyState {
             .collect();
         // dbg!("live", &live);
         // a candidate old track for each new track
+        let create_cost = self.config.create_cost;
+        let distance_max = self.config.distance_max;
         let candidates: Vec<_> = live
             .iter()
             .map(|s| {
@@ -733,7 +1015,7 @@ impl MappyState {
                     .iter()
                     .enumerate()
                     .filter_map(|(ti, old)| {
-                        if (s.distance(old.current_data()) as u32) < Self::DISTANCE_MAX {
+                        if (s.distance(old.current_data()) as u32) < distance_max {
                             Some(Target(Some(ti), Self::sprite_change_cost(s, old)))
                         } else {
                             None
@@ -748,10 +1030,10 @@ impl MappyState {
                         options
                             .into_iter()
                             .find(|Target(oi, _cost)| *oi == Some(s.index as usize))
-                            .unwrap_or(Target(None, Self::CREATE_COST)),
+                            .unwrap_or(Target(None, create_cost)),
                     ];
                 } else {
-                    options.insert(0, Target(None, Self::CREATE_COST));
+                    options.insert(0, Target(None, create_cost));
                 }
                 MatchTo(s.index as usize, options)
             })
@@ -777,12 +1059,15 @@ impl MappyState {
                     None => {
                         // println!("Create new {:?}", new);
                         _new_count += 1;
-                        self.live_tracks.push(SpriteTrack::new(
+                        let track = SpriteTrack::new(
                             self.live_tracks.len() + self.dead_tracks.len(),
                             self.now,
                             self.scroll,
                             self.live_sprites[new],
-                        ));
+                        );
+                        let id = track.id;
+                        self.live_tracks.push(track);
+                        self.emit(EventKind::TrackCreated(id));
                         // println!("{:?} create {:?}", now, self.live_tracks.last().unwrap().id);
                     }
                     Some(oldi) => {
@@ -822,14 +1107,14 @@ impl MappyState {
                         b.blob_score(
                             &self.live_tracks[*tx],
                             &self.live_tracks,
-                            Self::BLOB_LOOKBACK,
+                            self.config.blob_lookback,
                             self.now,
                         ),
                     )
                 })
                 .min_by(|(_b1, s1), (_b2, s2)| s1.partial_cmp(s2).unwrap())
             {
-                if score < Self::BLOB_THRESHOLD {
+                if score < self.config.blob_threshold {
                     // TODO: is this already part of some other blob?  give some penalty?
                     assigned_tracks.push((*tx, bi));
                     // assign
@@ -887,9 +1172,9 @@ impl MappyState {
                 if SpriteBlob::blob_score_pair(
                     &self.live_tracks[tx],
                     &self.live_tracks[ty],
-                    Self::BLOB_LOOKBACK,
+                    self.config.blob_lookback,
                     self.now,
-                ) < Self::BLOB_THRESHOLD
+                ) < self.config.blob_threshold
                 {
                     let mut blob = SpriteBlob::new(self.dead_blobs.len() + self.live_blobs.len());
                     blob.use_track(self.live_tracks[tx].id);
@@ -904,16 +1189,18 @@ impl MappyState {
                         if blob.blob_score(
                             &self.live_tracks[tz],
                             &self.live_tracks,
-                            Self::BLOB_LOOKBACK,
+                            self.config.blob_lookback,
                             self.now,
-                        ) < Self::BLOB_THRESHOLD
+                        ) < self.config.blob_threshold
                         {
                             blob.use_track(self.live_tracks[tz].id);
                             // println!("{:?} extend blob {:?} with {:?}", now, blob.id, self.live_tracks[tz].id);
                             blobbed.push(tzi);
                         }
                     }
+                    let id = blob.id;
                     self.live_blobs.push(blob);
+                    self.emit(EventKind::BlobCreated(id));
                 }
             }
         }
@@ -946,63 +1233,20 @@ impl MappyState {
     #[must_use]
     pub fn split_region(&self) -> Rect {
         splits::split_region_for(
-            u32::from(self.splits[0].0.scanline),
-            u32::from(self.splits[0].1.scanline),
+            u32::from(self.bands[self.main_band].top.scanline),
+            u32::from(self.bands[self.main_band].bottom.scanline),
             self.grid_align.0,
             self.grid_align.1,
             self.fb.w as u32,
             self.fb.h as u32,
+            &self.config.screen_safe,
         )
     }
 
-    fn get_changes(&mut self, emu: &Emulator) {
-        let get_changes_fn: Symbol<unsafe extern "C" fn(*mut ScrollChange, u32) -> u32> =
-            emu.get_symbol(b"retro_count_scroll_changes").unwrap();
-        unsafe {
-            self.change_count = get_changes_fn(std::ptr::null_mut(), 0);
-            self.changes
-                .resize_with(self.change_count as usize, Default::default);
-            get_changes_fn(self.changes.as_mut_ptr(), self.change_count);
-        }
-    }
-    // safety: these slices don't belong to us so we should drop them
-    // before the next time FCEU emulation happens
-    #[allow(clippy::similar_names)]
-    pub(crate) unsafe fn get_layers(emu: &Emulator) -> [&[u8]; 3] {
-        unsafe {
-            let get_layer_fn: Symbol<unsafe extern "C" fn(i32) -> *const u8> =
-                emu.get_symbol(b"retro_layer").unwrap();
-            let sz = 256 * 240;
-            let sp_bg = get_layer_fn(0);
-            let bg = get_layer_fn(1);
-            let sp_fg = get_layer_fn(2);
-            [
-                std::slice::from_raw_parts(sp_bg, sz),
-                std::slice::from_raw_parts(bg, sz),
-                std::slice::from_raw_parts(sp_fg, sz),
-            ]
-        }
+    fn get_changes<S: FrameSource + ?Sized>(&mut self, emu: &S) -> Result<(), MappyError> {
+        emu.scroll_changes(&mut self.changes)
     }
 
-    #[must_use]
-    pub fn metaroom_exits(&self, mr: &Metaroom) -> Vec<MetaroomID> {
-        let mut out_to = vec![];
-        for (rid, _pos) in &mr.registrations {
-            if self.resets.contains(rid) {
-                continue;
-            }
-            if let Some(mr2) = self
-                .metarooms
-                .metarooms()
-                .find(|mri| mri.registrations.iter().any(|(mrrid, _)| *mrrid == rid + 1))
-            {
-                if !out_to.contains(&mr2.id) {
-                    out_to.push(mr2.id);
-                }
-            }
-        }
-        out_to
-    }
     #[must_use]
     #[allow(clippy::cast_possible_wrap, clippy::cast_possible_truncation)]
     pub fn world_to_tile(&self, wx: i32, wy: i32) -> (i32, i32) {
@@ -1032,106 +1276,22 @@ impl MappyState {
         self.world_to_screen(wx, wy)
     }
 
-    /// # Panics
-    /// Panics if the room mutex can't be obtained, or if an I/O error takes place
-    pub fn dump_map(&self, dotfolder: &Path) {
-        use std::collections::BTreeMap;
-        use std::fs;
-        use tabbycat::attributes::{Shape, image, shape, xlabel};
-        use tabbycat::{AttrList, Edge, GraphBuilder, GraphType, Identity, StmtList};
-        let rooms = &self.rooms.read().unwrap();
-        let gname = "map".to_string();
-        let node_image_paths: BTreeMap<usize, String> = self
-            .metarooms
-            .all_metarooms()
-            .map(|mr| (mr.id.0, format!("mr_{}.png", mr.id.0)))
-            .collect();
-        let node_labels: BTreeMap<usize, String> = self
-            .metarooms
-            .metarooms()
-            .map(|mr| {
-                let r = mr.region(rooms);
-                (
-                    mr.id.0,
-                    format!("{},{}<>{},{}\n", r.x, r.y, r.w, r.h)
-                        + &mr
-                            .registrations
-                            .iter()
-                            .map(|(ri, pos)| format!("{}@{},{}", ri, pos.0, pos.1))
-                            .collect::<Vec<_>>()
-                            .join("\n"),
-                )
-            })
-            .collect();
-        let mut all_stmts = StmtList::new();
-        for mr in self.metarooms.all_metarooms() {
-            self.dump_metaroom(
-                mr,
-                &dotfolder.join(Path::new(&node_image_paths[&mr.id.0].clone())),
-            );
-        }
-        for mr in self.metarooms.metarooms() {
-            let mut stmts = StmtList::new();
-            let mr_ident = Identity::from(mr.id.0);
-            let mut attrs = AttrList::new()
-                .add_pair(xlabel(&node_labels[&mr.id.0]))
-                .add_pair(image(&node_image_paths[&mr.id.0]));
-            if mr
-                .registrations
-                .iter()
-                .any(|(rid, _pos)| *rid == 0 || self.resets.contains(rid))
-            {
-                attrs = attrs.add_pair(shape(Shape::Box));
-            } else {
-                attrs = attrs.add_pair(shape(Shape::Plain));
-            }
-            stmts = stmts.add_node(mr_ident.clone(), None, Some(attrs));
-            for mr2_id in self.metaroom_exits(mr) {
-                stmts = stmts.add_edge(
-                    Edge::head_node(mr_ident.clone(), None)
-                        .arrow_to_node(Identity::from(mr2_id.0), None),
-                );
-            }
-            all_stmts = all_stmts.extend(stmts);
-        }
-        let graph = GraphBuilder::default()
-            .graph_type(GraphType::DiGraph)
-            .strict(false)
-            .id(Identity::id(&gname).unwrap())
-            .stmts(all_stmts)
-            .build()
-            .unwrap();
-        fs::write(dotfolder.join(Path::new("graph.dot")), graph.to_string()).unwrap();
-    }
-    /// # Panics
-    /// May panic if the tile index mutex is poisoned, or if there's an I/O error
-    #[allow(clippy::cast_possible_truncation)]
-    pub fn dump_tiles(&self, root: &Path) {
-        let mut buf = vec![0_u8; TILE_SIZE * TILE_SIZE * 3];
-        for (ti, tile) in self.tiles.read().unwrap().gfx_iter().enumerate() {
-            tile.write_rgb888(&mut buf);
-            let img: ImageBuffer<Rgb<u8>, _> =
-                ImageBuffer::from_raw(TILE_SIZE as u32, TILE_SIZE as u32, &buf[..])
-                    .expect("Couldn't create image buffer");
-            img.save(root.join(format!("t{ti:}.png"))).unwrap();
-        }
-    }
-    /// # Panics
-    /// May panic if the tile index mutex is poisoned, or if there's an I/O error
+    /// # Errors
+    /// Returns an error if the image can't be written
     #[allow(
         clippy::cast_possible_truncation,
         clippy::cast_sign_loss,
         clippy::cast_precision_loss
     )]
-    pub fn dump_tiles_single(where_to: &Path, tiles: &TileDB) {
-        let all_gfx: Vec<_> = tiles.gfx_iter().collect();
+    pub fn dump_tiles_single(where_to: &Path, tiles: &TileDB) -> Result<(), MappyError> {
+        let all_gfx: Vec<_> = tiles.gfx_ids().collect();
         let colrows = (all_gfx.len() as f32).sqrt().ceil() as usize;
         let mut t_buf = vec![0_u8; TILE_SIZE * TILE_SIZE * 3];
         let mut buf = vec![0_u8; colrows * colrows * TILE_SIZE * TILE_SIZE * 3];
         for (ti, tile) in all_gfx.into_iter().enumerate() {
             let row = ti / colrows;
             let col = ti % colrows;
-            tile.write_rgb888(&mut t_buf);
+            tiles.write_rgb888(tile, &mut t_buf);
             for trow in 0..TILE_SIZE {
                 let image_step = TILE_SIZE * 3;
                 let image_pitch = colrows * image_step;
@@ -1157,88 +1317,52 @@ impl MappyState {
             &buf[..],
         )
         .expect("Couldn't create image buffer");
-        img.save(where_to).unwrap();
-    }
-
-    /// # Panics
-    /// May panic if the tile index mutex is poisoned, or if there's an I/O error
-    #[allow(
-        clippy::cast_possible_wrap,
-        clippy::cast_sign_loss,
-        clippy::cast_possible_truncation
-    )]
-    pub fn dump_room(&self, room: &Room, at: (u32, u32), tiles_wide: u32, buf: &mut [u8]) {
-        let region = room.region();
-        let tiles = self.tiles.read().unwrap();
-        for y in region.y..(region.y + region.h as i32) {
-            for x in region.x..(region.x + region.w as i32) {
-                let tile = room.get(x, y);
-                let tile_change_data_db =
-                    tiles.get_change_by_id(tile.unwrap_or_else(|| tiles.get_initial_change()));
-                let to_tile_gfx_id = tile_change_data_db.unwrap().to;
-                let corresponding_tile_gfx = tiles.get_tile_by_id(to_tile_gfx_id);
-                corresponding_tile_gfx.unwrap().write_rgb888_at(
-                    ((x + at.0 as i32 - region.x) * (TILE_SIZE as i32)) as usize,
-                    ((y + at.1 as i32 - region.y) * (TILE_SIZE as i32)) as usize,
-                    buf,
-                    tiles_wide as usize * TILE_SIZE,
-                );
-            }
-        }
+        img.save(where_to)?;
+        Ok(())
     }
 
+    /// # Errors
+    /// Returns an error if the image can't be written
     /// # Panics
-    /// May panic if the tile index mutex is poisoned, or if there's an I/O error
+    /// May panic if the tile index mutex is poisoned
     #[allow(clippy::cast_possible_truncation)]
-    pub fn dump_current_room(&self, path: &Path) {
+    pub fn dump_current_room(&self, path: &Path) -> Result<(), MappyError> {
         let Some(room) = self.current_room.as_ref() else {
-            return;
+            return Ok(());
         };
         let region = room.region();
         let mut buf =
             vec![0_u8; TILE_SIZE * (region.w as usize) * TILE_SIZE * (region.h as usize) * 3];
-        self.dump_room(room, (0, 0), region.w, &mut buf);
-        let img = ImageBuffer::<Rgb<u8>, _>::from_raw(
-            region.w * TILE_SIZE as u32,
-            region.h * TILE_SIZE as u32,
-            &buf[..],
-        )
-        .expect("Couldn't create image buffer");
-        img.save(path).unwrap();
-    }
-
-    /// # Panics
-    /// May panic if a mutex is poisoned or if there's an I/O error
-    #[allow(clippy::cast_sign_loss, clippy::cast_possible_truncation)]
-    pub fn dump_metaroom(&self, mr: &Metaroom, path: &Path) {
-        // need to dump every room into the same image.
-        // so, first get net region of metaroom and build the image buffer.
-        // then offset every reg so that the toppiest leftiest reg is at 0,0.
-        let Ok(rooms) = self.rooms.read() else {
-            return;
-        };
-        let region = mr.region(&rooms);
-        let mut buf =
-            vec![0_u8; TILE_SIZE * (region.w as usize) * TILE_SIZE * (region.h as usize) * 3];
-        for (room_i, pos) in &mr.registrations {
-            assert!(pos.0 - region.x >= 0);
-            assert!(pos.1 - region.y >= 0);
-            let new_pos = ((pos.0 - region.x) as u32, (pos.1 - region.y) as u32);
-            self.dump_room(&rooms[*room_i], new_pos, region.w, &mut buf);
-        }
+        finalized::draw_room(
+            &self.tiles.read().unwrap(),
+            room,
+            (0, 0),
+            region.w,
+            &mut buf,
+        );
         let img = ImageBuffer::<Rgb<u8>, _>::from_raw(
             region.w * TILE_SIZE as u32,
             region.h * TILE_SIZE as u32,
             &buf[..],
         )
         .expect("Couldn't create image buffer");
-        img.save(path).unwrap();
+        img.save(path)?;
+        Ok(())
     }
 }
 
+/// The (at most) `candidates` best places `room` fits in `metaroom` for less
+/// than `threshold`, best first; ties go to the first offset scanned.  No
+/// two are [near](Placement::is_near) each other.  `search` picks which
+/// offsets are tried, though both searches find the same placements.
 /// # Panics
 /// May panic if a mutex is poisoned
-#[allow(clippy::similar_names, clippy::cast_possible_wrap)]
+#[allow(
+    clippy::similar_names,
+    clippy::cast_possible_wrap,
+    clippy::too_many_lines,
+    clippy::too_many_arguments
+)]
 pub fn merge_cost(
     room: &Room,
     _metaroom_id: MetaroomID,
@@ -1246,8 +1370,11 @@ pub fn merge_cost(
     rooms: &RwLock<Vec<Room>>,
     tiles: &RwLock<TileDB>,
     mut threshold: f32,
-) -> Option<((i32, i32), f32)> {
-    let mut best = None;
+    search: MergeSearch,
+    candidates: usize,
+) -> Vec<Placement> {
+    let candidates = candidates.max(1);
+    let mut best: Vec<Placement> = Vec::with_capacity(candidates + 1);
     let ar = room.region();
     let br = {
         let rooms = rooms.read().unwrap();
@@ -1277,90 +1404,126 @@ pub fn merge_cost(
     let rooms = rooms.read().unwrap();
     let tiles = tiles.read().unwrap();
     let initial = tiles.get_initial_change();
-    for yo in top..bot {
-        'reg: for xo in left..right {
-            // put top left of room at x,y and match
-            let mut cost = 0.0;
-            let mut comparisons = 0;
-            // for each tile of the merged room, find the least costly
-            // way to match this tile against the correspond tile of
-            // any example in the room
-            for ry in 0..(ar.h as i32) {
-                for rx in 0..(ar.w as i32) {
-                    let ax = ar.x + rx;
-                    let ay = ar.y + ry;
-                    // let bx = br.x + xo + rx;
-                    // let by = br.y + yo + ry;
-                    let screen1 = room.get_screen_for(ax, ay);
-                    if screen1.is_none() {
+    let offsets: Vec<_> = match search {
+        MergeSearch::Sparse => {
+            if merge_search::hopeless(room, metaroom, &rooms, &tiles, overlap_req, threshold) {
+                return best;
+            }
+            #[allow(clippy::cast_sign_loss)]
+            let bounds = Rect::new(left, top, (right - left) as u32, (bot - top) as u32);
+            merge_search::candidate_offsets(
+                room,
+                metaroom,
+                &rooms,
+                &tiles,
+                bounds,
+                overlap_req,
+                threshold,
+            )
+        }
+        MergeSearch::Exhaustive => (top..bot)
+            .flat_map(|yo| (left..right).map(move |xo| ((xo, yo), 0.0)))
+            .collect(),
+    };
+    'reg: for ((xo, yo), lower_bound) in offsets {
+        // Nothing here can beat the threshold as it stands now
+        if lower_bound >= threshold {
+            continue;
+        }
+        // put top left of room at x,y and match
+        let mut cost = 0.0;
+        let mut comparisons = 0;
+        // for each tile of the merged room, find the least costly
+        // way to match this tile against the correspond tile of
+        // any example in the room
+        for ry in 0..(ar.h as i32) {
+            let ay = ar.y + ry;
+            for (ax, &room_tile) in room
+                .row_slices(ay, ar.x, ar.x + ar.w as i32)
+                .flat_map(|(x, cells)| (x..).zip(cells))
+            {
+                let rx = ax - ar.x;
+                // let bx = br.x + xo + rx;
+                // let by = br.y + yo + ry;
+                if initial == room_tile {
+                    continue;
+                }
+                let mut best_tile_cost = None;
+                for &(room_id, (rxo, ryo)) in metaroom {
+                    let room_b = &rooms[room_id];
+                    let s2x = rxo + rx + xo;
+                    let s2y = ryo + ry + yo;
+                    // Not really an observation!
+                    if !room_b.region().contains(s2x, s2y) {
                         continue;
                     }
-                    let room_tile = room.screens[screen1.unwrap()][(ax, ay)];
-                    if initial == room_tile {
+                    let Some(room_b_tile) = room_b.get(s2x, s2y) else {
+                        continue;
+                    };
+                    if initial == room_b_tile {
                         continue;
                     }
-                    let mut best_tile_cost = None;
-                    for &(room_id, (rxo, ryo)) in metaroom {
-                        let room_b = &rooms[room_id];
-                        let s2x = rxo + rx + xo;
-                        let s2y = ryo + ry + yo;
-                        let screen2 = room_b.get_screen_for(s2x, s2y);
-                        if screen2.is_none() {
-                            continue;
-                        }
-                        let room_b_tile = room_b.screens[screen2.unwrap()][(s2x, s2y)];
-                        // Not really an observation!
-                        if !room_b.region().contains(s2x, s2y) {
-                            continue;
-                        }
-                        if initial == room_b_tile {
-                            continue;
-                        }
 
-                        let tc = tiles.change_cost(room_tile, room_b_tile);
-                        // if room.id == 2 && metaroom_id.0 == 0 && xo == 0 && yo == 0 {
-                        // dbg!(tc, ax, ay, s2x, s2y, room_id, room_tile, room_b_tile, tiles.get_change_by_id(room_tile), tiles.get_change_by_id(room_b_tile));
-                        // }
-                        // if room.id == 9 && room_id == 8 {
-                        // println!("Compare {:?},{:?} : {:?},{:?} : {:?},{:?} :: {:?}",rx,ry,ax,ay,s2x,s2y,room_b.region());
-                        // }
-                        // if metaroom_id.0 == 20 && room.id == 39 {
-                        // println!("rt {:?}, rbt {:?}, xy {:?}, tc {:?}, best {:?}", room_tile, room_b_tile, (rx,ry), tc, best_tile_cost);
-                        // }
-                        if tc < best_tile_cost.unwrap_or(f32::MAX) {
-                            best_tile_cost = Some(tc);
-                        }
-                    }
-                    if let Some(best_cost) = best_tile_cost {
-                        comparisons += 1;
-                        cost += best_cost;
-                    }
-                    if cost >= threshold {
-                        // if room.id == 2 && metaroom_id.0 == 0 && xo == 0 && yo == 0 {
-                        // dbg!("R2B", room.id, comparisons, overlap_req, cost, threshold);
-                        // panic!("done");
-                        // }
-                        continue 'reg;
+                    let tc = tiles.change_cost(room_tile, room_b_tile);
+                    // if room.id == 2 && metaroom_id.0 == 0 && xo == 0 && yo == 0 {
+                    // dbg!(tc, ax, ay, s2x, s2y, room_id, room_tile, room_b_tile, tiles.get_change_by_id(room_tile), tiles.get_change_by_id(room_b_tile));
+                    // }
+                    // if room.id == 9 && room_id == 8 {
+                    // println!("Compare {:?},{:?} : {:?},{:?} : {:?},{:?} :: {:?}",rx,ry,ax,ay,s2x,s2y,room_b.region());
+                    // }
+                    // if metaroom_id.0 == 20 && room.id == 39 {
+                    // println!("rt {:?}, rbt {:?}, xy {:?}, tc {:?}, best {:?}", room_tile, room_b_tile, (rx,ry), tc, best_tile_cost);
+                    // }
+                    if tc < best_tile_cost.unwrap_or(f32::MAX) {
+                        best_tile_cost = Some(tc);
                     }
                 }
+                if let Some(best_cost) = best_tile_cost {
+                    comparisons += 1;
+                    cost += best_cost;
+                }
+                if cost >= threshold {
+                    // if room.id == 2 && metaroom_id.0 == 0 && xo == 0 && yo == 0 {
+                    // dbg!("R2B", room.id, comparisons, overlap_req, cost, threshold);
+                    // panic!("done");
+                    // }
+                    continue 'reg;
+                }
+            }
+        }
+        // if xo == 0 && yo == 0 && room.id == 2 && metaroom_id.0 == 0 {
+        // dbg!("R2A", room.id, comparisons, overlap_req, cost, threshold);
+        // }
+        // if room.id == 39 && metaroom_id.0 == 20 {
+        // MappyState::dump_tiles_single(Path::new("out"), &tiles);
+        // dbg!(room.id,metaroom_id,xo,yo,comparisons,cost);
+        // panic!("done");
+        // }
+        // if room.id == 8 {
+        // dbg!(room.id,xo,yo,comparisons,cost);
+        // }
+        if cost < threshold && comparisons > overlap_req {
+            // dbg!(room.id, comparisons, cost, (xo, yo));
+            // assert!(room.id != 1);
+            let placement = Placement {
+                offset: (xo, yo),
+                cost,
+                comparisons,
+            };
+            // A tile or so off from a placement kept already is the same
+            // fit, so only the better of the two counts
+            if best.iter().any(|p| p.is_near(&placement) && p.cost <= cost) {
+                continue;
             }
-            // if xo == 0 && yo == 0 && room.id == 2 && metaroom_id.0 == 0 {
-            // dbg!("R2A", room.id, comparisons, overlap_req, cost, threshold);
-            // }
-            // if room.id == 39 && metaroom_id.0 == 20 {
-            // MappyState::dump_tiles_single(Path::new("out"), &tiles);
-            // dbg!(room.id,metaroom_id,xo,yo,comparisons,cost);
-            // panic!("done");
-            // }
-            // if room.id == 8 {
-            // dbg!(room.id,xo,yo,comparisons,cost);
-            // }
-            if cost < threshold && comparisons > overlap_req {
-                // dbg!(room.id, comparisons, cost, (xo, yo));
-                // assert!(room.id != 1);
-                threshold = cost;
-                best = Some(((xo, yo), cost));
-                if cost == 0.0 {
+            best.retain(|p| !p.is_near(&placement));
+            // Offsets come in scan order, so this one goes after any ties
+            let at = best.partition_point(|p| p.cost <= cost);
+            best.insert(at, placement);
+            best.truncate(candidates);
+            if best.len() == candidates {
+                // Only something better than the worst kept is worth keeping
+                threshold = best[candidates - 1].cost;
+                if threshold == 0.0 {
                     return best;
                 }
             }
@@ -0,0 +1,745 @@
+use super::scrolling::{ScrollChange, ScrollLatch};
+use super::splits::{self, Split};
+use crate::config::{ConfigError, ControlDetectorKind, MappyConfig, ProbePresses};
+use crate::ringbuffer::RingBuffer;
+use crate::sprites::{self, SPRITE_COUNT, SpriteData, SpriteTrack};
+use crate::{FrameSource, MappyError, Time};
+use retro_rs::Buttons;
+use serde::{Deserialize, Serialize};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::process::{Child, Command, Stdio};
+
+/// What a [`ControlDetector`] gets to look at besides the game itself.
+pub struct ControlContext<'a> {
+    pub now: Time,
+    pub latch: ScrollLatch,
+    /// The player's recent inputs; `get(0)` is this frame's.
+    pub inputs: &'a RingBuffer<Buttons>,
+    pub live_tracks: &'a [SpriteTrack],
+    pub config: &'a MappyConfig,
+}
+
+/// Decides whether the game is responding to the player.
+///
+/// Mappy asks every `control_check_interval` frames and smooths the answers
+/// over `control_check_k` frames before it believes a change in control.
+pub trait ControlDetector: Send {
+    /// Whether the game responded to the player this time.  Detectors may
+    /// run the game forward to find out, but must leave it as they found it.
+    /// # Errors
+    /// Returns an error if the game can't be read, saved, or restored
+    fn responded(
+        &mut self,
+        ctx: &ControlContext,
+        emu: &mut dyn FrameSource,
+    ) -> Result<bool, MappyError>;
+    /// Called when the game is reset, so nothing seen before it counts.
+    fn reset(&mut self) {}
+}
+
+/// Builds the detector `config` asks for.
+#[must_use]
+pub fn from_config(config: &MappyConfig) -> Box<dyn ControlDetector> {
+    match &config.control_detector {
+        ControlDetectorKind::Probe => Box::new(ProbeDetector::new(config)),
+        ControlDetectorKind::Avatar => Box::new(AvatarDetector::default()),
+        ControlDetectorKind::RamDiff { addresses } => {
+            let addresses = if addresses.is_empty() {
+                config.ram_addresses.values().copied().collect()
+            } else {
+                addresses.clone()
+            };
+            Box::new(RamDiffDetector::new(config, addresses))
+        }
+    }
+}
+
+/// The pairs of contrasting inputs probe-based detectors play, taking turns
+/// from one check to the next.
+struct Probes {
+    pairs: Vec<[Buttons; 2]>,
+    presses: ProbePresses,
+    checks: usize,
+    rng: u64,
+}
+
+impl Probes {
+    fn new(config: &MappyConfig) -> Self {
+        let pairs = config
+            .control_probes
+            .iter()
+            .chain(&config.control_probe_sets)
+            // MappyConfig::validate rules out any that don't parse
+            .filter_map(|[first, second]| {
+                Some([
+                    crate::parse_bitstring(first)?,
+                    crate::parse_bitstring(second)?,
+                ])
+            })
+            .collect();
+        Self {
+            pairs,
+            presses: config.probe_presses,
+            checks: 0,
+            rng: 0x9E37_79B9_7F4A_7C15,
+        }
+    }
+    fn next(&mut self, now: Time) -> Probe {
+        self.checks += 1;
+        let pair = if self.pairs.is_empty() {
+            // Down-left against up-right, taking turns with A and B
+            let odd = *now % 2 == 1;
+            [
+                Buttons::new().down(true).left(true).b(!odd).a(odd),
+                Buttons::new().up(true).right(true).a(!odd).b(odd),
+            ]
+        } else {
+            self.pairs[(self.checks - 1) % self.pairs.len()]
+        };
+        Probe {
+            pair,
+            presses: self.presses,
+            rng: xorshift(&mut self.rng),
+        }
+    }
+}
+
+// So random probes are the same from run to run
+fn xorshift(rng: &mut u64) -> u64 {
+    *rng ^= *rng << 13;
+    *rng ^= *rng >> 7;
+    *rng ^= *rng << 17;
+    *rng
+}
+
+/// The two inputs of a single check, and how to press them.
+#[derive(Clone, Copy)]
+struct Probe {
+    pair: [Buttons; 2],
+    presses: ProbePresses,
+    rng: u64,
+}
+
+impl Probe {
+    /// The buttons to hold on frame `frame` of a probe with `buttons`.
+    fn press(&mut self, buttons: Buttons, frame: usize) -> Buttons {
+        let held = pressed(buttons);
+        if held.is_empty() {
+            return buttons;
+        }
+        match self.presses {
+            ProbePresses::Together => buttons,
+            ProbePresses::Alternate => only(held[frame % held.len()]),
+            ProbePresses::Random => {
+                let mut bits = [false; 8];
+                // Any nonempty subset of the held buttons
+                #[allow(clippy::cast_possible_truncation)]
+                let subset = xorshift(&mut self.rng) as usize % ((1 << held.len()) - 1) + 1;
+                for (i, &b) in held.iter().enumerate() {
+                    bits[b] = subset & (1 << i) != 0;
+                }
+                crate::from_bitstring(bits)
+            }
+        }
+    }
+    /// Saves the game into `state`, plays each input for `frames` frames
+    /// from that save, and returns what `observe` saw after each.  The game
+    /// is left as it was.
+    fn run<T>(
+        mut self,
+        frames: usize,
+        state: &mut Vec<u8>,
+        emu: &mut dyn FrameSource,
+        mut observe: impl FnMut(&dyn FrameSource) -> Result<T, MappyError>,
+    ) -> Result<[T; 2], MappyError> {
+        state.resize(emu.save_size(), 0);
+        if !emu.save(state) {
+            return Err(MappyError::Emulator(
+                "couldn't save state for control probes".to_string(),
+            ));
+        }
+        let [first, second] = self.pair;
+        for frame in 0..frames {
+            let input = self.press(first, frame);
+            emu.run([input, Buttons::default()]);
+        }
+        let first = observe(emu)?;
+        if !emu.load(state) {
+            return Err(MappyError::Emulator(format!(
+                "couldn't load state, save size {} vs state size {}",
+                emu.save_size(),
+                state.len()
+            )));
+        }
+        for frame in 0..frames {
+            let input = self.press(second, frame);
+            emu.run([input, Buttons::default()]);
+        }
+        let second = observe(emu)?;
+        if !emu.load(state) {
+            return Err(MappyError::Emulator(
+                "couldn't load state after control probes".to_string(),
+            ));
+        }
+        Ok([first, second])
+    }
+}
+
+fn pressed(buttons: Buttons) -> Vec<usize> {
+    [
+        buttons.get_right(),
+        buttons.get_left(),
+        buttons.get_down(),
+        buttons.get_up(),
+        buttons.get_start(),
+        buttons.get_select(),
+        buttons.get_b(),
+        buttons.get_a(),
+    ]
+    .into_iter()
+    .enumerate()
+    .filter_map(|(i, held)| held.then_some(i))
+    .collect()
+}
+fn only(button: usize) -> Buttons {
+    let mut bits = [false; 8];
+    bits[button] = true;
+    crate::from_bitstring(bits)
+}
+
+/// What [`ProbeDetector`] compares after each probe: the sprites and the
+/// scroll splits.
+fn sprites_and_splits(
+    emu: &dyn FrameSource,
+    changes: &mut Vec<ScrollChange>,
+    latch: ScrollLatch,
+) -> Result<([SpriteData; SPRITE_COUNT], Vec<Split>), MappyError> {
+    // What can we learn from hardware screen splitting operations?
+    emu.scroll_changes(changes)?;
+    let (splits, _latch) = splits::get_splits(changes, latch, emu.ppuctrl()?);
+    let mut sprites = [SpriteData::default(); SPRITE_COUNT];
+    sprites::get_sprites(emu, &mut sprites)?;
+    Ok((sprites, splits))
+}
+
+/// The original detector: the player has control if two contrasting probe
+/// inputs leave the sprites or the scroll splits different.
+///
+/// It's expensive (two `control_check_k`-frame rollouts per check) but
+/// doesn't need to know anything about the game.
+pub struct ProbeDetector {
+    probes: Probes,
+    state: Vec<u8>,
+    changes: Vec<ScrollChange>,
+}
+
+impl ProbeDetector {
+    #[must_use]
+    pub fn new(config: &MappyConfig) -> Self {
+        Self {
+            probes: Probes::new(config),
+            state: vec![],
+            changes: Vec::with_capacity(32000),
+        }
+    }
+}
+
+impl ControlDetector for ProbeDetector {
+    fn responded(
+        &mut self,
+        ctx: &ControlContext,
+        emu: &mut dyn FrameSource,
+    ) -> Result<bool, MappyError> {
+        let changes = &mut self.changes;
+        let [first, second] = self.probes.next(ctx.now).run(
+            ctx.config.control_check_k,
+            &mut self.state,
+            emu,
+            |emu| sprites_and_splits(emu, changes, ctx.latch),
+        )?;
+        Ok(first != second)
+    }
+}
+
+/// A check for a probe worker, as it goes over the pipe.
+#[derive(Serialize, Deserialize)]
+struct ProbeJob {
+    check: usize,
+    state: Vec<u8>,
+    pair: [[bool; 8]; 2],
+    presses: ProbePresses,
+    rng: u64,
+    frames: usize,
+    latch: ScrollLatch,
+}
+
+// Cores may log to stdout too, so each result starts with this
+const RESULT_MARK: &[u8; 4] = b"MPRB";
+
+fn bits(buttons: Buttons) -> [bool; 8] {
+    let mut bits = [false; 8];
+    for b in pressed(buttons) {
+        bits[b] = true;
+    }
+    bits
+}
+
+fn pipe_error(e: &bincode::Error) -> MappyError {
+    MappyError::Emulator(format!("control probe worker pipe: {e}"))
+}
+
+/// Answers the checks a [`ProbeWorker`] sends down `jobs`, probing on
+/// `emu`, until `jobs` or `results` is closed.  Probe worker processes run this on their
+/// stdin and stdout, with `emu` playing the same game as the main emulator.
+/// # Errors
+/// Returns an error if `jobs` or `results` fail other than by closing
+pub fn serve_probes(
+    emu: &mut dyn FrameSource,
+    jobs: impl Read,
+    results: impl Write,
+) -> Result<(), MappyError> {
+    let mut jobs = BufReader::new(jobs);
+    let mut results = BufWriter::new(results);
+    let mut own_state = vec![];
+    let mut changes = Vec::with_capacity(32000);
+    loop {
+        let job: ProbeJob = match bincode::deserialize_from(&mut jobs) {
+            Ok(job) => job,
+            Err(e) => match *e {
+                bincode::ErrorKind::Io(ref io) if io.kind() == ErrorKind::UnexpectedEof => {
+                    return Ok(());
+                }
+                _ => return Err(pipe_error(&e)),
+            },
+        };
+        let probe = Probe {
+            pair: job.pair.map(crate::from_bitstring),
+            presses: job.presses,
+            rng: job.rng,
+        };
+        let result = if emu.load(&job.state) {
+            probe
+                .run(job.frames, &mut own_state, emu, |emu| {
+                    sprites_and_splits(emu, &mut changes, job.latch)
+                })
+                .map(|[first, second]| first != second)
+                .map_err(|e| e.to_string())
+        } else {
+            Err("couldn't load the main emulator's state for control probes".to_string())
+        };
+        let result = bincode::serialize(&(job.check, result)).map_err(|e| pipe_error(&e))?;
+        let sent = results
+            .write_all(RESULT_MARK)
+            .and_then(|()| results.write_all(&result))
+            .and_then(|()| results.flush());
+        match sent {
+            Ok(()) => {}
+            // The detector is gone and doesn't need the rest
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// One of [`ParallelProbeDetector`]'s secondary emulators.  Cores keep
+/// their state in globals, so each worker has to be a process of its own.
+pub struct ProbeWorker {
+    jobs: Option<BufWriter<Box<dyn Write + Send>>>,
+    results: BufReader<Box<dyn Read + Send>>,
+    child: Option<Child>,
+}
+
+impl ProbeWorker {
+    /// Starts `command`, which should run [`serve_probes`] on its stdin and
+    /// stdout.
+    /// # Errors
+    /// Returns an error if the process can't be started
+    pub fn spawn(mut command: Command) -> Result<Self, MappyError> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let jobs = child.stdin.take().expect("worker stdin is piped");
+        let results = child.stdout.take().expect("worker stdout is piped");
+        let mut worker = Self::connect(jobs, results);
+        worker.child = Some(child);
+        Ok(worker)
+    }
+    /// A worker at the other end of a pair of pipes already running
+    /// [`serve_probes`].
+    #[must_use]
+    pub fn connect(jobs: impl Write + Send + 'static, results: impl Read + Send + 'static) -> Self {
+        Self {
+            jobs: Some(BufWriter::new(Box::new(jobs))),
+            results: BufReader::new(Box::new(results)),
+            child: None,
+        }
+    }
+    fn send(&mut self, job: &ProbeJob) -> Result<(), MappyError> {
+        let jobs = self.jobs.as_mut().expect("probe worker already shut down");
+        bincode::serialize_into(&mut *jobs, job).map_err(|e| pipe_error(&e))?;
+        jobs.flush()?;
+        Ok(())
+    }
+    fn receive(&mut self) -> Result<(usize, Result<bool, String>), MappyError> {
+        // Skip anything else the worker printed
+        let mut mark = [0; 4];
+        self.results.read_exact(&mut mark)?;
+        while &mark != RESULT_MARK {
+            mark.rotate_left(1);
+            self.results.read_exact(&mut mark[3..])?;
+        }
+        bincode::deserialize_from(&mut self.results).map_err(|e| pipe_error(&e))
+    }
+}
+
+impl Drop for ProbeWorker {
+    fn drop(&mut self) {
+        // Hanging up tells the worker to stop once it finishes its jobs
+        self.jobs.take();
+        if let Some(mut child) = self.child.take() {
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Runs [`ProbeDetector`]'s probes on [`ProbeWorker`]s, so the main
+/// emulator never leaves the frame it's on (and its framebuffer shows what
+/// the player saw).
+///
+/// Each check sends the main emulator's savestate off to the next worker
+/// and answers with the result of the check `workers` checks earlier,
+/// waiting for it if need be; the first few checks (and the first few after
+/// a reset) answer no.  Answers are the same from run to run however the
+/// workers are scheduled.
+pub struct ParallelProbeDetector {
+    probes: Probes,
+    workers: Vec<ProbeWorker>,
+    checks: usize,
+    // Checks before this one were from before a reset
+    since_reset: usize,
+}
+
+impl ParallelProbeDetector {
+    /// Probes on `workers`, which should be playing the same game as the
+    /// main emulator.
+    /// # Panics
+    /// Panics if `workers` is empty
+    #[must_use]
+    pub fn new(config: &MappyConfig, workers: Vec<ProbeWorker>) -> Self {
+        assert!(!workers.is_empty(), "need at least one probe worker");
+        Self {
+            probes: Probes::new(config),
+            workers,
+            checks: 0,
+            since_reset: 0,
+        }
+    }
+}
+
+impl ControlDetector for ParallelProbeDetector {
+    fn responded(
+        &mut self,
+        ctx: &ControlContext,
+        emu: &mut dyn FrameSource,
+    ) -> Result<bool, MappyError> {
+        let mut state = vec![0; emu.save_size()];
+        if !emu.save(&mut state) {
+            return Err(MappyError::Emulator(
+                "couldn't save state for control probes".to_string(),
+            ));
+        }
+        let probe = self.probes.next(ctx.now);
+        let job = ProbeJob {
+            check: self.checks,
+            state,
+            pair: probe.pair.map(bits),
+            presses: probe.presses,
+            rng: probe.rng,
+            frames: ctx.config.control_check_k,
+            latch: ctx.latch,
+        };
+        // Each worker gets every `workers`th check, and answers in order
+        let count = self.workers.len();
+        self.workers[self.checks % count].send(&job)?;
+        self.checks += 1;
+        let Some(wanted) = self.checks.checked_sub(count + 1) else {
+            return Ok(false);
+        };
+        let (check, result) = self.workers[wanted % count].receive()?;
+        if check != wanted {
+            return Err(MappyError::Emulator(format!(
+                "control probe worker answered check {check} instead of {wanted}"
+            )));
+        }
+        if wanted < self.since_reset {
+            return Ok(false);
+        }
+        result.map_err(MappyError::Emulator)
+    }
+    fn reset(&mut self) {
+        self.since_reset = self.checks;
+    }
+}
+
+/// Like [`ProbeDetector`], but compares a few RAM addresses (say, the
+/// player's position) after each probe.  That ignores enemies and effects
+/// that happen to react to inputs, and works for avatars drawn in the
+/// background.
+pub struct RamDiffDetector {
+    probes: Probes,
+    state: Vec<u8>,
+    addresses: Vec<u16>,
+}
+
+impl RamDiffDetector {
+    #[must_use]
+    pub fn new(config: &MappyConfig, addresses: Vec<u16>) -> Self {
+        Self {
+            probes: Probes::new(config),
+            state: vec![],
+            addresses,
+        }
+    }
+}
+
+impl ControlDetector for RamDiffDetector {
+    fn responded(
+        &mut self,
+        ctx: &ControlContext,
+        emu: &mut dyn FrameSource,
+    ) -> Result<bool, MappyError> {
+        // Most games copy the controller into RAM, so all of RAM would
+        // always look different
+        if self.addresses.is_empty() {
+            return Err(MappyError::Config(ConfigError::Invalid(
+                "ram_diff control detection needs addresses to watch".to_string(),
+            )));
+        }
+        let addresses = &self.addresses;
+        let probe = self.probes.next(ctx.now);
+        let frames = ctx.config.control_check_k;
+        let [first, second] = probe.run(frames, &mut self.state, emu, |emu| {
+            let ram = emu.ram()?;
+            addresses
+                .iter()
+                .map(|&addr| {
+                    ram.get(usize::from(addr)).copied().ok_or_else(|| {
+                        MappyError::Emulator(format!("RAM address {addr:#06x} is out of range"))
+                    })
+                })
+                .collect::<Result<Vec<u8>, _>>()
+        })?;
+        Ok(first != second)
+    }
+}
+
+/// Watches the sprite tracks mappy already recognizes as the avatar (see
+/// [`SpriteTrack::get_is_avatar`]): the player has control if one of them
+/// moved while the player was pressing buttons.
+///
+/// This never runs the game, so it's cheap, but it can't tell until some
+/// track has been judged the avatar.
+#[derive(Default)]
+pub struct AvatarDetector {
+    responded: bool,
+}
+
+impl ControlDetector for AvatarDetector {
+    fn responded(
+        &mut self,
+        ctx: &ControlContext,
+        _emu: &mut dyn FrameSource,
+    ) -> Result<bool, MappyError> {
+        let window = ctx.config.control_check_interval.min(ctx.inputs.get_sz());
+        let now = *ctx.now;
+        if now <= window {
+            return Ok(false);
+        }
+        let since = Time(now - window);
+        let mut avatars = ctx
+            .live_tracks
+            .iter()
+            .filter(|t| t.get_is_avatar() && t.last_observation_time() >= since);
+        let pushed = (0..window).any(|ago| {
+            let b = ctx.inputs.get(ago);
+            b.get_left() || b.get_right() || b.get_up() || b.get_down() || b.get_a() || b.get_b()
+        });
+        self.responded = if pushed {
+            avatars.any(|t| {
+                t.point_at(since).unwrap_or_else(|| t.starting_point()) != t.current_point()
+            })
+        } else {
+            // Standing still with nothing pressed is no news either way
+            self.responded && avatars.next().is_some()
+        };
+        Ok(self.responded)
+    }
+    fn reset(&mut self) {
+        self.responded = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappy::scrolling::ScrollChange;
+
+    /// A dot that moves right and left, with its x position in RAM at 0x10.
+    struct Dot {
+        ram: [u8; 0x800],
+        oam: [u8; 256],
+        fb: Vec<u8>,
+        frozen: bool,
+    }
+    impl Dot {
+        fn new(frozen: bool) -> Self {
+            let mut dot = Self {
+                ram: [0; 0x800],
+                oam: [0xFF; 256],
+                fb: vec![0; 256 * 240],
+                frozen,
+            };
+            dot.ram[0x10] = 100;
+            dot.oam[0..4].copy_from_slice(&[120, 1, 0, 100]);
+            dot
+        }
+    }
+    impl FrameSource for Dot {
+        fn run(&mut self, inputs: [Buttons; 2]) {
+            // The controller is always copied into RAM
+            self.ram[0x01] = u8::from(inputs[0].get_right()) | u8::from(inputs[0].get_left()) << 1;
+            if self.frozen {
+                return;
+            }
+            if inputs[0].get_right() {
+                self.ram[0x10] = self.ram[0x10].wrapping_add(1);
+            }
+            if inputs[0].get_left() {
+                self.ram[0x10] = self.ram[0x10].wrapping_sub(1);
+            }
+            self.oam[3] = self.ram[0x10];
+        }
+        fn framebuffer_size(&self) -> (usize, usize) {
+            (256, 240)
+        }
+        fn copy_framebuffer_rgb332(&self, buf: &mut [u8]) -> Result<(), MappyError> {
+            buf.copy_from_slice(&self.fb);
+            Ok(())
+        }
+        fn layers(&self) -> Result<[&[u8]; 3], MappyError> {
+            Ok([&self.fb, &self.fb, &self.fb])
+        }
+        fn scroll_changes(&self, changes: &mut Vec<ScrollChange>) -> Result<(), MappyError> {
+            changes.clear();
+            Ok(())
+        }
+        fn oam(&self) -> &[u8] {
+            &self.oam
+        }
+        fn ppuctrl(&self) -> Result<u8, MappyError> {
+            Ok(0)
+        }
+        fn ram(&self) -> Result<&[u8], MappyError> {
+            Ok(&self.ram)
+        }
+        fn save_size(&self) -> usize {
+            1
+        }
+        fn save(&self, buf: &mut [u8]) -> bool {
+            buf[0] = self.ram[0x10];
+            true
+        }
+        fn load(&mut self, buf: &[u8]) -> bool {
+            self.ram[0x10] = buf[0];
+            self.oam[3] = buf[0];
+            true
+        }
+    }
+
+    fn check(detector: &mut dyn ControlDetector, game: &mut Dot, config: &MappyConfig) -> bool {
+        let inputs = RingBuffer::new(Buttons::new(), 60);
+        let ctx = ControlContext {
+            now: Time(100),
+            latch: ScrollLatch::default(),
+            inputs: &inputs,
+            live_tracks: &[],
+            config,
+        };
+        let before = game.ram[0x10];
+        let responded = detector.responded(&ctx, game).unwrap();
+        assert_eq!(game.ram[0x10], before);
+        responded
+    }
+
+    #[test]
+    fn test_probe_detectors() {
+        for presses in [
+            ProbePresses::Together,
+            ProbePresses::Alternate,
+            ProbePresses::Random,
+        ] {
+            let config = MappyConfig {
+                probe_presses: presses,
+                ..MappyConfig::default()
+            };
+            let mut probe = ProbeDetector::new(&config);
+            assert!(check(&mut probe, &mut Dot::new(false), &config));
+            assert!(!check(&mut probe, &mut Dot::new(true), &config));
+            let mut ram_diff = RamDiffDetector::new(&config, vec![0x10]);
+            assert!(check(&mut ram_diff, &mut Dot::new(false), &config));
+            assert!(!check(&mut ram_diff, &mut Dot::new(true), &config));
+        }
+        let config = MappyConfig::default();
+        let mut ram_diff = RamDiffDetector::new(&config, vec![]);
+        let inputs = RingBuffer::new(Buttons::new(), 60);
+        let ctx = ControlContext {
+            now: Time(100),
+            latch: ScrollLatch::default(),
+            inputs: &inputs,
+            live_tracks: &[],
+            config: &config,
+        };
+        assert!(matches!(
+            ram_diff.responded(&ctx, &mut Dot::new(false)),
+            Err(MappyError::Config(ConfigError::Invalid(_)))
+        ));
+    }
+
+    #[test]
+    fn test_parallel_probes() {
+        let config = MappyConfig::default();
+        for frozen in [false, true] {
+            // Threads stand in for worker processes, which Dot doesn't need
+            let mut servers = vec![];
+            let workers = (0..2)
+                .map(|_| {
+                    let (job_rx, job_tx) = std::io::pipe().unwrap();
+                    let (result_rx, mut result_tx) = std::io::pipe().unwrap();
+                    // Like a core logging to stdout
+                    result_tx
+                        .write_all(b"[libretro INFO] MPR loaded\n")
+                        .unwrap();
+                    servers.push(std::thread::spawn(move || {
+                        serve_probes(&mut Dot::new(frozen), job_rx, result_tx).unwrap();
+                    }));
+                    ProbeWorker::connect(job_tx, result_rx)
+                })
+                .collect();
+            let mut parallel = ParallelProbeDetector::new(&config, workers);
+            let mut game = Dot::new(false);
+            let answers: Vec<_> = (0..4)
+                .map(|_| check(&mut parallel, &mut game, &config))
+                .collect();
+            // Two checks behind, one per worker
+            assert_eq!(answers, [false, false, !frozen, !frozen]);
+            // The main game never ran, so it never even saw the probe inputs
+            assert_eq!(game.ram[0x01], 0);
+            // Hanging up stops the workers
+            drop(parallel);
+            for server in servers {
+                server.join().unwrap();
+            }
+        }
+    }
+}
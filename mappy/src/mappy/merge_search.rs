@@ -0,0 +1,284 @@
+//! Narrowing down where [`crate::merge_cost`] looks in
+//! [`MergeSearch::Sparse`](crate::config::MergeSearch::Sparse) mode: ruling
+//! out metarooms a room has too little in common with, and skipping the
+//! offsets where too few of its tiles line up to fit.  Both only rule out
+//! what couldn't fit anyway, so the sparse search finds the same placements
+//! as the exhaustive one.
+use crate::Rect;
+use crate::room::Room;
+use crate::tile::{TileDB, TileGfxId};
+use std::collections::{HashMap, HashSet};
+
+// A lower bound on the change_cost between two changes to tiles which
+// aren't the same tile or frames of one animation
+const MIN_MISMATCH_COST: f32 = 0.15;
+// Only count this many matching pairs of tiles, rarest tiles first
+const MAX_VOTES: usize = 1 << 18;
+
+/// Whether `room` can't possibly be registered into `metaroom` for less than
+/// `threshold` while comparing more than `overlap_req` tiles.  Every tile
+/// whose change costs 1.0 against all of the metaroom's changes adds 1.0 to
+/// the cost wherever it lands, so if there are too many of those, no offset
+/// will do.
+#[allow(clippy::cast_precision_loss)]
+pub(super) fn hopeless(
+    room: &Room,
+    metaroom: &[(usize, (i32, i32))],
+    rooms: &[Room],
+    tiles: &TileDB,
+    overlap_req: u32,
+    threshold: f32,
+) -> bool {
+    let hist = room.change_histogram();
+    if hist.values().sum::<usize>() <= overlap_req as usize {
+        return true;
+    }
+    let costs = tiles.change_costs();
+    let seen: HashSet<_> = metaroom
+        .iter()
+        .flat_map(|&(rid, _)| rooms[rid].change_histogram().keys().copied())
+        .collect();
+    // What the metaroom's changes go from and to, for finding changes that
+    // come right before or after them or go to the same tile (or animation)
+    let mut seen_from = HashSet::new();
+    let mut seen_to = HashSet::new();
+    let mut seen_animated = HashSet::new();
+    for &m in &seen {
+        let change = tiles.get_change_by_id(m).unwrap();
+        seen_from.insert(change.from);
+        seen_to.insert(change.to);
+        if costs.animations {
+            seen_animated.insert(tiles.canonical(change.to));
+        }
+    }
+    let compatible: usize = hist
+        .iter()
+        .filter(|&(&tc, _)| {
+            let change = tiles.get_change_by_id(tc).unwrap();
+            seen.contains(&tc)
+                || seen_to.contains(&change.to)
+                || seen_from.contains(&change.to)
+                || seen_to.contains(&change.from)
+                || (costs.animations && seen_animated.contains(&tiles.canonical(change.to)))
+                || (costs.reachable && tiles.reachable_neighbors(tc).any(|m| seen.contains(&m)))
+        })
+        .map(|(_, n)| n)
+        .sum();
+    (overlap_req as usize + 1).saturating_sub(compatible) as f32 >= threshold
+}
+
+/// Offsets within `bounds`, in the order [`crate::merge_cost`] scans them,
+/// where `room` might still fit the metaroom for less than `threshold` over
+/// more than `overlap_req` tiles, each with a lower bound on what it would
+/// cost there.  Only tiles lined up with the same tile (or another frame of
+/// its animation, if the tile database counts those alike) cost less than
+/// `MIN_MISMATCH_COST`, so an offset where too few line up can't fit.  Tiles
+/// too common to count are taken to line up everywhere.
+#[allow(clippy::cast_precision_loss, clippy::cast_possible_wrap)]
+pub(super) fn candidate_offsets(
+    room: &Room,
+    metaroom: &[(usize, (i32, i32))],
+    rooms: &[Room],
+    tiles: &TileDB,
+    bounds: Rect,
+    overlap_req: u32,
+    threshold: f32,
+) -> Vec<((i32, i32), f32)> {
+    let tile_of = |tc| {
+        let tile = tiles.get_change_by_id(tc).unwrap().to;
+        if tiles.change_costs().animations {
+            tiles.canonical(tile)
+        } else {
+            tile
+        }
+    };
+    let ar = room.region();
+    let mut room_at: HashMap<TileGfxId, Vec<(i32, i32)>> = HashMap::new();
+    for (x, y, tc) in room.seen() {
+        room_at
+            .entry(tile_of(tc))
+            .or_default()
+            .push((x - ar.x, y - ar.y));
+    }
+    // Where each tile is in the metaroom, less the offset of the room it's in
+    let mut metaroom_at: HashMap<TileGfxId, Vec<(i32, i32)>> = HashMap::new();
+    for &(rid, (rxo, ryo)) in metaroom {
+        for (x, y, tc) in rooms[rid].seen() {
+            let tile = tile_of(tc);
+            if room_at.contains_key(&tile) {
+                metaroom_at
+                    .entry(tile)
+                    .or_default()
+                    .push((x - rxo, y - ryo));
+            }
+        }
+    }
+    // Rare tiles are cheap to count, so count them first and skip any too
+    // common to count
+    let mut pairs: Vec<_> = room_at
+        .iter()
+        .filter_map(|(tile, a)| {
+            metaroom_at
+                .get(tile)
+                .map(|b| (a.len() * b.len(), *tile, a, b))
+        })
+        .collect();
+    pairs.sort_unstable_by_key(|&(n, tile, _, _)| (n, tile));
+    let mut votes: HashMap<(i32, i32), usize> = HashMap::new();
+    let mut counted = 0;
+    let mut skipped = 0;
+    for (n, _, a, b) in pairs {
+        if counted + n > MAX_VOTES {
+            skipped += a.len();
+            continue;
+        }
+        counted += n;
+        for &(ax, ay) in a {
+            for &(bx, by) in b {
+                let (xo, yo) = (bx - ax, by - ay);
+                if bounds.contains(xo, yo) {
+                    *votes.entry((xo, yo)).or_default() += 1;
+                }
+            }
+        }
+    }
+    // Votes can only overcount the tiles that line up at an offset, and
+    // every other tile compared costs at least MIN_MISMATCH_COST
+    let needed = overlap_req as usize + 1;
+    let lower_bound =
+        |votes: usize| needed.saturating_sub(votes + skipped) as f32 * MIN_MISMATCH_COST;
+    if lower_bound(0) < threshold {
+        // Even where nothing lines up might do, so try everywhere
+        return (bounds.y..bounds.y + bounds.h as i32)
+            .flat_map(|yo| (bounds.x..bounds.x + bounds.w as i32).map(move |xo| (xo, yo)))
+            .map(|o| (o, lower_bound(votes.get(&o).copied().unwrap_or(0))))
+            .collect();
+    }
+    let mut offsets: Vec<_> = votes
+        .into_iter()
+        .map(|(o, v)| (o, lower_bound(v)))
+        .filter(|&(_, cost)| cost < threshold)
+        .collect();
+    offsets.sort_unstable_by_key(|&((xo, yo), _)| (yo, xo));
+    offsets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MergeSearch;
+    use crate::merge_cost;
+    use crate::metaroom::{MergeConfidence, MetaroomID};
+    use crate::screen::Screen;
+    use crate::tile::{TILE_NUM_PX, TileGfx};
+    use std::sync::RwLock;
+
+    #[test]
+    #[allow(clippy::cast_sign_loss)]
+    fn test_sparse_search() {
+        let mut db = TileDB::new();
+        let gfx: Vec<_> = (1..=7)
+            .map(|c| db.get_tile(TileGfx([c; TILE_NUM_PX])))
+            .collect();
+        // Scrambled, so no two offsets line up the same tiles
+        let world = |x: i32, y: i32| {
+            let mut h = ((x * 73 + y * 151) as u32).wrapping_mul(0x9E37_79B1);
+            h ^= h >> 15;
+            h = h.wrapping_mul(0x2C1B_3C6D);
+            h ^= h >> 12;
+            gfx[h as usize % 6]
+        };
+        let room = |id, f: &dyn Fn(i32, i32) -> TileGfxId, db: &mut TileDB| {
+            let region = Rect::new(0, 0, 16, 12);
+            let cells = (0..12)
+                .flat_map(|y| (0..16).map(move |x| (x, y)))
+                .map(|(x, y)| f(x, y))
+                .collect();
+            Room::new(id, &Screen::from_tiles(region, cells), db).finalize()
+        };
+        let rooms = vec![
+            room(0, &world, &mut db),
+            // The same place seen from further right and down
+            room(1, &|x, y| world(x + 5, y + 3), &mut db),
+            // And again, with a few tiles different
+            room(
+                2,
+                &|x, y| {
+                    if x < 8 && y == 2 {
+                        gfx[6]
+                    } else {
+                        world(x + 5, y + 3)
+                    }
+                },
+                &mut db,
+            ),
+            // Somewhere else entirely
+            room(3, &|_, _| gfx[6], &mut db),
+            // Fits anywhere in room 3
+            room(4, &|_, _| gfx[6], &mut db),
+        ];
+        let metaroom = [(0, (0, 0))];
+        let rooms = RwLock::new(rooms);
+        let tiles = RwLock::new(db);
+        let place = |rid: usize, into: &[(usize, (i32, i32))], search, k| {
+            let room = rooms.read().unwrap()[rid].clone();
+            merge_cost(&room, MetaroomID(0), into, &rooms, &tiles, 16.0, search, k)
+        };
+        let best = |rid, search| {
+            place(rid, &metaroom, search, 1)
+                .iter()
+                .map(|p| (p.offset, p.cost))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(best(1, MergeSearch::Sparse), [((5, 3), 0.0)]);
+        assert_eq!(best(2, MergeSearch::Sparse), [((5, 3), 8.0)]);
+        // Sparse only skips what couldn't fit anyway
+        for (rid, into) in [(1, 0), (2, 0), (3, 0), (4, 3), (0, 1)] {
+            for k in [1, 2, 4] {
+                assert_eq!(
+                    place(rid, &[(into, (0, 0))], MergeSearch::Sparse, k),
+                    place(rid, &[(into, (0, 0))], MergeSearch::Exhaustive, k)
+                );
+            }
+        }
+        // Only one good fit, so nothing to be unsure about
+        let placements = place(1, &metaroom, MergeSearch::Sparse, 2);
+        assert_eq!(placements.len(), 1);
+        assert_eq!(placements[0].comparisons, 99);
+        assert_eq!(MergeConfidence::of(&placements).margin, None);
+        let placements = place(2, &metaroom, MergeSearch::Exhaustive, 2);
+        assert!((MergeConfidence::of(&placements).cost_per_comparison - 8.0 / 99.0).abs() < 1e-6);
+        // But a blank room fits a blank room lots of ways
+        for search in [MergeSearch::Sparse, MergeSearch::Exhaustive] {
+            let placements = place(4, &[(3, (0, 0))], search, 2);
+            assert_eq!(placements.len(), 2);
+            assert!(!placements[0].is_near(&placements[1]));
+            assert!(MergeConfidence::of(&placements).is_close_call(0.01));
+        }
+        let rooms = rooms.read().unwrap();
+        let tiles = tiles.read().unwrap();
+        assert!(hopeless(&rooms[3], &metaroom, &rooms, &tiles, 96, 16.0));
+        assert!(!hopeless(&rooms[2], &metaroom, &rooms, &tiles, 96, 16.0));
+        let offsets = candidate_offsets(
+            &rooms[1],
+            &metaroom,
+            &rooms,
+            &tiles,
+            Rect::new(-16, -12, 32, 24),
+            96,
+            1.0,
+        );
+        assert_eq!(offsets, [((5, 3), 0.0)]);
+        // A threshold so high that an offset needs no matches at all
+        let offsets = candidate_offsets(
+            &rooms[2],
+            &metaroom,
+            &rooms,
+            &tiles,
+            Rect::new(-16, -12, 32, 24),
+            96,
+            16.0 * 96.0,
+        );
+        assert_eq!(offsets.len(), 32 * 24);
+    }
+}
@@ -1,4 +1,6 @@
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ScrollLatch {
     H,
     V,
@@ -46,10 +48,11 @@ pub struct ScrollChange {
     pub value: u8,
 }
 
-pub fn find_offset(old: u8, new: u8, limit: i16) -> i16 {
+#[allow(clippy::cast_possible_wrap)]
+pub fn find_offset(old: u16, new: u16, limit: i16) -> i16 {
     // each coordinate either increased and possibly wrapped or decreased and possibly wrapped or stayed the same
-    let old = i16::from(old);
-    let new = i16::from(new);
+    let old = old as i16;
+    let new = new as i16;
     let decrease = if new <= old {
         new - old
     } else {
@@ -1,5 +1,6 @@
-use super::scrolling::{ScrollChange, ScrollChangeReason, ScrollLatch};
+use super::scrolling::{self, ScrollChange, ScrollChangeReason, ScrollLatch};
 use crate::Rect;
+use crate::config::{ScreenSafe, SplitOverride};
 use crate::framebuffer::Framebuffer;
 use crate::tile::TILE_SIZE;
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
@@ -7,18 +8,126 @@ pub struct Split {
     pub scanline: u8,
     pub scroll_x: u8,
     pub scroll_y: u8,
+    // Nametable select bits, as in PPUCTRL: bit 0 adds 256 to X, bit 1 adds 240 to Y
+    pub nametable: u8,
+}
+
+impl Split {
+    /// The full 9-bit scroll across all four nametables: X in `0..512`, Y in
+    /// `0..480`.
+    #[must_use]
+    pub fn nametable_scroll(&self) -> (u16, u16) {
+        (
+            u16::from(self.scroll_x) + 256 * u16::from(self.nametable & 1),
+            u16::from(self.scroll_y) + 240 * u16::from(self.nametable >> 1),
+        )
+    }
+}
+
+/// How far the scroll moved from split `old` to split `new`.  Along axes
+/// where `nametables` says the game selects nametables, uses the full 9-bit
+/// scroll, so jumps of up to a whole screen come out right; otherwise wraps
+/// the 8-bit scroll, which only handles moves of up to half a screen.
+///
+/// The nametable bits come from PPUCTRL as of the end of the frame and
+/// from `$2006` writes; the core doesn't report `$2000` writes, so a game
+/// that switches nametables mid-frame through PPUCTRL alone gets the
+/// end-of-frame nametable on all its splits.
+#[must_use]
+pub fn scroll_offset(old: Split, new: Split, nametables: (bool, bool)) -> (i32, i32) {
+    let (old_x, old_y) = old.nametable_scroll();
+    let (new_x, new_y) = new.nametable_scroll();
+    let dx = if nametables.0 {
+        scrolling::find_offset(old_x, new_x, 512)
+    } else {
+        scrolling::find_offset(old.scroll_x.into(), new.scroll_x.into(), 256)
+    };
+    let dy = if nametables.1 {
+        scrolling::find_offset(old_y, new_y, 480)
+    } else {
+        scrolling::find_offset(old.scroll_y.into(), new.scroll_y.into(), 240)
+    };
+    (i32::from(dx), i32::from(dy))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum BandKind {
+    // Game world; scrolls on its own
+    Playfield,
+    // Status bar or menu; never mapped
+    Hud,
+}
+
+/// The scanlines from `top.scanline` up to `bottom.scanline`, drawn with
+/// `top`'s scroll.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Band {
+    pub top: Split,
+    pub bottom: Split,
+    pub kind: BandKind,
+}
+
+impl Band {
+    #[must_use]
+    pub fn height(&self) -> u8 {
+        self.bottom.scanline - self.top.scanline
+    }
+    /// One playfield band covering the whole screen.
+    #[must_use]
+    pub fn whole_screen() -> Self {
+        Band {
+            top: Split {
+                scanline: 0,
+                scroll_x: 0,
+                scroll_y: 0,
+                nametable: 0,
+            },
+            bottom: Split {
+                scanline: 240,
+                scroll_x: 0,
+                scroll_y: 0,
+                nametable: 0,
+            },
+            kind: BandKind::Playfield,
+        }
+    }
+}
+
+/// A playfield band other than the one rooms are mapped from, with its own
+/// scroll and grid alignment.  Only its position is tracked: no room maps
+/// its tiles, so they aren't read.
+#[derive(Clone, Copy)]
+pub struct BandScroll {
+    pub band: Band,
+    pub grid_align: (u8, u8),
+    pub scroll: (i32, i32),
+}
+
+impl BandScroll {
+    #[must_use]
+    pub fn new(band: Band, nametables: (bool, bool)) -> Self {
+        let mut bs = BandScroll {
+            band: Band::whole_screen(),
+            grid_align: (0, 0),
+            scroll: (0, 0),
+        };
+        bs.follow(band, nametables);
+        bs
+    }
+    /// Moves to `band`'s position this frame and updates the scroll from
+    /// its change in PPU scroll.
+    pub fn follow(&mut self, band: Band, nametables: (bool, bool)) {
+        let (dx, dy) = scroll_offset(self.band.top, band.top, nametables);
+        self.grid_align = (band.top.scroll_x, band.top.scroll_y);
+        self.scroll = (self.scroll.0 + dx, self.scroll.1 + dy);
+        self.band = band;
+    }
 }
 
 fn register_split(splits: &mut Vec<Split>, scanline: u8) {
     let last = &splits[splits.len() - 1];
     if last.scanline < scanline {
-        let scroll_x = last.scroll_x;
-        let scroll_y = last.scroll_y;
-        splits.push(Split {
-            scanline,
-            scroll_x,
-            scroll_y,
-        });
+        splits.push(Split { scanline, ..*last });
     }
 }
 
@@ -52,11 +161,18 @@ pub fn skim_rect(fb: &Framebuffer, start: i16, dir: i16) -> u8 {
     u8::try_from((last_good_row + 1 - start).unsigned_abs()).unwrap()
 }
 
-pub fn get_splits(changes: &[ScrollChange], mut latch: ScrollLatch) -> (Vec<Split>, ScrollLatch) {
+/// The splits made by this frame's scroll `changes`, starting from
+/// nametable `nametable` (the low bits of PPUCTRL).
+pub fn get_splits(
+    changes: &[ScrollChange],
+    mut latch: ScrollLatch,
+    nametable: u8,
+) -> (Vec<Split>, ScrollLatch) {
     let mut splits = vec![Split {
         scanline: 0,
         scroll_x: 0,
         scroll_y: 0,
+        nametable: nametable & 0b11,
     }];
     for &ScrollChange {
         reason,
@@ -95,7 +211,8 @@ pub fn get_splits(changes: &[ScrollChange], mut latch: ScrollLatch) -> (Vec<Spli
                         // bits 0 and 1 are ignored, rest mapped to yyNNYY
                         // (and the leftmost bit of y_fine is forced to 0)
                         let y_fine = (value & 0b0011_0000) >> 4;
-                        // (ignore nametable select NN for now)
+                        // nametable select NN
+                        splits[last].nametable = (value & 0b0000_1100) >> 2;
                         // two highest bits of y_coarse are written
                         let y_coarse_hi = (value & 0b0000_0011) << 6;
                         // combine that with the three middle bits of old y scroll
@@ -124,72 +241,255 @@ pub fn get_splits(changes: &[ScrollChange], mut latch: ScrollLatch) -> (Vec<Spli
             scanline: 240,
             scroll_x: 0,
             scroll_y: 0,
+            nametable: 0,
         });
     }
     (splits, latch)
 }
 
-fn get_best_effort_splits(fb: &Framebuffer, lo: Split, hi: Split) -> (Split, Split) {
-    //If we can skim a rectangle bigger than 24px high at the top or the bottom, our split is height - that
+/// Splits `band` into a playfield and any solid-colored HUD above or below
+/// it; returns the bands and the playfield's index among them.
+fn get_best_effort_bands(fb: &Framebuffer, band: Band) -> (Vec<Band>, usize) {
+    //If we can skim a rectangle bigger than 24px high at the top or the bottom, it's a HUD
     let down_skim_len = skim_rect(fb, 0, 1);
     let up_skim_len = skim_rect(fb, 239, -1);
-    let mut s0 = lo;
-    let mut s1 = hi;
+    let mut bands = Vec::with_capacity(3);
+    let mut playfield = band;
     if (24..120).contains(&down_skim_len) {
         //move the top split lower
-        s0.scanline = down_skim_len;
+        playfield.top.scanline = down_skim_len;
+        bands.push(Band {
+            bottom: playfield.top,
+            kind: BandKind::Hud,
+            ..band
+        });
     }
+    let main = bands.len();
     if (24..120).contains(&up_skim_len) {
         //move the bottom split higher
-        s1.scanline = 240 - up_skim_len;
+        playfield.bottom.scanline = 240 - up_skim_len;
+        bands.push(playfield);
+        bands.push(Band {
+            top: Split {
+                scanline: playfield.bottom.scanline,
+                ..band.top
+            },
+            kind: BandKind::Hud,
+            ..band
+        });
+    } else {
+        bands.push(playfield);
     }
-    (s0, s1)
+    (bands, main)
 }
 
-pub fn get_main_split(
+/// Every band between this frame's splits, top to bottom, and the index of
+/// the main playfield (the tallest band).  Short bands at the top or bottom
+/// of the screen are marked as HUDs.
+/// # Panics
+/// Panics if the splits don't cover any scanlines
+pub fn get_bands(
     changes: &[ScrollChange],
     latch: ScrollLatch,
+    nametable: u8,
     fb: &Framebuffer,
-) -> (Split, Split, ScrollLatch) {
-    let (splits, latch) = get_splits(changes, latch);
-    let (lo, hi) = {
-        match splits.windows(2).max_by_key(|&win| match win {
-            [lo, hi] => hi.scanline - lo.scanline,
+    hud_max_height: u8,
+) -> (Vec<Band>, usize, ScrollLatch) {
+    let (splits, latch) = get_splits(changes, latch, nametable);
+    let mut bands: Vec<_> = splits
+        .windows(2)
+        .map(|win| match *win {
+            [top, bottom] => Band {
+                top,
+                bottom,
+                kind: BandKind::Playfield,
+            },
             _ => panic!("Misshapen windows"),
-        }) {
-            Some(&[lo, hi]) => (lo, hi),
-            _ => panic!("No valid splits"),
-        }
-    };
+        })
+        .collect();
+    let mut main = bands
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, band)| band.height())
+        .map(|(i, _)| i)
+        .expect("No valid splits");
     // Is splitting happening some other way?
     // E.g. in Zelda the "room" abuts the "menu"
-    if hi.scanline - lo.scanline >= 239 {
-        let (lo_b, hi_b) = get_best_effort_splits(fb, lo, hi);
-        // dbg!(("a", lo, hi, lo_b, hi_b, latch, changes.len()));
-        (lo_b, hi_b, latch)
-    } else {
-        // dbg!(("b", lo,hi,latch,changes.len()));
-        (lo, hi, latch)
+    if bands[main].height() >= 239 {
+        let (effort, effort_main) = get_best_effort_bands(fb, bands[main]);
+        bands.splice(main..=main, effort);
+        main += effort_main;
     }
+    let last = bands.len() - 1;
+    for (i, band) in bands.iter_mut().enumerate() {
+        if i != main && (i == 0 || i == last) && band.height() <= hud_max_height {
+            band.kind = BandKind::Hud;
+        }
+    }
+    (bands, main, latch)
+}
+/// Like `get_bands`, but for games whose playfield is known in advance;
+/// everything outside of it is HUD.
+pub fn get_fixed_bands(
+    changes: &[ScrollChange],
+    latch: ScrollLatch,
+    nametable: u8,
+    fixed: SplitOverride,
+) -> (Vec<Band>, usize, ScrollLatch) {
+    let (splits, latch) = get_splits(changes, latch, nametable);
+    let active = splits
+        .iter()
+        .rev()
+        .find(|s| s.scanline <= fixed.top)
+        .copied()
+        .unwrap_or(splits[0]);
+    let at = |scanline| Split {
+        scanline,
+        scroll_x: 0,
+        scroll_y: 0,
+        nametable: 0,
+    };
+    let mut bands = Vec::with_capacity(3);
+    if fixed.top > 0 {
+        bands.push(Band {
+            top: at(0),
+            bottom: at(fixed.top),
+            kind: BandKind::Hud,
+        });
+    }
+    let main = bands.len();
+    bands.push(Band {
+        top: Split {
+            scanline: fixed.top,
+            ..active
+        },
+        bottom: at(fixed.bottom),
+        kind: BandKind::Playfield,
+    });
+    if fixed.bottom < 240 {
+        bands.push(Band {
+            top: at(fixed.bottom),
+            bottom: at(240),
+            kind: BandKind::Hud,
+        });
+    }
+    (bands, main, latch)
 }
-const SCREEN_SAFE_LEFT: u32 = 8;
-const SCREEN_SAFE_RIGHT: u32 = 8;
-const SCREEN_SAFE_TOP: u32 = 8;
-const SCREEN_SAFE_BOTTOM: u32 = 8;
 #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
-pub fn split_region_for(lo: u32, hi: u32, xo: u8, yo: u8, w: u32, h: u32) -> Rect {
-    let lo = lo.max(SCREEN_SAFE_TOP);
-    let hi = hi.min(h - SCREEN_SAFE_BOTTOM);
+pub fn split_region_for(
+    lo: u32,
+    hi: u32,
+    xo: u8,
+    yo: u8,
+    w: u32,
+    h: u32,
+    safe: &ScreenSafe,
+) -> Rect {
+    let lo = lo.max(safe.top);
+    let hi = hi.min(h.saturating_sub(safe.bottom));
     let xo = ((TILE_SIZE - (xo as usize % TILE_SIZE)) % TILE_SIZE) as u32;
     let yo = ((TILE_SIZE - (yo as usize % TILE_SIZE)) % TILE_SIZE) as u32;
-    let dy = hi - (lo + yo);
+    let dy = hi.saturating_sub(lo + yo);
     let dy = (dy / (TILE_SIZE as u32)) * (TILE_SIZE as u32);
-    let dx = (w - SCREEN_SAFE_RIGHT) - (xo + SCREEN_SAFE_LEFT);
+    let dx = w.saturating_sub(safe.right).saturating_sub(xo + safe.left);
     let dx = (dx / (TILE_SIZE as u32)) * (TILE_SIZE as u32);
-    Rect::new(
-        SCREEN_SAFE_LEFT as i32 + xo as i32,
-        lo as i32 + yo as i32,
-        dx,
-        dy,
-    )
+    Rect::new(safe.left as i32 + xo as i32, lo as i32 + yo as i32, dx, dy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn write_scroll(changes: &mut Vec<ScrollChange>, scanline: u8, x: u8, y: u8) {
+        for value in [x, y] {
+            changes.push(ScrollChange {
+                reason: ScrollChangeReason::Write2005,
+                scanline,
+                value,
+            });
+        }
+    }
+    #[test]
+    fn test_bands() {
+        let fb = Framebuffer::new(256, 240);
+        // Scroll set in vblank, then a status bar from scanline 192 down
+        let mut changes = vec![];
+        write_scroll(&mut changes, 241, 40, 0);
+        write_scroll(&mut changes, 191, 0, 0);
+        let (bands, main, _) = get_bands(&changes, ScrollLatch::default(), 0, &fb, 56);
+        let kinds: Vec<_> = bands.iter().map(|b| b.kind).collect();
+        assert_eq!(kinds, [BandKind::Hud, BandKind::Playfield, BandKind::Hud]);
+        assert_eq!(main, 1);
+        assert_eq!((bands[1].top.scanline, bands[1].top.scroll_x), (1, 40));
+        assert_eq!(bands[2].top.scanline, 192);
+        // Two independently scrolling bands between the status bars
+        changes.clear();
+        write_scroll(&mut changes, 241, 40, 0);
+        write_scroll(&mut changes, 99, 80, 0);
+        write_scroll(&mut changes, 191, 0, 0);
+        let (bands, main, _) = get_bands(&changes, ScrollLatch::default(), 0, &fb, 56);
+        let kinds: Vec<_> = bands.iter().map(|b| b.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                BandKind::Hud,
+                BandKind::Playfield,
+                BandKind::Playfield,
+                BandKind::Hud
+            ]
+        );
+        assert_eq!(main, 1);
+        assert_eq!((bands[1].height(), bands[2].height()), (99, 92));
+        assert_eq!(bands[2].top.scroll_x, 80);
+    }
+    #[test]
+    fn test_nametable_scroll() {
+        let change = |reason, scanline, value| ScrollChange {
+            reason,
+            scanline,
+            value,
+        };
+        // Nametable 1 from PPUCTRL, then a $2006 split at 100 selecting nametable 2
+        let changes = [
+            change(ScrollChangeReason::Write2005, 241, 200),
+            change(ScrollChangeReason::Write2005, 241, 0),
+            change(ScrollChangeReason::Write2006, 102, 0b0000_1000),
+            change(ScrollChangeReason::Write2006, 102, 0),
+        ];
+        let (splits, _) = get_splits(&changes, ScrollLatch::default(), 0b1001_0001);
+        assert_eq!(splits[1].nametable, 1);
+        assert_eq!(splits[1].nametable_scroll(), (456, 0));
+        assert_eq!(splits[2].nametable, 2);
+        assert_eq!(splits[2].nametable_scroll(), (0, 240));
+        // A 200 pixel jump right into the next nametable
+        let old = Split {
+            scanline: 0,
+            scroll_x: 200,
+            scroll_y: 0,
+            nametable: 0,
+        };
+        let new = Split {
+            scroll_x: 144,
+            nametable: 1,
+            ..old
+        };
+        assert_eq!(scroll_offset(old, new, (true, false)), (200, 0));
+        // Without nametables it looks like a step left
+        assert_eq!(scroll_offset(old, new, (false, false)), (-56, 0));
+    }
+    #[test]
+    fn test_fixed_bands() {
+        let (bands, main, _) = get_fixed_bands(
+            &[],
+            ScrollLatch::default(),
+            0,
+            SplitOverride {
+                top: 64,
+                bottom: 240,
+            },
+        );
+        assert_eq!(bands.len(), 2);
+        assert_eq!(main, 1);
+        assert_eq!(bands[0].kind, BandKind::Hud);
+        assert_eq!((bands[1].top.scanline, bands[1].height()), (64, 176));
+    }
 }
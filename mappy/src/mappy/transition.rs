@@ -0,0 +1,431 @@
+use super::scroll_diff;
+use crate::config::{MappyConfig, TransitionDetectorKind};
+use crate::framebuffer::Framebuffer;
+use crate::screen::Screen;
+use crate::tile::TileGfxId;
+use crate::{Rect, Time};
+
+/// What a [`RoomTransitionDetector`] gets to look at on each frame.
+pub struct TransitionContext<'a> {
+    pub now: Time,
+    /// This frame, as rgb332.
+    pub fb: &'a Framebuffer,
+    pub scroll: (i32, i32),
+    pub has_control: bool,
+    /// When we last had control before this frame.
+    pub last_control: Time,
+    pub last_controlled_scroll: (i32, i32),
+    pub current_screen: &'a Screen<TileGfxId>,
+    /// The screen as it was when control was last lost.
+    pub last_control_screen: &'a Screen<TileGfxId>,
+    /// Whether a room is being mapped at all.
+    pub in_room: bool,
+    pub config: &'a MappyConfig,
+}
+
+/// Decides when the player has moved on to a new room.
+///
+/// Every detector sees every frame.  When one is at least
+/// `room_transition_threshold` sure, mappy starts a new room as soon as the
+/// player has control.
+pub trait RoomTransitionDetector: Send {
+    /// A short name for logs and events.
+    fn name(&self) -> &'static str;
+    /// How sure (from 0 to 1) this detector is that a transition finished
+    /// on this frame.
+    fn observe(&mut self, ctx: &TransitionContext) -> f32;
+    /// Called when mappy starts a new room or the game is reset, so the same
+    /// evidence isn't counted twice.
+    fn clear(&mut self) {}
+}
+
+/// Builds the detectors `config` asks for.
+#[must_use]
+pub fn from_config(config: &MappyConfig) -> Vec<Box<dyn RoomTransitionDetector>> {
+    config
+        .room_transitions
+        .iter()
+        .map(|kind| -> Box<dyn RoomTransitionDetector> {
+            match kind {
+                TransitionDetectorKind::ControlGap => Box::new(ControlGapDetector),
+                TransitionDetectorKind::Fade => Box::new(FadeDetector::default()),
+                TransitionDetectorKind::Wipe => Box::new(WipeDetector::default()),
+                TransitionDetectorKind::HardCut => Box::new(HardCutDetector::default()),
+            }
+        })
+        .collect()
+}
+
+/// How much two screens differ, as a fraction of what counts as a moderate
+/// room change.
+fn changed(before: &Screen<TileGfxId>, after: &Screen<TileGfxId>, config: &MappyConfig) -> f32 {
+    (after.difference(before) / config.screen_room_change_diff_moderate).min(1.0)
+}
+
+/// The original rule: control came back after more than
+/// `control_room_change_threshold` frames, or the scroll jumped by most of a
+/// screen, and the screen is now different enough from when control was lost.
+/// It's always certain one way or the other.
+pub struct ControlGapDetector;
+
+impl RoomTransitionDetector for ControlGapDetector {
+    fn name(&self) -> &'static str {
+        "control_gap"
+    }
+    fn observe(&mut self, ctx: &TransitionContext) -> f32 {
+        if !ctx.has_control {
+            return 0.0;
+        }
+        let config = ctx.config;
+        let Rect { w: sw, h: sh, .. } = ctx.current_screen.region;
+        let (sdx, sdy) = scroll_diff(ctx.scroll, ctx.last_controlled_scroll);
+        if *ctx.now - *ctx.last_control > config.control_room_change_threshold
+            || sdx.unsigned_abs() >= (sw * 3) / 4
+            || sdy.unsigned_abs() >= (sh * 3) / 4
+        {
+            let diff = ctx.current_screen.difference(ctx.last_control_screen);
+            // println!(
+            //     "{:?}: Regained control after {:?}; diff {:?}, scrolldiff {:?}",
+            //     ctx.now.0,
+            //     ctx.now.0 - ctx.last_control.0,
+            //     diff,
+            //     (sdx, sdy)
+            // );
+            let moderate_difference = diff > config.screen_room_change_diff_moderate;
+            let big_difference = diff > config.screen_room_change_diff_big;
+            let small_scroll = (sdx != 0 || sdy != 0) && (sdx.abs() < 150 && sdy.abs() < 150);
+            if (moderate_difference && !small_scroll) || big_difference || !ctx.in_room {
+                return 1.0;
+            }
+        }
+        0.0
+    }
+}
+
+/// Average brightness of an rgb332 frame, from 0 to 1.
+#[allow(clippy::cast_precision_loss)]
+fn brightness(fb: &Framebuffer) -> f32 {
+    let total: u32 = fb
+        .fb
+        .iter()
+        .map(|&c| {
+            let (r, g, b) = (
+                u32::from(c >> 5),
+                u32::from((c >> 2) & 0b111),
+                u32::from(c & 0b11),
+            );
+            // Luma weights, scaled so full white is 21000
+            r * 299 * 3 + g * 587 * 3 + b * 114 * 7
+        })
+        .sum();
+    total as f32 / (fb.fb.len() as f32 * 21.0 * 1000.0)
+}
+
+/// Notices the screen fading out (to black, or through darker palettes) and
+/// back in, like between Castlevania's stages.  The deeper the fade and the
+/// more the screen changed across it, the surer it is.
+#[derive(Default)]
+pub struct FadeDetector {
+    // Brightness and screen before the fade
+    steady: Option<(f32, Screen<TileGfxId>)>,
+    darkest: Option<f32>,
+}
+
+impl FadeDetector {
+    // The screen is fading once it's this much dimmer than it was...
+    const START: f32 = 0.85;
+    // and fades shallower than this are just flicker
+    const MIN_DEPTH: f32 = 0.3;
+}
+
+impl RoomTransitionDetector for FadeDetector {
+    fn name(&self) -> &'static str {
+        "fade"
+    }
+    fn observe(&mut self, ctx: &TransitionContext) -> f32 {
+        let now = brightness(ctx.fb);
+        let Some((level, before)) = self.steady.as_ref() else {
+            self.steady = Some((now, ctx.current_screen.clone()));
+            return 0.0;
+        };
+        let level = *level;
+        match self.darkest {
+            None if now < level * Self::START => {
+                self.darkest = Some(now);
+                0.0
+            }
+            None => {
+                self.steady = Some((now, ctx.current_screen.clone()));
+                0.0
+            }
+            // Back at least halfway to where it started
+            Some(darkest) if now >= darkest + (level - darkest) / 2.0 && now > darkest => {
+                let depth = 1.0 - darkest / level;
+                let confidence = if depth < Self::MIN_DEPTH {
+                    0.0
+                } else {
+                    depth.min(1.0) * changed(before, ctx.current_screen, ctx.config)
+                };
+                self.darkest = None;
+                self.steady = Some((now, ctx.current_screen.clone()));
+                confidence
+            }
+            Some(darkest) => {
+                self.darkest = Some(darkest.min(now));
+                0.0
+            }
+        }
+    }
+    fn clear(&mut self) {
+        self.darkest = None;
+    }
+}
+
+/// Notices a band of solid color sweeping across the screen, as in
+/// Metroid-style door transitions, and fires once it sweeps away again to
+/// reveal a different screen.
+#[derive(Default)]
+pub struct WipeDetector {
+    before: Option<Screen<TileGfxId>>,
+    // How much of the screen is solid, how many frames that grew, and
+    // whether it covered the screen
+    wipe: Option<(f32, usize, bool)>,
+}
+
+impl WipeDetector {
+    // Below this much solid color the screen is just a screen
+    const IDLE: f32 = 0.2;
+    const COVERED: f32 = 0.9;
+    // Fades and cuts cover the screen all at once; wipes take a few frames
+    const MIN_STEPS: usize = 3;
+
+    /// The fraction of columns or rows (whichever is more) that are one
+    /// solid color, ignoring the top and bottom where status bars live.
+    #[allow(clippy::cast_precision_loss)]
+    fn coverage(fb: &Framebuffer) -> f32 {
+        let (w, h) = (fb.w, fb.h);
+        let (top, bottom) = (h / 5, h - h / 15);
+        let px = |x: usize, y: usize| fb.fb[y * w + x];
+        let columns = (0..w)
+            .filter(|&x| (top..bottom).all(|y| px(x, y) == px(x, top)))
+            .count();
+        let rows = (top..bottom)
+            .filter(|&y| (0..w).all(|x| px(x, y) == px(0, y)))
+            .count();
+        (columns as f32 / w as f32).max(rows as f32 / (bottom - top) as f32)
+    }
+}
+
+impl RoomTransitionDetector for WipeDetector {
+    fn name(&self) -> &'static str {
+        "wipe"
+    }
+    fn observe(&mut self, ctx: &TransitionContext) -> f32 {
+        let coverage = Self::coverage(ctx.fb);
+        match self.wipe {
+            None if coverage < Self::IDLE => {
+                self.before = Some(ctx.current_screen.clone());
+                0.0
+            }
+            None => {
+                self.wipe = Some((coverage, 0, coverage >= Self::COVERED));
+                0.0
+            }
+            Some((_, steps, covered)) if coverage < Self::IDLE => {
+                self.wipe = None;
+                let confidence = match self.before.as_ref() {
+                    Some(before) if covered && steps >= Self::MIN_STEPS => {
+                        changed(before, ctx.current_screen, ctx.config)
+                    }
+                    _ => 0.0,
+                };
+                self.before = Some(ctx.current_screen.clone());
+                confidence
+            }
+            Some((last, steps, covered)) => {
+                let steps = if coverage > last { steps + 1 } else { steps };
+                self.wipe = Some((coverage, steps, covered || coverage >= Self::COVERED));
+                0.0
+            }
+        }
+    }
+    fn clear(&mut self) {
+        self.wipe = None;
+    }
+}
+
+/// Notices most of the picture changing from one frame to the next while
+/// the scroll stays put, like a game swapping in a new room without any
+/// effect.  A cut only counts if the new screen is still there a few frames
+/// later, so flashes don't fool it.
+#[derive(Default)]
+pub struct HardCutDetector {
+    last_fb: Vec<u8>,
+    last_scroll: (i32, i32),
+    last_screen: Option<Screen<TileGfxId>>,
+    // The screen before the cut, and when it happened
+    cut: Option<(Screen<TileGfxId>, Time)>,
+}
+
+impl HardCutDetector {
+    // This much of the picture has to change at once...
+    const CUT: f32 = 0.6;
+    // and stay changed for this many frames
+    const SETTLE: usize = 8;
+}
+
+impl RoomTransitionDetector for HardCutDetector {
+    fn name(&self) -> &'static str {
+        "hard_cut"
+    }
+    #[allow(clippy::cast_precision_loss)]
+    fn observe(&mut self, ctx: &TransitionContext) -> f32 {
+        let mut confidence = 0.0;
+        if let Some((before, at)) = self.cut.as_ref() {
+            if *ctx.now - **at >= Self::SETTLE {
+                let diff = ctx.current_screen.difference(before);
+                confidence = (diff / ctx.config.screen_room_change_diff_big).min(1.0);
+                self.cut = None;
+            }
+        } else if self.last_fb.len() == ctx.fb.fb.len() && ctx.scroll == self.last_scroll {
+            let different = ctx
+                .fb
+                .fb
+                .iter()
+                .zip(&self.last_fb)
+                .filter(|(a, b)| a != b)
+                .count();
+            if different as f32 >= Self::CUT * ctx.fb.fb.len() as f32 {
+                self.cut = self.last_screen.take().map(|before| (before, ctx.now));
+            }
+        }
+        self.last_fb.clone_from(&ctx.fb.fb);
+        self.last_scroll = ctx.scroll;
+        self.last_screen = Some(ctx.current_screen.clone());
+        confidence
+    }
+    fn clear(&mut self) {
+        self.cut = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tile::{TILE_NUM_PX, TileDB, TileGfx};
+
+    struct Frames {
+        screens: [Screen<TileGfxId>; 2],
+        config: MappyConfig,
+        now: usize,
+    }
+    impl Frames {
+        fn new() -> Self {
+            let mut db = TileDB::new();
+            let region = Rect::new(0, 0, 32, 30);
+            let a = db.get_tile(TileGfx([1; TILE_NUM_PX]));
+            let b = db.get_tile(TileGfx([2; TILE_NUM_PX]));
+            Self {
+                screens: [Screen::new(region, a), Screen::new(region, b)],
+                config: MappyConfig::default(),
+                now: 0,
+            }
+        }
+        /// Shows each frame (with the screen it's on) and returns the
+        /// detector's highest confidence.
+        fn show(
+            &mut self,
+            detector: &mut dyn RoomTransitionDetector,
+            frames: impl IntoIterator<Item = (Framebuffer, usize)>,
+        ) -> f32 {
+            let mut most: f32 = 0.0;
+            for (fb, screen) in frames {
+                self.now += 1;
+                let ctx = TransitionContext {
+                    now: Time(self.now),
+                    fb: &fb,
+                    scroll: (0, 0),
+                    has_control: false,
+                    last_control: Time(0),
+                    last_controlled_scroll: (0, 0),
+                    current_screen: &self.screens[screen],
+                    last_control_screen: &self.screens[0],
+                    in_room: true,
+                    config: &self.config,
+                };
+                most = most.max(detector.observe(&ctx));
+            }
+            most
+        }
+    }
+    fn solid(c: u8) -> Framebuffer {
+        let mut fb = Framebuffer::new(256, 240);
+        fb.fb.fill(c);
+        fb
+    }
+    fn pattern(seed: u8) -> Framebuffer {
+        let mut fb = Framebuffer::new(256, 240);
+        for (i, px) in fb.fb.iter_mut().enumerate() {
+            #[allow(clippy::cast_possible_truncation)]
+            let xy = ((i % 256) ^ (i / 256)) as u8;
+            *px = xy.wrapping_mul(seed) | 0b1001_0010;
+        }
+        fb
+    }
+
+    #[test]
+    fn test_fade() {
+        let mut frames = Frames::new();
+        let mut fade = FadeDetector::default();
+        let out = [0xFF, 0xB6, 0x6D, 0x24, 0x00, 0x00];
+        let fade_to = |screen| {
+            (0..5)
+                .map(|_| (solid(0xFF), 0))
+                .chain(out.iter().map(|&c| (solid(c), 0)))
+                .chain(out.iter().rev().map(move |&c| (solid(c), screen)))
+        };
+        assert!(frames.show(&mut fade, fade_to(1)) > 0.9);
+        // Fading out and back to the same screen is probably a pause menu
+        assert!(frames.show(&mut fade, fade_to(0)) < 0.1);
+    }
+
+    #[test]
+    fn test_wipe() {
+        let mut frames = Frames::new();
+        let mut wipe = WipeDetector::default();
+        let wiped = |columns: usize| {
+            let mut fb = pattern(7);
+            for y in 0..240 {
+                fb.fb[y * 256..y * 256 + columns].fill(0);
+            }
+            fb
+        };
+        let sweep = (0..5)
+            .map(|_| (pattern(7), 0))
+            .chain((0..=8).map(|i| (wiped(i * 32), 0)))
+            .chain([(pattern(11), 1)]);
+        assert!(frames.show(&mut wipe, sweep) > 0.9);
+        // All at once is a cut (or a fade), not a wipe
+        let cut = (0..5)
+            .map(|_| (pattern(7), 0))
+            .chain([(solid(0), 0), (pattern(11), 1)]);
+        assert!(frames.show(&mut wipe, cut) < 0.1);
+    }
+
+    #[test]
+    fn test_hard_cut() {
+        let mut frames = Frames::new();
+        let mut hard_cut = HardCutDetector::default();
+        let cut = (0..5)
+            .map(|_| (pattern(7), 0))
+            .chain((0..10).map(|_| (pattern(11), 1)));
+        assert!(frames.show(&mut hard_cut, cut) > 0.9);
+        // A flash that goes right back isn't a new room
+        let mut hard_cut = HardCutDetector::default();
+        let flash = (0..5)
+            .map(|_| (pattern(7), 0))
+            .chain((0..2).map(|_| (pattern(11), 1)))
+            .chain((0..10).map(|_| (pattern(7), 0)));
+        assert!(frames.show(&mut hard_cut, flash) < 0.1);
+    }
+}
@@ -11,6 +11,108 @@ pub struct Metaroom {
     pub id: MetaroomID,
     pub registrations: Vec<(usize, (i32, i32))>,
     pub merged_into: Vec<MetaroomID>,
+    // How sure the merge that made this metaroom was of each metaroom it took in
+    pub confidences: Vec<(MetaroomID, MergeConfidence)>,
+}
+
+/// One way a room could be registered into a metaroom, as found by
+/// [`crate::merge_cost`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Placement {
+    pub offset: (i32, i32),
+    pub cost: f32,
+    // How many tiles were compared to come to that cost
+    pub comparisons: u32,
+}
+
+// Placements this many tiles apart or less (in both directions) are the
+// same fit, give or take a tile
+const NEAR_OFFSET: i32 = 1;
+
+impl Placement {
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn cost_per_comparison(&self) -> f32 {
+        self.cost / self.comparisons.max(1) as f32
+    }
+    /// Whether `other` is close enough to this placement to be the same
+    /// fit, so [`crate::merge_cost`] only keeps the better of the two.
+    #[must_use]
+    pub fn is_near(&self, other: &Placement) -> bool {
+        (self.offset.0 - other.offset.0).abs() <= NEAR_OFFSET
+            && (self.offset.1 - other.offset.1).abs() <= NEAR_OFFSET
+    }
+}
+
+/// How sure a merge was of where a room went in a metaroom.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MergeConfidence {
+    // Of the placement chosen
+    pub cost_per_comparison: f32,
+    // How much more the runner-up placement in the same metaroom cost per
+    // comparison, if there was one under the merge threshold
+    pub margin: Option<f32>,
+}
+
+impl MergeConfidence {
+    /// The confidence in the first of `placements`, which go best first,
+    /// judged against the runner-up among them.
+    /// # Panics
+    /// Panics if `placements` is empty
+    #[must_use]
+    pub fn of(placements: &[Placement]) -> Self {
+        let best = placements[0].cost_per_comparison();
+        Self {
+            cost_per_comparison: best,
+            margin: placements.get(1).map(|p| p.cost_per_comparison() - best),
+        }
+    }
+    /// Whether the runner-up came within `min_margin` of the best; never, if
+    /// `min_margin` is 0.
+    #[must_use]
+    pub fn is_close_call(&self, min_margin: f32) -> bool {
+        self.margin.is_some_and(|m| m < min_margin)
+    }
+}
+
+/// The best placement in each metaroom [`crate::merge_cost`] found any in,
+/// with how sure it was, ready for [`Merges::merge_new_room`].  Each is
+/// judged only against its own metaroom's runner-up: fitting several
+/// metarooms is what merges them, so that's no reason to doubt a fit.
+#[must_use]
+pub fn best_placements(
+    candidates: &[(MetaroomID, Vec<Placement>)],
+) -> Vec<(MetaroomID, (i32, i32), MergeConfidence)> {
+    candidates
+        .iter()
+        .filter(|(_, ps)| !ps.is_empty())
+        .map(|(mid, ps)| (*mid, ps[0].offset, MergeConfidence::of(ps)))
+        .collect()
+}
+
+/// Rooms whose merges were too close to call, each with the metarooms it
+/// fit, waiting for one of those to change before they're tried again.
+#[derive(Clone, Default)]
+pub(crate) struct Undecided(Vec<(usize, Vec<MetaroomID>)>);
+
+impl Undecided {
+    pub(crate) fn defer(&mut self, room: usize, candidates: &[(MetaroomID, Vec<Placement>)]) {
+        self.0
+            .push((room, candidates.iter().map(|(mid, _)| *mid).collect()));
+    }
+    /// Takes out the rooms which fit any of `merged`, the metarooms a merge
+    /// just took in, oldest first.
+    pub(crate) fn take_affected(&mut self, merged: &[MetaroomID]) -> Vec<usize> {
+        let (affected, rest) = std::mem::take(&mut self.0)
+            .into_iter()
+            .partition(|(_, fits)| fits.iter().any(|mid| merged.contains(mid)));
+        self.0 = rest;
+        affected.into_iter().map(|(room, _)| room).collect()
+    }
+    /// Takes out the room that has waited longest.
+    pub(crate) fn take_first(&mut self) -> Option<usize> {
+        (!self.0.is_empty()).then(|| self.0.remove(0).0)
+    }
 }
 
 impl Metaroom {
@@ -19,13 +121,19 @@ impl Metaroom {
             id,
             registrations: vec![(rid, (0, 0))],
             merged_into: vec![],
+            confidences: vec![],
         }
     }
-    fn new_merge(id: MetaroomID, registrations: Vec<(usize, (i32, i32))>) -> Self {
+    fn new_merge(
+        id: MetaroomID,
+        registrations: Vec<(usize, (i32, i32))>,
+        confidences: Vec<(MetaroomID, MergeConfidence)>,
+    ) -> Self {
         Self {
             id,
             registrations,
             merged_into: vec![],
+            confidences,
         }
     }
     #[must_use]
@@ -59,6 +167,11 @@ impl Merges {
     pub(crate) fn new() -> Self {
         Self { metarooms: vec![] }
     }
+    /// Rebuilds the merge graph from metarooms listed in the order
+    /// [`Merges::all_metarooms`] gives them.
+    pub(crate) fn from_metarooms(metarooms: Vec<Metaroom>) -> Self {
+        Self { metarooms }
+    }
     #[must_use]
     pub fn len(&self) -> usize {
         self.metarooms.len()
@@ -79,6 +192,13 @@ impl Merges {
     pub fn metaroom_mut(&mut self, id: MetaroomID) -> &mut Metaroom {
         self.metarooms.iter_mut().find(|mr| mr.id == id).unwrap()
     }
+    /// Whether metaroom `id` exists and hasn't been merged into another.
+    #[must_use]
+    pub fn is_unmerged(&self, id: MetaroomID) -> bool {
+        self.metarooms
+            .iter()
+            .any(|mr| mr.id == id && mr.merged_into.is_empty())
+    }
     pub fn metarooms(&self) -> impl Iterator<Item = &Metaroom> {
         self.all_metarooms()
             .take_while(|mr| mr.merged_into.is_empty())
@@ -89,7 +209,7 @@ impl Merges {
     pub fn merge_new_room(
         &mut self,
         room: usize,
-        merges: &[(MetaroomID, (i32, i32), f32)],
+        merges: &[(MetaroomID, (i32, i32), MergeConfidence)],
     ) -> MetaroomID {
         println!("Final merge {room}->{merges:?}");
         if merges.is_empty() {
@@ -124,9 +244,50 @@ impl Merges {
         }
         println!("pushed meta c {mid:?} {regs:?}");
 
-        self.metarooms.push(Metaroom::new_merge(mid, regs));
+        let confidences = merges.iter().map(|(mri, _, c)| (*mri, *c)).collect();
+        self.metarooms
+            .push(Metaroom::new_merge(mid, regs, confidences));
         //resort everything
         self.metarooms.sort_unstable_by_key(|m| m.merged_into.len());
         mid
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_best_placements() {
+        let at = |x, cost| Placement {
+            offset: (x, 0),
+            cost,
+            comparisons: 100,
+        };
+        assert!(at(0, 1.0).is_near(&at(1, 0.0)));
+        assert!(!at(0, 1.0).is_near(&at(2, 0.0)));
+        let candidates = [
+            (MetaroomID(0), vec![at(0, 1.0), at(5, 9.0)]),
+            (MetaroomID(1), vec![at(3, 2.0)]),
+            (MetaroomID(2), vec![]),
+        ];
+        let best = best_placements(&candidates);
+        assert_eq!(best.len(), 2);
+        // Only metaroom 0's own runner-up counts against its best fit, not
+        // metaroom 1's nearer one
+        assert!((best[0].2.margin.unwrap() - 0.08).abs() < 1e-6);
+        assert_eq!(best[1].2.margin, None);
+        assert!(best[0].2.is_close_call(0.1));
+        assert!(!best[0].2.is_close_call(0.02));
+        assert!(!best[0].2.is_close_call(0.0));
+
+        let mut undecided = Undecided::default();
+        undecided.defer(7, &candidates[..1]);
+        undecided.defer(8, &candidates[1..]);
+        undecided.defer(9, &candidates);
+        assert_eq!(undecided.take_affected(&[MetaroomID(2)]), [8, 9]);
+        assert!(undecided.take_affected(&[MetaroomID(2)]).is_empty());
+        assert_eq!(undecided.take_first(), Some(7));
+        assert_eq!(undecided.take_first(), None);
+    }
+}
@@ -0,0 +1,209 @@
+use crate::Rect;
+use crate::config::{
+    ConfigError, ControlDetectorKind, MappyConfig, ProbePresses, SplitOverride,
+    TransitionDetectorKind,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+const INES_HEADER_SIZE: usize = 16;
+const INES_TRAINER_SIZE: usize = 512;
+const PRG_BANK_SIZE: usize = 16384;
+const CHR_BANK_SIZE: usize = 8192;
+
+/// Identifies a ROM by the CRC32 and MD5 of its PRG and CHR data.
+///
+/// The iNES header (and trainer, if any) is left out, so re-headered dumps of
+/// the same game hash identically.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RomHash {
+    pub crc32: u32,
+    pub md5: [u8; 16],
+}
+
+impl RomHash {
+    /// # Errors
+    /// Returns an error if the ROM file can't be read
+    pub fn from_file(path: &Path) -> std::io::Result<Self> {
+        Ok(Self::of_rom(&std::fs::read(path)?))
+    }
+    /// Hashes an in-memory ROM image.  Files without an iNES header are
+    /// hashed in their entirety.
+    #[must_use]
+    pub fn of_rom(rom: &[u8]) -> Self {
+        let data = Self::prg_chr(rom);
+        Self {
+            crc32: crc32fast::hash(data),
+            md5: md5::compute(data).0,
+        }
+    }
+    fn prg_chr(rom: &[u8]) -> &[u8] {
+        if rom.len() < INES_HEADER_SIZE || &rom[0..4] != b"NES\x1a" {
+            return rom;
+        }
+        let trainer = if rom[6] & 0b100 != 0 {
+            INES_TRAINER_SIZE
+        } else {
+            0
+        };
+        let start = (INES_HEADER_SIZE + trainer).min(rom.len());
+        let len = usize::from(rom[4]) * PRG_BANK_SIZE + usize::from(rom[5]) * CHR_BANK_SIZE;
+        &rom[start..(start + len).min(rom.len())]
+    }
+    #[must_use]
+    pub fn crc32_hex(&self) -> String {
+        format!("{:08x}", self.crc32)
+    }
+    #[must_use]
+    pub fn md5_hex(&self) -> String {
+        self.md5.iter().map(|b| format!("{b:02x}")).collect()
+    }
+    /// The MD5 in the form FCEUX expects on an fm2 `romChecksum` line.
+    #[must_use]
+    pub fn fm2_checksum(&self) -> String {
+        use base64::Engine;
+        format!(
+            "base64:{}",
+            base64::engine::general_purpose::STANDARD.encode(self.md5)
+        )
+    }
+}
+
+/// Per-game overrides, matched against a [`RomHash`] by CRC32 and/or MD5
+/// (given as hex strings).  Any setting left out keeps whatever value the
+/// [`MappyConfig`] already had.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GameProfile {
+    pub name: String,
+    pub crc32: Option<String>,
+    pub md5: Option<String>,
+    pub room_merge_threshold: Option<f32>,
+    pub split: Option<SplitOverride>,
+    pub control_detector: Option<ControlDetectorKind>,
+    pub control_probes: Option<[String; 2]>,
+    pub control_probe_sets: Vec<[String; 2]>,
+    pub probe_presses: Option<ProbePresses>,
+    pub room_transitions: Option<Vec<TransitionDetectorKind>>,
+    pub ram: BTreeMap<String, u16>,
+    pub menus: Vec<Rect>,
+    pub nametable_scroll: Option<bool>,
+}
+
+impl GameProfile {
+    #[must_use]
+    pub fn matches(&self, hash: &RomHash) -> bool {
+        let crc_ok = self.crc32.as_deref().map(|c| {
+            c.trim_start_matches("0x")
+                .eq_ignore_ascii_case(&hash.crc32_hex())
+        });
+        let md5_ok = self
+            .md5
+            .as_deref()
+            .map(|m| m.eq_ignore_ascii_case(&hash.md5_hex()));
+        match (crc_ok, md5_ok) {
+            (None, None) => false,
+            (crc_ok, md5_ok) => crc_ok.unwrap_or(true) && md5_ok.unwrap_or(true),
+        }
+    }
+    pub fn apply(&self, config: &mut MappyConfig) {
+        if let Some(threshold) = self.room_merge_threshold {
+            config.room_merge_threshold = threshold;
+        }
+        if let Some(split) = self.split {
+            config.split_override = Some(split);
+        }
+        if let Some(probes) = &self.control_probes {
+            config.control_probes = Some(probes.clone());
+        }
+        if let Some(detector) = &self.control_detector {
+            config.control_detector = detector.clone();
+        }
+        config
+            .control_probe_sets
+            .extend_from_slice(&self.control_probe_sets);
+        if let Some(presses) = self.probe_presses {
+            config.probe_presses = presses;
+        }
+        if let Some(transitions) = &self.room_transitions {
+            config.room_transitions.clone_from(transitions);
+        }
+        config
+            .ram_addresses
+            .extend(self.ram.iter().map(|(k, v)| (k.clone(), *v)));
+        config.menu_regions.extend_from_slice(&self.menus);
+        if let Some(nametable_scroll) = self.nametable_scroll {
+            config.nametable_scroll = nametable_scroll;
+        }
+    }
+}
+
+/// A list of [`GameProfile`]s, normally read from `profiles.toml`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileDB {
+    #[serde(default, rename = "game")]
+    pub games: Vec<GameProfile>,
+}
+
+impl ProfileDB {
+    /// # Errors
+    /// Returns an error if the file can't be read or isn't a valid profile list
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+        Self::from_toml_str(&text)
+    }
+    /// Like [`ProfileDB::load`], but a missing file just means no profiles.
+    /// # Errors
+    /// Returns an error if the file exists but can't be read or parsed
+    pub fn load_if_present(path: &Path) -> Result<Self, ConfigError> {
+        if path.exists() {
+            Self::load(path)
+        } else {
+            Ok(Self::default())
+        }
+    }
+    /// # Errors
+    /// Returns an error if the text isn't a valid profile list
+    pub fn from_toml_str(text: &str) -> Result<Self, ConfigError> {
+        toml::from_str(text).map_err(ConfigError::Toml)
+    }
+    #[must_use]
+    pub fn lookup(&self, hash: &RomHash) -> Option<&GameProfile> {
+        self.games.iter().find(|g| g.matches(hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_header_ignored() {
+        let mut rom = vec![0_u8; 16 + PRG_BANK_SIZE + CHR_BANK_SIZE];
+        rom[0..4].copy_from_slice(b"NES\x1a");
+        rom[4] = 1;
+        rom[5] = 1;
+        rom[16] = 0xEA;
+        let hash = RomHash::of_rom(&rom);
+        rom[7] = 0x08;
+        rom[10] = 0xFF;
+        assert_eq!(hash, RomHash::of_rom(&rom));
+        assert_eq!(hash, RomHash::of_rom(&rom[16..]));
+        let db = ProfileDB::from_toml_str(&format!(
+            "[[game]]\nname = \"test\"\ncrc32 = \"{}\"\nroom_merge_threshold = 4.0\nmenus = [{{ x = 0, y = 0, w = 256, h = 56 }}]\n",
+            hash.crc32_hex().to_uppercase()
+        ))
+        .unwrap();
+        let mut config = MappyConfig::default();
+        db.lookup(&hash).unwrap().apply(&mut config);
+        assert_eq!(config.room_merge_threshold, 4.0);
+        assert_eq!(config.menu_regions, vec![Rect::new(0, 0, 256, 56)]);
+        assert!(db.lookup(&RomHash::of_rom(&rom[17..])).is_none());
+        // A misspelled setting is an error rather than silently ignored
+        assert!(
+            ProfileDB::from_toml_str("[[game]]\nname = \"test\"\nroom_merge_treshold = 4.0\n")
+                .is_err()
+        );
+    }
+}
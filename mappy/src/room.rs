@@ -1,49 +1,85 @@
 use crate::Rect;
 use crate::screen::Screen;
 use crate::tile::{TileChange, TileDB, TileGfxId};
-// use std::collections::HashSet;
+use std::collections::HashMap;
+
+/// Side length, in tiles, of the square chunks a room is stored in.
+pub const CHUNK_SIZE: usize = 32;
+type Chunk = Box<[TileChange; CHUNK_SIZE * CHUNK_SIZE]>;
 
-type RoomScreen = Screen<TileChange>;
 #[derive(Clone)]
 pub struct Room {
     pub id: usize,
-    pub screens: Vec<RoomScreen>,
-    // pub seen_changes: HashSet<TileChange>,
+    // Chunk (cx,cy) holds the CHUNK_SIZE square of tiles, row by row, whose
+    // top left is origin + (cx,cy) * CHUNK_SIZE
+    origin: (i32, i32),
+    chunks: HashMap<(i32, i32), Chunk>,
+    // What a chunk's cells hold before anything is seen there
+    initial: TileChange,
     pub top_left: (i32, i32),
     pub bottom_right: (i32, i32),
+    // How many cells have seen each change, counted once the room is done
+    histogram: HashMap<TileChange, usize>,
 }
-// TODO consider dense grid of screens so that lookups are fast and predictable
 
 impl Room {
     pub fn new(id: usize, screen: &Screen<TileGfxId>, db: &mut TileDB) -> Self {
+        let initial = db.get_initial_change();
         let mut ret = Self {
             id,
-            screens: vec![Screen::new(
-                Rect {
-                    x: screen.region.x,
-                    y: screen.region.y,
-                    w: 32,
-                    h: 32,
-                },
-                db.get_initial_change(),
-            )],
-            // seen_changes: HashSet::new(),
+            origin: (screen.region.x, screen.region.y),
+            chunks: HashMap::from([((0, 0), Box::new([initial; CHUNK_SIZE * CHUNK_SIZE]))]),
+            initial,
             top_left: (screen.region.x, screen.region.y),
             // TODO hacky, probably not right
             bottom_right: (screen.region.x + 1, screen.region.y + 1),
+            histogram: HashMap::new(),
         };
         if screen.region.w != 0 && screen.region.h != 0 {
             ret.register_screen(screen, db);
         }
         ret
     }
+    /// Rebuilds a room from `screens` of changes, which can be any size.
+    /// Chunks line up with the first screen's top left corner.
+    /// # Panics
+    /// Panics if `screens` is empty
+    #[must_use]
+    pub fn from_screens(
+        id: usize,
+        top_left: (i32, i32),
+        bottom_right: (i32, i32),
+        screens: &[Screen<TileChange>],
+        initial: TileChange,
+    ) -> Self {
+        let mut room = Self {
+            id,
+            origin: (screens[0].region.x, screens[0].region.y),
+            chunks: HashMap::new(),
+            initial,
+            top_left,
+            bottom_right,
+            histogram: HashMap::new(),
+        };
+        for s in screens {
+            for y in s.region.y..(s.region.y + s.region.h as i32) {
+                for x in s.region.x..(s.region.x + s.region.w as i32) {
+                    *room.cell_mut(x, y) = s[(x, y)];
+                }
+            }
+        }
+        room.histogram = room.count_changes();
+        room
+    }
+    /// The width of the region this room has seen.
     #[must_use]
     pub fn width(&self) -> u32 {
-        self.screens[0].region.w
+        self.region().w
     }
+    /// The height of the region this room has seen.
     #[must_use]
     pub fn height(&self) -> u32 {
-        self.screens[0].region.h
+        self.region().h
     }
     #[must_use]
     #[allow(clippy::cast_sign_loss)]
@@ -55,173 +91,158 @@ impl Room {
             h: (self.bottom_right.1 - self.top_left.1) as u32,
         }
     }
-    /// # Panics
-    /// Panics if the computed screen region doesn't contain the region pointed by x,y.
     #[allow(clippy::cast_possible_wrap)]
     pub fn reregister_at(&mut self, x: i32, y: i32) {
         let Rect { x: ox, y: oy, w, h } = self.region();
         self.top_left = (x, y);
         self.bottom_right = (x + w as i32, y + h as i32);
-        let xoff = x - ox;
-        let yoff = y - oy;
-        for s in &mut self.screens {
-            s.reregister_at(s.region.x + xoff, s.region.y + yoff);
-        }
+        self.origin = (self.origin.0 + x - ox, self.origin.1 + y - oy);
         println!("rereg {:?}", self.region());
-        let r = self.region();
-        let sr = self.screens_region();
-        assert!(sr.contains_rect(&r), "{sr:?} does not contain {r:?}");
     }
-    /// # Panics
-    /// Panics if the computed screen region doesn't contain the region pointed by x,y.
     #[must_use]
-    pub fn finalize(mut self, initial: TileChange) -> Self {
-        let r = self.region();
-        let sr = self.screens_region();
-        assert!(sr.contains_rect(&r), "{sr:?} does not contain {r:?}");
+    pub fn finalize(mut self) -> Self {
         self.reregister_at(0, 0);
-        self.screens = vec![Screen::combine(self.screens, initial)];
-        let r = self.region();
-        let sr = self.screens_region();
-        assert!(sr.contains_rect(&r), "{sr:?} does not contain {r:?}");
+        self.histogram = self.count_changes();
         self
     }
     #[must_use]
+    #[inline]
     pub fn get(&self, x: i32, y: i32) -> Option<TileChange> {
-        self.get_screen_for(x, y).map(|s| self.screens[s][(x, y)])
+        let (chunk, i) = self.chunk_index(x, y);
+        self.chunks.get(&chunk).map(|c| c[i])
     }
-    #[must_use]
-    pub fn get_screen_for(&self, x: i32, y: i32) -> Option<usize> {
-        self.screens.iter().position(|s| s.region.contains(x, y))
+    /// Row `y` from `x0` up to `x1` as runs of cells that lie next to each
+    /// other in memory, each with the x of its first cell.  Cells no chunk
+    /// covers are skipped.
+    #[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+    pub fn row_slices(
+        &self,
+        y: i32,
+        x0: i32,
+        x1: i32,
+    ) -> impl Iterator<Item = (i32, &[TileChange])> {
+        let mut x = x0;
+        std::iter::from_fn(move || {
+            while x < x1 {
+                let (chunk, i) = self.chunk_index(x, y);
+                let run = (CHUNK_SIZE - i % CHUNK_SIZE).min((x1 - x) as usize);
+                let start = x;
+                x += run as i32;
+                if let Some(c) = self.chunks.get(&chunk) {
+                    return Some((start, &c[i..i + run]));
+                }
+            }
+            None
+        })
     }
-    // x,y are in tile coordinates
-    #[must_use]
+    /// Column `x` from `y0` up to `y1`, top to bottom, skipping cells no
+    /// chunk covers.
+    pub fn column(&self, x: i32, y0: i32, y1: i32) -> impl Iterator<Item = (i32, TileChange)> {
+        (y0..y1).filter_map(move |y| self.get(x, y).map(|t| (y, t)))
+    }
+    /// Every cell of the region that has seen some change, with its x and y,
+    /// row by row.
     #[allow(clippy::cast_possible_wrap)]
-    fn get_screen_for_or_add(&mut self, x: i32, y: i32, db: &TileDB) -> usize {
-        if let Some(n) = self.get_screen_for(x, y) {
-            return n;
-        }
-        let r0 = self.screens[0].region;
-        let mut sx = r0.x;
-        if x < r0.x {
-            while x < sx {
-                sx -= r0.w as i32;
-            }
-        } else {
-            while sx + r0.w as i32 <= x {
-                sx += r0.w as i32;
-            }
+    pub fn seen(&self) -> impl Iterator<Item = (i32, i32, TileChange)> {
+        let r = self.region();
+        (r.y..r.y + r.h as i32).flat_map(move |y| {
+            self.row_slices(y, r.x, r.x + r.w as i32)
+                .flat_map(move |(x, cells)| (x..).zip(cells).map(move |(x, &t)| (x, y, t)))
+                .filter(|&(_, _, t)| t != self.initial)
+        })
+    }
+    /// How many cells of the region have seen each change, as of when the
+    /// room was finalized (or rebuilt from screens); empty before then.
+    #[must_use]
+    pub fn change_histogram(&self) -> &HashMap<TileChange, usize> {
+        &self.histogram
+    }
+    fn count_changes(&self) -> HashMap<TileChange, usize> {
+        let mut hist = HashMap::new();
+        for (_, _, t) in self.seen() {
+            *hist.entry(t).or_default() += 1;
         }
-        let mut sy = r0.y;
-        if y < r0.y {
-            while y < sy {
-                sy -= r0.h as i32;
-            }
-        } else {
-            while sy + r0.h as i32 <= y {
-                sy += r0.h as i32;
-            }
+        hist
+    }
+    /// Every chunk's region and cells (row by row), top to bottom and left
+    /// to right.
+    #[allow(clippy::cast_possible_wrap)]
+    #[must_use]
+    pub fn chunks(&self) -> Vec<(Rect, &[TileChange])> {
+        let mut keys: Vec<_> = self.chunks.keys().copied().collect();
+        keys.sort_unstable_by_key(|&(cx, cy)| (cy, cx));
+        keys.into_iter()
+            .map(|(cx, cy)| {
+                let region = Rect::new(
+                    self.origin.0 + cx * CHUNK_SIZE as i32,
+                    self.origin.1 + cy * CHUNK_SIZE as i32,
+                    CHUNK_SIZE as u32,
+                    CHUNK_SIZE as u32,
+                );
+                (region, &self.chunks[&(cx, cy)][..])
+            })
+            .collect()
+    }
+    /// Replaces every change `t` with `f(t)`.
+    pub fn remap(&mut self, f: impl Fn(TileChange) -> TileChange) {
+        self.initial = f(self.initial);
+        for t in self.chunks.values_mut().flat_map(|c| c.iter_mut()) {
+            *t = f(*t);
         }
-        if !Rect::new(sx, sy, r0.w, r0.h).contains(x, y) {
-            println!(
-                "Rect {},{} {},{} does not contain {},{}",
-                sx, sy, r0.w, r0.h, x, y
-            );
+        let mut histogram = HashMap::with_capacity(self.histogram.len());
+        for (t, n) in self.histogram.drain() {
+            *histogram.entry(f(t)).or_default() += n;
         }
-        assert!(Rect::new(sx, sy, r0.w, r0.h).contains(x, y));
-        assert!(self.get_screen_for(sx, sy).is_none());
-        assert!(self.get_screen_for(sx + r0.w as i32 - 1, sy).is_none());
-        assert!(self.get_screen_for(sx, sy + r0.h as i32 - 1).is_none());
-        assert!(
-            self.get_screen_for(sx + r0.w as i32 - 1, sy + r0.h as i32 - 1)
-                .is_none()
-        );
-        self.screens.push(Screen::new(
-            Rect::new(sx, sy, r0.w, r0.h),
-            db.get_initial_change(),
-        ));
-
-        //println!("Added region {:?}", self.screens.last().unwrap().region);
-        assert_eq!(self.get_screen_for(x, y).unwrap(), self.screens.len() - 1);
-        self.screens.len() - 1
+        self.histogram = histogram;
     }
-    // r is presumed to be in tile coordinates
-    #[allow(clippy::cast_possible_wrap)]
-    fn gather_screens(&mut self, r: Rect, db: &TileDB) -> (usize, usize, usize, usize) {
+    // Which chunk holds x,y (in tile coordinates), and where in it
+    #[inline]
+    #[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+    fn chunk_index(&self, x: i32, y: i32) -> ((i32, i32), usize) {
+        let size = CHUNK_SIZE as i32;
+        let (dx, dy) = (x - self.origin.0, y - self.origin.1);
         (
-            self.get_screen_for_or_add(r.x, r.y, db),
-            self.get_screen_for_or_add(r.x + r.w as i32 - 1, r.y, db),
-            self.get_screen_for_or_add(r.x, r.y + (r.h as i32) - 1, db),
-            self.get_screen_for_or_add(r.x + (r.w as i32) - 1, r.y + (r.h as i32) - 1, db),
+            (dx.div_euclid(size), dy.div_euclid(size)),
+            (dy.rem_euclid(size) * size + dx.rem_euclid(size)) as usize,
         )
     }
-    /// # Panics
-    /// Panics if the computed screen region doesn't contain the region pointed by x,y.
-    #[allow(clippy::cast_possible_wrap)]
+    fn cell_mut(&mut self, x: i32, y: i32) -> &mut TileChange {
+        let (chunk, i) = self.chunk_index(x, y);
+        let initial = self.initial;
+        &mut self
+            .chunks
+            .entry(chunk)
+            .or_insert_with(|| Box::new([initial; CHUNK_SIZE * CHUNK_SIZE]))[i]
+    }
+    #[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
     pub fn register_screen(&mut self, s: &Screen<TileGfxId>, db: &mut TileDB) {
-        let (ul, ur, bl, br) = self.gather_screens(s.region, db);
-        // Four loops: the ul part, the ur part, the bl part, the br part.
-        // ul is s.y..(s.y+s.h).min(ul.y+ul.h)
+        let initial_tile = db.get_initial_tile();
         let xmax = s.region.x + s.region.w as i32;
         let ymax = s.region.y + s.region.h as i32;
-        let ulr = self.screens[ul].region;
-        let lr_split = xmax.min(ulr.x + ulr.w as i32);
-        let ud_split = ymax.min(ulr.y + ulr.h as i32);
-        //let mut seen = Vec::with_capacity(s.region.w as usize * s.region.h as usize);
-        // TODO any way to avoid bounds checking within these loops?
-        // ul
-        let ul = &mut self.screens[ul];
-        for y in s.region.y..ud_split {
-            for x in s.region.x..lr_split {
-                extend_tile(ul, s, x, y, db);
-            }
-        }
-        // ur
-        let ur = &mut self.screens[ur];
-        for y in s.region.y..ud_split {
-            for x in lr_split..xmax {
-                extend_tile(ur, s, x, y, db);
-            }
-        }
-        // bl
-        let bl = &mut self.screens[bl];
-        for y in ud_split..ymax {
-            for x in s.region.x..lr_split {
-                extend_tile(bl, s, x, y, db);
-            }
-        }
-        // br
-        let br = &mut self.screens[br];
-        for y in ud_split..ymax {
-            for x in lr_split..xmax {
-                extend_tile(br, s, x, y, db);
+        for y in s.region.y..ymax {
+            // A run of the row at a time, so each chunk is looked up once
+            let mut x = s.region.x;
+            while x < xmax {
+                let (chunk, i) = self.chunk_index(x, y);
+                let run = (CHUNK_SIZE - i % CHUNK_SIZE).min((xmax - x) as usize);
+                let initial = self.initial;
+                let cells = self
+                    .chunks
+                    .entry(chunk)
+                    .or_insert_with(|| Box::new([initial; CHUNK_SIZE * CHUNK_SIZE]));
+                for (cell, sx) in cells[i..i + run].iter_mut().zip(x..) {
+                    let tile = s[(sx, y)];
+                    if tile != initial_tile {
+                        *cell = db.change_from_to(*cell, tile);
+                    }
+                }
+                x += run as i32;
             }
         }
-        // self.seen_changes.extend(seen.into_iter());
         self.top_left.0 = self.top_left.0.min(s.region.x);
         self.top_left.1 = self.top_left.1.min(s.region.y);
         self.bottom_right.0 = self.bottom_right.0.max(xmax);
         self.bottom_right.1 = self.bottom_right.1.max(ymax);
-        let r = self.region();
-        let sr = self.screens_region();
-        assert!(sr.contains_rect(&r), "{sr:?} does not contain {r:?}");
-    }
-    fn screens_region(&self) -> Rect {
-        let mut r = self.screens[0].region;
-        for s in &self.screens {
-            r = r.union(&s.region);
-        }
-        r
-    }
-}
-
-#[inline]
-fn extend_tile(rs: &mut RoomScreen, s: &Screen<TileGfxId>, x: i32, y: i32, db: &mut TileDB) {
-    assert!(s.region.contains(x, y), "{},{} : {:?}", x, y, s.region);
-    assert!(rs.region.contains(x, y), "{},{} : {:?}", x, y, rs.region);
-    if s[(x, y)] != db.get_initial_tile() {
-        let change = db.change_from_to(rs[(x, y)], s[(x, y)]);
-        rs.set(change, x, y);
     }
 }
 
@@ -231,51 +252,84 @@ mod tests {
 
     #[test]
     #[allow(clippy::cast_possible_wrap)]
-    fn test_get_screen_for() {
+    fn test_chunks() {
         let mut db = TileDB::new();
         let r0 = Rect::new(5, 5, 32, 32);
         let mut r = Room::new(0, &Screen::new(r0, db.get_initial_tile()), &mut db);
-        let _ = r.get_screen_for_or_add(5, 5, &db);
-        assert_eq!(r.screens[r.get_screen_for(5, 5).unwrap()].region, r0);
-        assert_eq!(r.screens.len(), 1);
-        let _ = r.get_screen_for_or_add(36, 36, &db);
-        assert_eq!(r.screens[r.get_screen_for(24, 24).unwrap()].region, r0);
-        assert_eq!(r.screens.len(), 1);
-        let _ = r.get_screen_for_or_add(37, 37, &db);
+        let init = db.get_initial_change();
+        let regions = |r: &Room| {
+            r.chunks()
+                .into_iter()
+                .map(|(rect, _)| rect)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(regions(&r), [r0]);
+        assert_eq!(r.get(36, 36), Some(init));
+        assert_eq!(r.get(37, 37), None);
+        *r.cell_mut(37, 37) = init;
         assert_eq!(
-            r.screens[r.get_screen_for(37, 37).unwrap()].region,
+            regions(&r)[1],
             Rect::new(r0.x + r0.w as i32, r0.y + r0.h as i32, r0.w, r0.h)
         );
-        assert_eq!(r.screens.len(), 2);
-        dbg!(r.screens.iter().map(|s| s.region).collect::<Vec<_>>());
-        let _ = r.get_screen_for_or_add(0, 0, &db);
-        assert_eq!(
-            r.screens[r.get_screen_for(0, 0).unwrap()].region,
-            Rect::new(r0.x - r0.w as i32, r0.y - r0.h as i32, r0.w, r0.h)
-        );
-        assert_eq!(r.screens.len(), 3);
-        let _ = r.get_screen_for_or_add(-10, -10, &db);
+        *r.cell_mut(0, 0) = init;
         assert_eq!(
-            r.screens[r.get_screen_for(-10, -10).unwrap()].region,
+            regions(&r)[0],
             Rect::new(r0.x - r0.w as i32, r0.y - r0.h as i32, r0.w, r0.h)
         );
-        assert_eq!(r.screens.len(), 3);
-        let _ = r.get_screen_for_or_add(-30, -30, &db);
+        assert_eq!(regions(&r).len(), 3);
+        *r.cell_mut(-10, -10) = init;
+        assert_eq!(regions(&r).len(), 3);
+        *r.cell_mut(-30, -30) = init;
         assert_eq!(
-            r.screens[r.get_screen_for(-30, -30).unwrap()].region,
+            regions(&r)[0],
             Rect::new(r0.x - r0.w as i32 * 2, r0.y - r0.h as i32 * 2, r0.w, r0.h)
         );
-        assert_eq!(r.screens.len(), 4);
+        assert_eq!(regions(&r).len(), 4);
+        // Moving the room moves its chunks along
+        r.reregister_at(0, 0);
+        assert_eq!(regions(&r)[1], Rect::new(-32, -32, 32, 32));
+        assert_eq!(r.get(-32, -32), Some(init));
+        assert_eq!(r.get(-33, 0), None);
     }
 
     #[test]
-    fn test_get_screen_for_2() {
+    #[allow(clippy::cast_possible_wrap)]
+    fn test_slices() {
+        use crate::tile::{TILE_NUM_PX, TileGfx};
         let mut db = TileDB::new();
-        let r0 = Rect::new(2, 29, 29, 27);
-        let mut r = Room::new(0, &Screen::new(r0, db.get_initial_tile()), &mut db);
-        let _ = r.get_screen_for_or_add(-56, 29, &db);
-        let _ = r.get_screen_for_or_add(-27, 29, &db);
-        assert_eq!(r.screens.len(), 3);
+        let t1 = db.get_tile(TileGfx([1; TILE_NUM_PX]));
+        // Taller and wider than a chunk, like a long shaft
+        let s = Screen::new(Rect::new(-3, 2, 40, 100), t1);
+        let r = Room::new(0, &s, &mut db);
+        assert_eq!(r.chunks().len(), 2 * 4);
+        let runs: Vec<_> = r
+            .row_slices(50, -10, 37)
+            .map(|(x, cells)| (x, cells.len()))
+            .collect();
+        assert_eq!(runs, [(-3, 32), (29, 8)]);
+        let change = r.get(0, 2).unwrap();
+        assert_eq!(db.get_change_by_id(change).unwrap().to, t1);
+        assert!(
+            r.row_slices(50, -10, 37)
+                .all(|(_, cells)| cells.iter().all(|&c| c == change))
+        );
+        let column: Vec<_> = r.column(10, 0, 200).map(|(y, _)| y).collect();
+        assert_eq!(column, (2..130).collect::<Vec<_>>());
+        assert!(r.column(10, 2, 102).all(|(_, c)| c == change));
+        // Rebuilding from the chunks gives back the same room
+        let screens: Vec<_> = r
+            .chunks()
+            .into_iter()
+            .map(|(region, cells)| Screen::from_tiles(region, cells.to_vec()))
+            .collect();
+        let r2 = Room::from_screens(
+            0,
+            r.top_left,
+            r.bottom_right,
+            &screens,
+            db.get_initial_change(),
+        );
+        assert_eq!(r2.chunks(), r.chunks());
     }
 
     #[test]
@@ -289,23 +343,19 @@ mod tests {
         let s = Screen::new(r0, t1);
         let mut r = Room::new(0, &s, &mut db);
 
-        assert_eq!(r.screens.len(), 1);
+        assert_eq!(r.chunks().len(), 1);
         for y in s.region.y..(s.region.y + s.region.h as i32) {
             for x in s.region.x..(s.region.x + s.region.w as i32) {
-                let atile = db
-                    .get_change_by_id(r.screens[r.get_screen_for(x, y).unwrap()][(x, y)])
-                    .unwrap();
+                let atile = db.get_change_by_id(r.get(x, y).unwrap()).unwrap();
                 assert_eq!(atile.from, t0);
                 assert_eq!(atile.to, t1);
             }
         }
         r.register_screen(&s, &mut db);
-        assert_eq!(r.screens.len(), 1);
+        assert_eq!(r.chunks().len(), 1);
         for y in s.region.y..(s.region.y + s.region.h as i32) {
             for x in s.region.x..(s.region.x + s.region.w as i32) {
-                let atile = db
-                    .get_change_by_id(r.screens[r.get_screen_for(x, y).unwrap()][(x, y)])
-                    .unwrap();
+                let atile = db.get_change_by_id(r.get(x, y).unwrap()).unwrap();
                 assert_eq!(atile.from, t0);
                 assert_eq!(atile.to, t1);
             }
@@ -318,12 +368,10 @@ mod tests {
         );
         r.register_screen(&s, &mut db);
 
-        assert_eq!(r.screens.len(), 4);
+        assert_eq!(r.chunks().len(), 4);
         for y in s.region.y..(s.region.y + s.region.h as i32) {
             for x in s.region.x..(s.region.x + s.region.w as i32) {
-                let atile = db
-                    .get_change_by_id(r.screens[r.get_screen_for(x, y).unwrap()][(x, y)])
-                    .unwrap();
+                let atile = db.get_change_by_id(r.get(x, y).unwrap()).unwrap();
                 if x < r0.x || y >= r0.y + (r0.h as i32) {
                     assert_eq!(atile.from, t0);
                     assert_eq!(atile.to, t2);
@@ -23,6 +23,26 @@ impl<T: Tile> Screen<T> {
             tiles: vec![tile; region.w as usize * region.h as usize].into_boxed_slice(),
         }
     }
+    /// Builds a screen from its tiles in row-major order.
+    /// # Panics
+    /// Panics if there isn't exactly one tile per cell of `region`
+    pub fn from_tiles(region: Rect, tiles: Vec<T>) -> Self {
+        assert_eq!(tiles.len(), region.w as usize * region.h as usize);
+        Self {
+            region,
+            tiles: tiles.into_boxed_slice(),
+        }
+    }
+    #[must_use]
+    pub fn tiles(&self) -> &[T] {
+        &self.tiles
+    }
+    /// Replaces every tile `t` with `f(t)`.
+    pub fn remap(&mut self, f: impl Fn(T) -> T) {
+        for t in &mut self.tiles {
+            *t = f(*t);
+        }
+    }
     #[inline]
     #[allow(clippy::cast_sign_loss, clippy::cast_possible_wrap)]
     pub fn get(&self, x: i32, y: i32) -> Option<T> {
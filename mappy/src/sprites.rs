@@ -1,6 +1,7 @@
 use crate::ringbuffer::RingBuffer;
 use crate::{Rect, Time};
-use retro_rs::{Buttons, Emulator};
+use crate::{FrameSource, MappyError};
+use retro_rs::Buttons;
 use std::collections::HashSet;
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
 pub struct SpriteData {
@@ -64,10 +65,15 @@ impl SpriteData {
 const SPRITE_SIZE: usize = 4;
 pub const SPRITE_COUNT: usize = 0x100 / SPRITE_SIZE;
 
+/// # Errors
+/// Returns an error if the PPU state or sprite layers can't be read
 /// # Panics
 /// Panics if the memory layout of the emulated system is not what's expected
 #[allow(clippy::similar_names, clippy::cast_possible_truncation)]
-pub fn get_sprites(emu: &Emulator, sprites: &mut [SpriteData]) {
+pub fn get_sprites<S: FrameSource + ?Sized>(
+    emu: &S,
+    sprites: &mut [SpriteData],
+) -> Result<(), MappyError> {
     const PIX_332_EMPTY: u8 = 191;
     #[allow(clippy::similar_names, clippy::cast_possible_truncation)]
     fn get_mask(x: u8, y: u8, h: u8, buf: &[u8], fbw: usize, fbh: usize) -> [u8; 16] {
@@ -142,9 +148,9 @@ pub fn get_sprites(emu: &Emulator, sprites: &mut [SpriteData]) {
     //     }
     //     colors
     // }
-    let buf = &emu.system_ram_ref()[0x0200..0x0200 + SPRITE_COUNT * SPRITE_SIZE];
+    let buf = &emu.oam()[0..SPRITE_COUNT * SPRITE_SIZE];
     // let ppuctrl = 0;
-    let ppuctrl = emu.memory_ref(0x2000).expect("Couldn't get PPU CTRL bit")[0];
+    let ppuctrl = emu.ppuctrl()?;
     let sprite_height: u8 = if ((ppuctrl & 0b0010_0000) >> 5) == 1 {
         16
     } else {
@@ -153,7 +159,7 @@ pub fn get_sprites(emu: &Emulator, sprites: &mut [SpriteData]) {
     let (fbw, fbh) = emu.framebuffer_size();
     let table_bit = (ppuctrl & 0b0000_1000) >> 3;
     
-    let [bg_sp, _, fg_sp] = unsafe { super::MappyState::get_layers(emu)};
+    let [bg_sp, _, fg_sp] = emu.layers()?;
     for (i, bs) in buf.chunks_exact(SPRITE_SIZE).enumerate() {
         let [y, pattern_id, attrs, x] = *bs else {
             unreachable!()
@@ -207,6 +213,7 @@ pub fn get_sprites(emu: &Emulator, sprites: &mut [SpriteData]) {
             }
         }
     }
+    Ok(())
 }
 
 // TODO return list of overlapping sprites
@@ -1,7 +1,8 @@
+use crate::Time;
 use crate::framebuffer::Framebuffer;
 use id_arena::{Arena, ArenaBehavior};
 use retro_rs::pixels;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 use std::hash::{Hash, Hasher};
 
@@ -74,11 +75,35 @@ impl TileGfx {
         }
     }
 
+    /// A descriptor of the tile's structure, for finding similar tiles:
+    /// tiles that look alike have hashes a small [`perceptual_distance`]
+    /// apart.  It's lossy, so it can't tell tiles apart; palette swaps in
+    /// particular may well share a hash.
+    ///
+    /// The low 64 bits say which pixels are brighter than the tile's mean,
+    /// the next 56 whether each pixel is brighter than its left neighbor, and
+    /// the top 8 are the mean brightness as a thermometer code.  So palette
+    /// swaps of one pattern usually differ only in the top bits.
     #[must_use]
     pub fn perceptual_hash(&self) -> u128 {
-        self.0
-            .iter()
-            .fold(0_u128, |x, &y| x.wrapping_add(u128::from(y)))
+        let lum = self.0.map(luminance);
+        let total: u32 = lum.iter().sum();
+        let mut hash = 0_u128;
+        for (i, &l) in lum.iter().enumerate() {
+            if l * (TILE_NUM_PX as u32) > total {
+                hash |= 1 << i;
+            }
+        }
+        for (row_i, row) in lum.chunks_exact(TILE_SIZE).enumerate() {
+            for (x, pair) in row.windows(2).enumerate() {
+                if pair[1] > pair[0] {
+                    hash |= 1 << (64 + row_i * (TILE_SIZE - 1) + x);
+                }
+            }
+        }
+        // 0..=8 bits set, so distances grow with the difference in brightness
+        let level = total * 8 / (LUMINANCE_MAX * TILE_NUM_PX as u32);
+        hash | (((1_u128 << level) - 1) << 120)
     }
     #[allow(clippy::new_without_default)]
     #[must_use]
@@ -86,6 +111,23 @@ impl TileGfx {
         Self([0; TILE_NUM_PX])
     }
 }
+const LUMINANCE_MAX: u32 = 7 * 90 + 7 * 177 + 3 * 77;
+/// Roughly perceived brightness of an rgb332 color, up to `LUMINANCE_MAX`.
+fn luminance(c: u8) -> u32 {
+    let (r, g, b) = (
+        u32::from(c >> 5),
+        u32::from((c >> 2) & 0b111),
+        u32::from(c & 0b11),
+    );
+    r * 90 + g * 177 + b * 77
+}
+
+/// How many bits differ between two [`TileGfx::perceptual_hash`]es.
+#[must_use]
+pub fn perceptual_distance(a: u128, b: u128) -> u32 {
+    (a ^ b).count_ones()
+}
+
 impl PartialEq for TileGfx {
     fn eq(&self, other: &Self) -> bool {
         for (a, b) in self.0.iter().zip(other.0.iter()) {
@@ -110,6 +152,27 @@ impl fmt::Debug for TileGfx {
     }
 }
 
+/// A tile's pixels in the colors the emulator actually drew them, rgb888
+/// row by row, where [`TileGfx`] only has them quantized to rgb332.
+pub type TileColors = [u8; TILE_NUM_PX * 3];
+
+/// Reads the tile at `x`,`y` of `rgb`, an rgb888 frame `w` pixels wide.
+/// # Panics
+/// Panics if the given framebuffer is too small
+#[must_use]
+pub fn read_colors(rgb: &[u8], w: usize, x: usize, y: usize) -> TileColors {
+    let mut colors = [0_u8; TILE_NUM_PX * 3];
+    assert!(w * (y + TILE_SIZE) * 3 <= rgb.len());
+    let rows = &rgb[w * y * 3..w * (y + TILE_SIZE) * 3];
+    for (row_t, row) in colors
+        .chunks_exact_mut(TILE_SIZE * 3)
+        .zip(rows.chunks_exact(w * 3))
+    {
+        row_t.copy_from_slice(&row[x * 3..(x + TILE_SIZE) * 3]);
+    }
+    colors
+}
+
 #[derive(Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash, Debug)]
 pub struct TileGfxId(u16);
 
@@ -118,9 +181,13 @@ impl TileGfxId {
     pub fn index(&self) -> u16 {
         self.0
     }
+    pub(crate) fn from_index(index: u16) -> Self {
+        Self(index)
+    }
 }
 impl Tile for TileGfxId {}
 
+#[derive(Clone)]
 struct TileGfxArenaBehavior();
 impl ArenaBehavior for TileGfxArenaBehavior {
     type Id = TileGfxId;
@@ -138,60 +205,59 @@ impl ArenaBehavior for TileGfxArenaBehavior {
         0
     }
 }
-// use std::collections::HashSet;
-// struct Chain<T: Ord + Eq + Copy> {
-//     elts: Vec<T>,
-//     // TODO this is actually bad, there must be a bug since metroid is showing many tiles with >1200 predecessors in the chain even though there are only 624 tiles and somehow 2453 changes (seems like too many changes)
-//     fwd: Vec<HashSet<usize>>,
-//     back: Vec<HashSet<usize>>,
-// }
-// impl<T> Chain<Id<T>> {
-//     fn new() -> Self {
-//         Self {
-//             elts: vec![],
-//             fwd: vec![],
-//             back: vec![],
-//         }
-//     }
-//     fn insert(&mut self, t: Id<T>) {
-//         if t.index() == self.elts.len() {
-//             self.elts.push(t);
-//             self.fwd.push(HashSet::new());
-//             self.back.push(HashSet::new());
-//         } else {
-//             assert!(t.index() < self.elts.len());
-//         }
-//     }
-//     fn chain(&mut self, tpre: Id<T>, tpost: Id<T>) {
-//         // TODO, if tpre is initial, should this just be ignored?
-//         let ipre = tpre.index();
-//         if ipre == 0 {
-//             return;
-//         }
-//         let ipost = tpost.index();
-//         self.fwd[ipre].insert(ipost);
-//         self.back[ipost].insert(ipre);
-//         // TODO resolve too much copying, can't prove that ipre isn't in to_add_back.
-//         // a dense matrix would improve things but then adding tiles stinks
-
-//         // tpost's descendants get backward links to all of tpre's ancestors
-//         for to_add_back in self.fwd[ipost].iter() {
-//             self.back[*to_add_back].insert(ipre);
-//             let cp: Vec<_> = self.back[ipre].iter().copied().collect();
-//             self.back[*to_add_back].extend(cp);
-//         }
-//         // tpre's ancestors get forward links to all of tpost's descendants
-//         for to_add_fwd in self.back[ipre].iter() {
-//             self.fwd[*to_add_fwd].insert(ipost);
-//             let cp: Vec<_> = self.fwd[ipost].iter().copied().collect();
-//             self.fwd[*to_add_fwd].extend(cp);
-//         }
-//     }
-
-//     fn goes_to(&self, tpre: Id<T>, tpost: Id<T>) -> bool {
-//         self.fwd[tpre.index()].contains(&(tpost.index()))
-//     }
-// }
+// Reachability stops recording new pairs of changes past this many
+const MAX_REACHABLE_PAIRS: usize = 1 << 20;
+
+/// Which changes can lead to which: `to[a]` holds `b` (and `from[b]` holds
+/// `a`) if a cell holding change `a` has been seen to go on, maybe through
+/// other changes, to change `b`.  Kept transitively closed as edges come
+/// in, so lookups are a set lookup, and sparse, since most changes lead to
+/// few others.  Past `MAX_REACHABLE_PAIRS` pairs, new ones are dropped, so
+/// in games with that many, `reaches` can miss some.
+#[derive(Clone, Default)]
+struct Reachability {
+    to: HashMap<usize, HashSet<usize>>,
+    from: HashMap<usize, HashSet<usize>>,
+    pairs: usize,
+}
+impl Reachability {
+    fn reaches(&self, a: usize, b: usize) -> bool {
+        self.to.get(&a).is_some_and(|to| to.contains(&b))
+    }
+    /// Everything `a` reaches, then everything that reaches `a`.
+    fn neighbors(&self, a: usize) -> impl Iterator<Item = usize> + '_ {
+        let to = self.to.get(&a).into_iter().flatten();
+        let from = self.from.get(&a).into_iter().flatten();
+        to.chain(from).copied()
+    }
+    /// Notes that `a` went directly to `b`.
+    fn add_edge(&mut self, a: usize, b: usize) {
+        if self.reaches(a, b) {
+            return;
+        }
+        // `a` and whatever reaches it now reach `b` and everything `b` reaches
+        let sources: Vec<usize> = std::iter::once(a)
+            .chain(self.from.get(&a).into_iter().flatten().copied())
+            .collect();
+        let targets: Vec<usize> = std::iter::once(b)
+            .chain(self.to.get(&b).into_iter().flatten().copied())
+            .collect();
+        for &s in &sources {
+            for &t in &targets {
+                if self.pairs >= MAX_REACHABLE_PAIRS {
+                    return;
+                }
+                if self.to.entry(s).or_default().insert(t) {
+                    self.from.entry(t).or_default().insert(s);
+                    self.pairs += 1;
+                }
+            }
+        }
+    }
+    fn pairs(&self) -> usize {
+        self.pairs
+    }
+}
 
 #[derive(Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash, Debug)]
 pub struct TileChange(u32);
@@ -200,7 +266,11 @@ impl TileChange {
     pub fn index(&self) -> u32 {
         self.0
     }
+    pub(crate) fn from_index(index: u32) -> Self {
+        Self(index)
+    }
 }
+#[derive(Clone)]
 struct TileChangeArenaBehavior();
 impl ArenaBehavior for TileChangeArenaBehavior {
     type Id = TileChange;
@@ -227,10 +297,106 @@ pub struct TileChangeData {
     pub to: TileGfxId,
     successors: Vec<(TileGfxId, usize)>,
     count: usize,
+    // The frame this change last happened on, and how many frames apart
+    // (up to MAX_ANIMATION_PERIOD) it has happened, with how often
+    last_seen: Option<usize>,
+    intervals: Vec<(u32, usize)>,
+}
+
+impl TileChangeData {
+    pub(crate) fn new(
+        from: TileGfxId,
+        to: TileGfxId,
+        successors: Vec<(TileGfxId, usize)>,
+        count: usize,
+        intervals: Vec<(u32, usize)>,
+    ) -> Self {
+        Self {
+            from,
+            to,
+            successors,
+            count,
+            last_seen: None,
+            intervals,
+        }
+    }
+    /// The tiles this change has been seen to change into next, with how often.
+    #[must_use]
+    pub fn successors(&self) -> &[(TileGfxId, usize)] {
+        &self.successors
+    }
+    /// How many room cells currently hold this change.
+    #[must_use]
+    pub fn count(&self) -> usize {
+        self.count
+    }
+    /// How many frames apart this change has happened (anywhere), with how
+    /// often.
+    #[must_use]
+    pub fn intervals(&self) -> &[(u32, usize)] {
+        &self.intervals
+    }
+    /// The usual number of frames between repeats of this change, if it has
+    /// repeated at that interval at least `MIN_ANIMATION_REPEATS` times.
+    #[must_use]
+    pub fn period(&self) -> Option<u32> {
+        self.intervals
+            .iter()
+            .filter(|(_, n)| *n >= MIN_ANIMATION_REPEATS)
+            .max_by_key(|&&(dt, n)| (n, std::cmp::Reverse(dt)))
+            .map(|&(dt, _)| dt)
+    }
+    /// Notes that this change happened at frame `now`, returning whether
+    /// that changed its [`TileChangeData::period`].
+    fn observe(&mut self, now: usize) -> bool {
+        if self.last_seen == Some(now) {
+            return false;
+        }
+        let period = self.period();
+        if let Some(dt) = self
+            .last_seen
+            .and_then(|last| now.checked_sub(last))
+            .and_then(|dt| u32::try_from(dt).ok())
+            .filter(|dt| *dt <= MAX_ANIMATION_PERIOD)
+        {
+            match self.intervals.iter_mut().find(|(t, _)| *t == dt) {
+                Some((_, n)) => *n += 1,
+                None => self.intervals.push((dt, 1)),
+            }
+        }
+        self.last_seen = Some(now);
+        self.period() != period
+    }
+}
+
+// Changes further apart than this aren't part of an animation
+const MAX_ANIMATION_PERIOD: u32 = 256;
+const MIN_ANIMATION_REPEATS: usize = 3;
+
+/// Tiles that cells cycle through on their own, like water, conveyors or
+/// flashing blocks: a strongly connected group of changes that each repeat
+/// about every `period` frames.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Animation {
+    // In id order; the first stands in for the others when drawing
+    pub frames: Vec<TileGfxId>,
+    pub period: u32,
+}
+
+/// Looser matches [`TileDB::change_cost`] can allow beyond the ones mappy
+/// has always used; all off by default.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct ChangeCosts {
+    // Frames of one animation cost nothing against each other
+    pub animations: bool,
+    // Changes on one path of changes, like a door seen before and after it
+    // opened, cost 0.50 instead of 1.00
+    pub reachable: bool,
 }
 
 type GfxArena = Arena<TileGfx, TileGfxArenaBehavior>;
 type ChangeArena = Arena<TileChangeData, TileChangeArenaBehavior>;
+#[derive(Clone)]
 pub struct TileDB {
     gfx_arena: GfxArena,
     change_arena: ChangeArena,
@@ -241,7 +407,22 @@ pub struct TileDB {
     gfx: HashMap<TileGfx, TileGfxId>,
 
     changes: HashMap<(TileGfxId, TileGfxId), TileChange>,
-    // change_closure: Chain<TileChange>,
+    reachable: Reachability,
+
+    // True colors of the tiles seen in a full color frame
+    colors: HashMap<TileGfxId, TileColors>,
+    // The perceptual hash of every tile, by id, for similarity queries (which
+    // scan all of them; there's no index)
+    hashes: Vec<u128>,
+
+    // The current frame, for timing changes
+    now: usize,
+    animations: Vec<Animation>,
+    animation_of: HashMap<TileGfxId, usize>,
+    // Whether any change's period has changed since animations were last
+    // detected
+    periods_changed: bool,
+    costs: ChangeCosts,
 }
 
 impl TileDB {
@@ -252,18 +433,19 @@ impl TileDB {
         let mut change_arena = Arena::new();
         let init_tile = TileGfx::new();
         let initial = gfx_arena.alloc(init_tile);
+        let hashes = vec![init_tile.perceptual_hash()];
         let gfx = HashMap::new();
         let initial_change_data = TileChangeData {
             from: initial,
             to: initial,
             successors: vec![],
             count: 0,
+            last_seen: None,
+            intervals: vec![],
         };
         let initial_change = change_arena.alloc(initial_change_data);
         let mut changes = HashMap::new();
         changes.insert((initial, initial), initial_change);
-        // let mut change_closure = Chain::new();
-        // change_closure.insert(initial_change);
         TileDB {
             gfx_arena,
             change_arena,
@@ -271,8 +453,61 @@ impl TileDB {
             initial_change,
             gfx,
             changes,
-            // change_closure,
+            reachable: Reachability::default(),
+            colors: HashMap::new(),
+            hashes,
+            now: 0,
+            animations: vec![],
+            animation_of: HashMap::new(),
+            periods_changed: false,
+            costs: ChangeCosts::default(),
+        }
+    }
+    /// Rebuilds a database from its tiles and changes in id order.  The
+    /// first of each must be the blank initial tile and its `initial ->
+    /// initial` change, as in [`TileDB::new`].  `colors` gives the true
+    /// colors of the tiles which have them.
+    pub(crate) fn from_parts(
+        gfx: Vec<TileGfx>,
+        changes: Vec<TileChangeData>,
+        colors: Vec<(TileGfxId, TileColors)>,
+    ) -> Self {
+        let mut gfx_arena = Arena::new();
+        let mut gfx_ids = HashMap::with_capacity(gfx.len());
+        let hashes = gfx.iter().map(TileGfx::perceptual_hash).collect();
+        for (i, tg) in gfx.into_iter().enumerate() {
+            let id = gfx_arena.alloc(tg);
+            // new() never puts the initial tile in the lookup table
+            if i > 0 {
+                gfx_ids.insert(tg, id);
+            }
+        }
+        let mut change_arena = Arena::new();
+        let mut change_ids = HashMap::with_capacity(changes.len());
+        for tc in changes {
+            let key = (tc.from, tc.to);
+            let id = change_arena.alloc(tc);
+            change_ids.insert(key, id);
         }
+        let mut db = TileDB {
+            gfx_arena,
+            change_arena,
+            initial: TileGfxId(0),
+            initial_change: TileChange(0),
+            gfx: gfx_ids,
+            changes: change_ids,
+            reachable: Reachability::default(),
+            colors: colors.into_iter().collect(),
+            hashes,
+            now: 0,
+            animations: vec![],
+            animation_of: HashMap::new(),
+            periods_changed: true,
+            costs: ChangeCosts::default(),
+        };
+        db.rebuild_reachability();
+        db.detect_animations();
+        db
     }
     #[must_use]
     pub fn get_initial_change(&self) -> TileChange {
@@ -284,10 +519,197 @@ impl TileDB {
     }
     #[must_use]
     pub fn get_tile(&mut self, tg: TileGfx) -> TileGfxId {
-        let arena = &mut self.gfx_arena;
-        let id = *self.gfx.entry(tg).or_insert_with(|| arena.alloc(tg));
+        let (arena, hashes) = (&mut self.gfx_arena, &mut self.hashes);
+        let id = *self.gfx.entry(tg).or_insert_with(|| {
+            hashes.push(tg.perceptual_hash());
+            arena.alloc(tg)
+        });
         id
     }
+    /// Whether a cell holding change `a` has been seen to go on, directly or
+    /// through other changes, to change `b`.
+    #[must_use]
+    pub fn reaches(&self, a: TileChange, b: TileChange) -> bool {
+        self.reachable.reaches(a.0 as usize, b.0 as usize)
+    }
+    /// The changes `tc` [reaches](TileDB::reaches) and the ones that reach
+    /// it.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn reachable_neighbors(&self, tc: TileChange) -> impl Iterator<Item = TileChange> + '_ {
+        self.reachable
+            .neighbors(tc.0 as usize)
+            .map(|i| TileChange(i as u32))
+    }
+    /// Recomputes which changes lead to which from their successors.
+    fn rebuild_reachability(&mut self) {
+        self.reachable = Reachability::default();
+        for (id, tc) in self.change_arena.iter() {
+            if id == self.initial_change {
+                continue;
+            }
+            for (succ, _) in &tc.successors {
+                if let Some(&next) = self.changes.get(&(tc.to, *succ)) {
+                    self.reachable.add_edge(id.0 as usize, next.0 as usize);
+                }
+            }
+        }
+    }
+    /// Sets the frame that changes made from now on happen at.
+    pub fn set_time(&mut self, now: Time) {
+        self.now = *now;
+    }
+    /// Finds the animations among the changes seen so far, replacing any
+    /// found before.  Tiles animate together if changes among them form a
+    /// cycle and all repeat at about the same period.  Since only the
+    /// changes' periods matter, this does nothing unless one has changed
+    /// since the last time.
+    pub fn detect_animations(&mut self) {
+        if !std::mem::take(&mut self.periods_changed) {
+            return;
+        }
+        // Regularly repeating changes between real tiles
+        let mut edges: BTreeMap<TileGfxId, Vec<(TileGfxId, u32)>> = BTreeMap::new();
+        for (_id, tc) in self.change_arena.iter() {
+            if tc.from == self.initial {
+                continue;
+            }
+            if let Some(period) = tc.period() {
+                edges.entry(tc.from).or_default().push((tc.to, period));
+            }
+        }
+        self.animations.clear();
+        self.animation_of.clear();
+        for frames in strongly_connected(&edges) {
+            if frames.len() < 2 {
+                continue;
+            }
+            let mut periods: Vec<u32> = frames
+                .iter()
+                .flat_map(|from| edges.get(from).into_iter().flatten())
+                .filter(|(to, _)| frames.binary_search(to).is_ok())
+                .map(|&(_, period)| period)
+                .collect();
+            periods.sort_unstable();
+            let (lo, hi) = (periods[0], periods[periods.len() - 1]);
+            // Allow for lag frames
+            if hi - lo > hi / 8 + 1 {
+                continue;
+            }
+            let idx = self.animations.len();
+            for &f in &frames {
+                self.animation_of.insert(f, idx);
+            }
+            self.animations.push(Animation {
+                period: periods[periods.len() / 2],
+                frames,
+            });
+        }
+    }
+    /// The animations found by the last [`TileDB::detect_animations`].
+    #[must_use]
+    pub fn animations(&self) -> &[Animation] {
+        &self.animations
+    }
+    #[must_use]
+    pub fn animation_of(&self, id: TileGfxId) -> Option<&Animation> {
+        self.animation_of.get(&id).map(|&a| &self.animations[a])
+    }
+    #[must_use]
+    pub fn same_animation(&self, a: TileGfxId, b: TileGfxId) -> bool {
+        match (self.animation_of.get(&a), self.animation_of.get(&b)) {
+            (Some(aa), Some(ab)) => aa == ab,
+            _ => false,
+        }
+    }
+    #[must_use]
+    pub fn change_costs(&self) -> ChangeCosts {
+        self.costs
+    }
+    pub fn set_change_costs(&mut self, costs: ChangeCosts) {
+        self.costs = costs;
+    }
+    /// The tile to draw in place of `id`: the first frame of its animation,
+    /// or `id` itself.
+    #[must_use]
+    pub fn canonical(&self, id: TileGfxId) -> TileGfxId {
+        self.animation_of(id).map_or(id, |a| a.frames[0])
+    }
+    /// The `k` tiles (never the initial tile) whose perceptual hashes are
+    /// closest to `tile`'s, nearest first, with their distances.
+    #[must_use]
+    pub fn nearest(&self, tile: &TileGfx, k: usize) -> Vec<(TileGfxId, u32)> {
+        let mut found = self.similar_within(tile, u128::BITS);
+        found.truncate(k);
+        found
+    }
+    /// Every tile (but the initial tile) whose perceptual hash is at most
+    /// `dist` bits from `tile`'s, nearest first, with their distances.  This
+    /// checks every tile's hash, which is quick for the few thousand tiles a
+    /// game has.
+    #[must_use]
+    pub fn similar_within(&self, tile: &TileGfx, dist: u32) -> Vec<(TileGfxId, u32)> {
+        let hash = tile.perceptual_hash();
+        let mut found: Vec<_> = self
+            .hashes
+            .iter()
+            .enumerate()
+            // The initial tile is always first
+            .skip(1)
+            .filter_map(|(i, &h)| {
+                let d = perceptual_distance(hash, h);
+                (d <= dist).then(|| (TileGfxArenaBehavior::new_id(0, i), d))
+            })
+            .collect();
+        found.sort_unstable_by_key(|&(id, d)| (d, id));
+        found
+    }
+    /// Remembers `colors` as the true colors of `id`, unless it already has
+    /// some.
+    pub fn set_colors(&mut self, id: TileGfxId, colors: TileColors) {
+        if id != self.initial {
+            self.colors.entry(id).or_insert(colors);
+        }
+    }
+    /// The true colors of `id`, if it's been seen in a full color frame.
+    #[must_use]
+    pub fn colors(&self, id: TileGfxId) -> Option<&TileColors> {
+        self.colors.get(&id)
+    }
+    /// The tiles with true colors, in id order.
+    #[must_use]
+    pub fn all_colors(&self) -> Vec<(TileGfxId, &TileColors)> {
+        let mut colors: Vec<_> = self.colors.iter().map(|(&id, rgb)| (id, rgb)).collect();
+        colors.sort_unstable_by_key(|(id, _)| *id);
+        colors
+    }
+    /// Writes tile `id` into `buf`, an rgb888 image `buf_w` pixels wide, with
+    /// its top left corner at `x`,`y`.  Uses the tile's true colors if it has
+    /// them and its rgb332 pixels otherwise.
+    /// # Panics
+    /// Panics if the given buffer is too small or `id` isn't in the database
+    pub fn write_rgb888_at(&self, id: TileGfxId, x: usize, y: usize, buf: &mut [u8], buf_w: usize) {
+        let Some(colors) = self.colors.get(&id) else {
+            self.get_tile_by_id(id)
+                .unwrap()
+                .write_rgb888_at(x, y, buf, buf_w);
+            return;
+        };
+        assert!((x + TILE_SIZE) <= buf_w);
+        for (row_t, row_b) in colors
+            .chunks_exact(TILE_SIZE * 3)
+            .zip(buf[(y * 3 * buf_w)..((y + TILE_SIZE) * buf_w * 3)].chunks_mut(buf_w * 3))
+        {
+            row_b[(x * 3)..(x * 3 + TILE_SIZE * 3)].copy_from_slice(row_t);
+        }
+    }
+    /// Writes tile `id` into `buf`, which must be exactly one tile of rgb888.
+    /// # Panics
+    /// Panics if the given buffer is the wrong size or `id` isn't in the
+    /// database
+    pub fn write_rgb888(&self, id: TileGfxId, buf: &mut [u8]) {
+        assert!(buf.len() == TILE_NUM_PX * 3);
+        self.write_rgb888_at(id, 0, 0, buf, TILE_SIZE);
+    }
     #[must_use]
     pub fn contains(&self, tg: &TileGfx) -> bool {
         self.gfx.contains_key(tg)
@@ -323,6 +745,17 @@ impl TileDB {
     pub fn gfx_iter(&self) -> impl Iterator<Item = &TileGfx> {
         self.gfx_arena.iter().map(|(_id, t)| t)
     }
+    /// Every tile id, the initial tile's first.
+    pub fn gfx_ids(&self) -> impl Iterator<Item = TileGfxId> + '_ {
+        self.gfx_arena.iter().map(|(id, _t)| id)
+    }
+    pub fn change_iter(&self) -> impl Iterator<Item = &TileChangeData> {
+        self.change_arena.iter().map(|(_id, t)| t)
+    }
+    #[must_use]
+    pub fn change_count(&self) -> usize {
+        self.change_arena.len()
+    }
     #[must_use]
     pub fn gfx_count(&self) -> usize {
         self.gfx.len()
@@ -333,12 +766,19 @@ impl TileDB {
     pub fn change_cost(&self, tc1: TileChange, tc2: TileChange) -> f32 {
         let tc1_c = self.change_arena.get(tc1).unwrap();
         let tc2_c = self.change_arena.get(tc2).unwrap();
-        if tc1 == tc2 || tc1 == self.initial_change || tc2 == self.initial_change {
+        if tc1 == tc2
+            || tc1 == self.initial_change
+            || tc2 == self.initial_change
+            // Frames of one animation are all the same animated tile
+            || (self.costs.animations && self.same_animation(tc1_c.to, tc2_c.to))
+        {
             0.00
         } else if tc1_c.to == tc2_c.from || tc1_c.from == tc2_c.to {
             0.25
         } else if tc1_c.to == tc2_c.to {
             0.10
+        } else if self.costs.reachable && (self.reaches(tc1, tc2) || self.reaches(tc2, tc1)) {
+            0.50
         } else {
             1.00
         }
@@ -364,12 +804,14 @@ impl TileDB {
                     to: gfx,
                     successors: vec![],
                     count: 0,
+                    last_seen: None,
+                    intervals: vec![],
                 })
             });
-            // self.change_closure.insert(tc2);
-
-            // self.change_closure.chain(tc, tc2);
             let init = self.get_initial_change();
+            if tc != init {
+                self.reachable.add_edge(tc.0 as usize, tc2.0 as usize);
+            }
             let old_change = self.change_arena.get_mut(tc).unwrap();
             if tc != init {
                 old_change.count -= 1;
@@ -386,19 +828,85 @@ impl TileDB {
                 old_change.successors.push((gfx, 1));
             }
 
-            self.change_arena.get_mut(tc2).unwrap().count += 1;
+            let now = self.now;
+            let new_change = self.change_arena.get_mut(tc2).unwrap();
+            new_change.count += 1;
+            if new_change.observe(now) {
+                self.periods_changed = true;
+            }
             tc2
         }
     }
+    /// Adds every tile and change of `other` to this database, matching
+    /// tiles by their graphics (and keeping true colors this database doesn't
+    /// have yet), and sums the counts of changes both share.
+    /// Returns tables mapping `other`'s tile and change indices to ids here.
+    /// # Panics
+    /// Panics if a change of `other` refers to a tile it doesn't contain
+    pub fn absorb(&mut self, other: &TileDB) -> (Vec<TileGfxId>, Vec<TileChange>) {
+        let gfx_map: Vec<_> = other
+            .gfx_arena
+            .iter()
+            .map(|(id, tg)| {
+                if id == other.initial {
+                    self.initial
+                } else {
+                    self.get_tile(*tg)
+                }
+            })
+            .collect();
+        for (&id, &colors) in &other.colors {
+            self.set_colors(gfx_map[TileGfxArenaBehavior::index(id)], colors);
+        }
+        let change_map = other
+            .change_arena
+            .iter()
+            .map(|(id, tc)| {
+                if id == other.initial_change {
+                    return self.initial_change;
+                }
+                let from = gfx_map[TileGfxArenaBehavior::index(tc.from)];
+                let to = gfx_map[TileGfxArenaBehavior::index(tc.to)];
+                let arena = &mut self.change_arena;
+                let id = *self.changes.entry((from, to)).or_insert_with(|| {
+                    arena.alloc(TileChangeData {
+                        from,
+                        to,
+                        successors: vec![],
+                        count: 0,
+                        last_seen: None,
+                        intervals: vec![],
+                    })
+                });
+                let data = self.change_arena.get_mut(id).unwrap();
+                data.count += tc.count;
+                for &(dt, n) in &tc.intervals {
+                    match data.intervals.iter_mut().find(|(t, _)| *t == dt) {
+                        Some((_, count)) => *count += n,
+                        None => data.intervals.push((dt, n)),
+                    }
+                }
+                for &(succ, n) in &tc.successors {
+                    let succ = gfx_map[TileGfxArenaBehavior::index(succ)];
+                    match data.successors.iter_mut().find(|(to, _)| *to == succ) {
+                        Some((_, count)) => *count += n,
+                        None => data.successors.push((succ, n)),
+                    }
+                }
+                id
+            })
+            .collect();
+        self.rebuild_reachability();
+        self.periods_changed = true;
+        self.detect_animations();
+        (gfx_map, change_map)
+    }
     #[must_use]
     pub fn tile_stats(&self) -> TileDBStats {
         TileDBStats {
             gfx: self.gfx.len(),
             changes: self.changes.len(),
-            // closure_sizes_fwd:
-            // self.change_closure.fwd.iter().map(|c| c.len()).collect(),
-            // closure_sizes_back:
-            // self.change_closure.back.iter().map(|c| c.len()).collect(),
+            reachable_pairs: self.reachable.pairs(),
         }
     }
 }
@@ -407,6 +915,228 @@ impl TileDB {
 pub struct TileDBStats {
     pub gfx: usize,
     pub changes: usize,
-    // pub closure_sizes_fwd:Vec<usize>,
-    // pub closure_sizes_back:Vec<usize>,
+    // Pairs of changes where the first can lead to the second
+    pub reachable_pairs: usize,
+}
+
+/// The strongly connected components of a graph given by its edges, each
+/// in id order.
+fn strongly_connected<T>(edges: &BTreeMap<TileGfxId, Vec<(TileGfxId, T)>>) -> Vec<Vec<TileGfxId>> {
+    // Order tiles by when a depth first search finishes with them
+    let mut visited = HashSet::new();
+    let mut order = vec![];
+    for &start in edges.keys() {
+        if !visited.insert(start) {
+            continue;
+        }
+        let mut stack = vec![(start, 0)];
+        while let Some((node, i)) = stack.pop() {
+            match edges.get(&node).and_then(|out| out.get(i)) {
+                Some(&(next, _)) => {
+                    stack.push((node, i + 1));
+                    if visited.insert(next) {
+                        stack.push((next, 0));
+                    }
+                }
+                None => order.push(node),
+            }
+        }
+    }
+    // Then search the reversed graph, last finished first
+    let mut reversed: BTreeMap<TileGfxId, Vec<TileGfxId>> = BTreeMap::new();
+    for (&from, out) in edges {
+        for &(to, _) in out {
+            reversed.entry(to).or_default().push(from);
+        }
+    }
+    let mut assigned = HashSet::new();
+    let mut components = vec![];
+    for &start in order.iter().rev() {
+        if !assigned.insert(start) {
+            continue;
+        }
+        let mut component = vec![start];
+        let mut stack = vec![start];
+        while let Some(node) = stack.pop() {
+            for &prev in reversed.get(&node).into_iter().flatten() {
+                if assigned.insert(prev) {
+                    component.push(prev);
+                    stack.push(prev);
+                }
+            }
+        }
+        component.sort_unstable();
+        components.push(component);
+    }
+    components
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Left half `a`, right half `b`
+    fn halves(a: u8, b: u8) -> TileGfx {
+        TileGfx(std::array::from_fn(
+            |i| if i % TILE_SIZE < 4 { a } else { b },
+        ))
+    }
+
+    #[test]
+    fn test_perceptual_hash() {
+        // Same byte sums, different pictures
+        let (left, right) = (halves(0xFF, 0x00), halves(0x00, 0xFF));
+        assert!(perceptual_distance(left.perceptual_hash(), right.perceptual_hash()) > 32);
+        // A palette swap keeps the structure
+        let swapped = halves(0xFC, 0x00);
+        assert!(perceptual_distance(left.perceptual_hash(), swapped.perceptual_hash()) <= 1);
+        // Flat tiles differ only in brightness
+        let (dark, light) = (TileGfx([0x00; TILE_NUM_PX]), TileGfx([0xFF; TILE_NUM_PX]));
+        assert_eq!(
+            perceptual_distance(dark.perceptual_hash(), light.perceptual_hash()),
+            8
+        );
+    }
+
+    #[test]
+    fn test_animations() {
+        use crate::Rect;
+        use crate::room::Room;
+        use crate::screen::Screen;
+        let mut db = TileDB::new();
+        let water: Vec<_> = (1..=3)
+            .map(|c| db.get_tile(TileGfx([c; TILE_NUM_PX])))
+            .collect();
+        let (door, open) = (
+            db.get_tile(TileGfx([9; TILE_NUM_PX])),
+            db.get_tile(TileGfx([10; TILE_NUM_PX])),
+        );
+        let (dark, lit) = (
+            db.get_tile(TileGfx([11; TILE_NUM_PX])),
+            db.get_tile(TileGfx([12; TILE_NUM_PX])),
+        );
+        let region = Rect::new(0, 0, 2, 2);
+        let mut room = Room::new(0, &Screen::new(region, door), &mut db);
+        let mut earlier = None;
+        for t in 0..120 {
+            db.set_time(Time(t));
+            // Water changes every 8 frames, the door opens once, and a light
+            // flickers on and off at irregular times
+            let mut screen = Screen::new(region, water[(t / 8) % 3]);
+            screen.set(if t > 30 { open } else { door }, 1, 1);
+            let flicker = (0..=t).filter(|i| i * i == t).count() % 2 == 1;
+            screen.set(if flicker { lit } else { dark }, 0, 1);
+            room.register_screen(&screen, &mut db);
+            if t == 100 {
+                earlier = room.get(0, 0);
+            }
+        }
+        db.detect_animations();
+        // Nothing more to find until some change's period changes
+        assert!(!db.periods_changed);
+        assert_eq!(
+            db.animations(),
+            [Animation {
+                frames: water.clone(),
+                period: 24
+            }]
+        );
+        assert_eq!(db.canonical(water[2]), water[0]);
+        assert_eq!(db.canonical(open), open);
+        assert!(db.same_animation(water[1], water[2]));
+        // C -> A and B -> C are merely successive...
+        let (earlier, now) = (earlier.unwrap(), room.get(0, 0).unwrap());
+        assert!((db.change_cost(earlier, now) - 0.25).abs() < f32::EPSILON);
+        // unless frames of an animation count as one tile
+        db.set_change_costs(ChangeCosts {
+            animations: true,
+            reachable: false,
+        });
+        assert!(db.change_cost(earlier, now).abs() < f32::EPSILON);
+        assert!((db.change_cost(now, room.get(1, 1).unwrap()) - 1.0).abs() < f32::EPSILON);
+        let restored = TileDB::from_parts(
+            db.gfx_iter().copied().collect(),
+            db.change_iter().cloned().collect(),
+            vec![],
+        );
+        assert_eq!(restored.animations(), db.animations());
+    }
+
+    #[test]
+    fn test_reachability() {
+        let mut db = TileDB::new();
+        let [closed, opening, open, wall] =
+            [1, 2, 3, 4].map(|c| db.get_tile(TileGfx([c; TILE_NUM_PX])));
+        let init = db.get_initial_change();
+        let seen_closed = db.change_from_to(init, closed);
+        let seen_opening = db.change_from_to(seen_closed, opening);
+        let seen_open = db.change_from_to(seen_opening, open);
+        let seen_wall = db.change_from_to(init, wall);
+        assert!(db.reaches(seen_closed, seen_opening));
+        assert!(db.reaches(seen_closed, seen_open));
+        assert!(!db.reaches(seen_open, seen_closed));
+        assert!(!db.reaches(seen_wall, seen_open));
+        assert!(!db.reaches(init, seen_closed));
+        let mut neighbors: Vec<_> = db.reachable_neighbors(seen_opening).collect();
+        neighbors.sort_unstable();
+        assert_eq!(neighbors, [seen_closed, seen_open]);
+        // Neither the same tile nor one step apart, but one path
+        assert!((db.change_cost(seen_closed, seen_open) - 1.0).abs() < f32::EPSILON);
+        db.set_change_costs(ChangeCosts {
+            animations: false,
+            reachable: true,
+        });
+        assert!((db.change_cost(seen_closed, seen_open) - 0.50).abs() < f32::EPSILON);
+        assert!((db.change_cost(seen_wall, seen_open) - 1.0).abs() < f32::EPSILON);
+        // Closing and opening the door again makes a loop
+        let closed_again = db.change_from_to(seen_open, closed);
+        assert!(!db.reaches(seen_open, seen_opening));
+        let opening_again = db.change_from_to(closed_again, opening);
+        assert_eq!(opening_again, seen_opening);
+        assert!(db.reaches(seen_open, seen_opening));
+        assert!(db.reaches(closed_again, closed_again));
+        let restored = TileDB::from_parts(
+            db.gfx_iter().copied().collect(),
+            db.change_iter().cloned().collect(),
+            vec![],
+        );
+        assert_eq!(
+            restored.tile_stats().reachable_pairs,
+            db.tile_stats().reachable_pairs
+        );
+        assert!(restored.reaches(seen_closed, closed_again));
+        assert!(!restored.reaches(seen_wall, closed_again));
+    }
+
+    #[test]
+    fn test_nearest() {
+        let mut db = TileDB::new();
+        let left = db.get_tile(halves(0xFF, 0x00));
+        let right = db.get_tile(halves(0x00, 0xFF));
+        let light = db.get_tile(TileGfx([0xFF; TILE_NUM_PX]));
+        let mut altered = halves(0xFF, 0x00);
+        altered.0[TILE_SIZE + 4] = 0xFF;
+        let near = db.nearest(&altered, 2);
+        assert_eq!(near.len(), 2);
+        assert_eq!(near[0].0, left);
+        assert!(near[0].1 > 0 && near[0].1 < near[1].1);
+        assert_eq!(db.nearest(&halves(0xFF, 0x00), 1), [(left, 0)]);
+        assert_eq!(db.nearest(&altered, 10).len(), 3);
+        let similar = db.similar_within(&altered, near[0].1);
+        assert_eq!(similar, [near[0]]);
+        assert!(
+            !db.similar_within(&altered, u128::BITS)
+                .iter()
+                .any(|&(id, _)| id == db.get_initial_tile())
+        );
+        // Saved and restored databases answer the same
+        let restored = TileDB::from_parts(
+            db.gfx_iter().copied().collect(),
+            db.change_iter().cloned().collect(),
+            vec![],
+        );
+        assert_eq!(restored.nearest(&altered, 3), db.nearest(&altered, 3));
+        assert!(db.nearest(&altered, 3).iter().any(|&(id, _)| id == right));
+        assert!(db.nearest(&altered, 3).iter().any(|&(id, _)| id == light));
+    }
 }
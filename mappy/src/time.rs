@@ -37,7 +37,11 @@ impl<T: TimerID> Timers<T> {
     pub(crate) fn new() -> Self {
         let (tx, t_rx) = bounded(1);
         let (t_tx, rx) = bounded(1);
-        rayon::spawn(move || {
+        // Not on rayon: this loop lives as long as the Timers, and parking it
+        // on a worker of a small pool starves whatever else runs there (two
+        // sessions in one process, like a recording and its replay, can
+        // deadlock a one-core machine)
+        std::thread::spawn(move || {
             let mut rec = BTreeMap::new();
             for tm in &t_rx {
                 match tm {
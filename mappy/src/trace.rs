@@ -0,0 +1,558 @@
+use crate::mappy::scrolling::{ScrollChange, ScrollChangeReason};
+use crate::profile::RomHash;
+use crate::{FrameSource, MappyError, MappyState};
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use retro_rs::Buttons;
+use std::io::{self, Read, Write};
+
+const TRACE_MAGIC: &[u8; 8] = b"MAPPYTRC";
+pub const TRACE_VERSION: u16 = 1;
+const OAM_SIZE: usize = 256;
+
+const FLAG_RESET: u8 = 0b001;
+const FLAG_PROBED: u8 = 0b010;
+const FLAG_RESPONDED: u8 = 0b100;
+
+/// Everything `process_screen` reads from its source on one frame.
+///
+/// The framebuffer and layer planes are stored as XOR deltas against the
+/// previous frame, so the (zlib-compressed) stream is mostly runs of zeros.
+#[derive(Clone)]
+struct TraceFrame {
+    input: [Buttons; 2],
+    reset: bool,
+    probe: Option<bool>,
+    ppuctrl: u8,
+    oam: [u8; OAM_SIZE],
+    changes: Vec<ScrollChange>,
+    // framebuffer, then the three layers
+    planes: [Vec<u8>; 4],
+}
+
+impl TraceFrame {
+    fn new(w: usize, h: usize) -> Self {
+        Self {
+            input: [Buttons::new(), Buttons::new()],
+            reset: false,
+            probe: None,
+            ppuctrl: 0,
+            oam: [0; OAM_SIZE],
+            changes: vec![],
+            planes: [
+                vec![0; w * h],
+                vec![0; w * h],
+                vec![0; w * h],
+                vec![0; w * h],
+            ],
+        }
+    }
+}
+
+fn buttons_to_byte(b: Buttons) -> u8 {
+    [
+        b.get_right(),
+        b.get_left(),
+        b.get_down(),
+        b.get_up(),
+        b.get_start(),
+        b.get_select(),
+        b.get_b(),
+        b.get_a(),
+    ]
+    .iter()
+    .fold(0, |acc, &bit| (acc << 1) | u8::from(bit))
+}
+fn buttons_from_byte(byte: u8) -> Buttons {
+    let mut bs = [false; 8];
+    for (i, b) in bs.iter_mut().enumerate() {
+        *b = byte & (0x80 >> i) != 0;
+    }
+    crate::from_bitstring(bs)
+}
+
+fn reason_to_byte(reason: ScrollChangeReason) -> u8 {
+    match reason {
+        ScrollChangeReason::Write2005 => 0,
+        ScrollChangeReason::Write2006 => 1,
+        ScrollChangeReason::Read2002 => 2,
+    }
+}
+fn reason_from_byte(byte: u8) -> io::Result<ScrollChangeReason> {
+    match byte {
+        0 => Ok(ScrollChangeReason::Write2005),
+        1 => Ok(ScrollChangeReason::Write2006),
+        2 => Ok(ScrollChangeReason::Read2002),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown scroll change reason {byte}"),
+        )),
+    }
+}
+
+/// Wraps a [`FrameSource`] and writes a trace of everything mappy reads from
+/// it, including the outcome of control probes, so the same mapping can later
+/// be re-run with a [`TracePlayer`] and no emulator.
+pub struct TraceRecorder<S: FrameSource, W: Write> {
+    source: S,
+    out: ZlibEncoder<W>,
+    frame: TraceFrame,
+    prev_planes: [Vec<u8>; 4],
+    frame_count: usize,
+}
+
+impl<S: FrameSource, W: Write> TraceRecorder<S, W> {
+    /// # Errors
+    /// Returns an error if the trace header can't be written
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn new(source: S, mut out: W, rom: Option<&RomHash>) -> io::Result<Self> {
+        let (w, h) = source.framebuffer_size();
+        out.write_all(TRACE_MAGIC)?;
+        out.write_all(&TRACE_VERSION.to_le_bytes())?;
+        out.write_all(&(w as u32).to_le_bytes())?;
+        out.write_all(&(h as u32).to_le_bytes())?;
+        match rom {
+            Some(hash) => {
+                out.write_all(&[1])?;
+                out.write_all(&hash.crc32.to_le_bytes())?;
+                out.write_all(&hash.md5)?;
+            }
+            None => out.write_all(&[0; 21])?,
+        }
+        let frame = TraceFrame::new(w, h);
+        Ok(Self {
+            source,
+            out: ZlibEncoder::new(out, Compression::fast()),
+            prev_planes: frame.planes.clone(),
+            frame,
+            frame_count: 0,
+        })
+    }
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+    pub fn source_mut(&mut self) -> &mut S {
+        &mut self.source
+    }
+    #[must_use]
+    pub fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+    /// Marks the next recorded frame as following a reset, and resets `mappy`.
+    /// Resetting the source itself is up to the caller.
+    pub fn handle_reset(&mut self, mappy: &mut MappyState) {
+        mappy.handle_reset();
+        self.frame.reset = true;
+    }
+    /// Runs the source for one frame with `input`, lets `mappy` process it,
+    /// and writes the frame to the trace.
+    /// # Errors
+    /// Returns an error if the source can't be read, mappy fails, or the
+    /// frame can't be written
+    pub fn step(&mut self, mappy: &mut MappyState, input: [Buttons; 2]) -> Result<(), MappyError> {
+        self.source.run(input);
+        self.frame.input = input;
+        self.frame.probe = None;
+        self.frame.ppuctrl = self.source.ppuctrl()?;
+        self.frame
+            .oam
+            .copy_from_slice(&self.source.oam()[0..OAM_SIZE]);
+        self.source.scroll_changes(&mut self.frame.changes)?;
+        self.source
+            .copy_framebuffer_rgb332(&mut self.frame.planes[0])?;
+        for (plane, layer) in self.frame.planes[1..].iter_mut().zip(self.source.layers()?) {
+            plane.copy_from_slice(layer);
+        }
+        mappy.process_screen(self, input)?;
+        Ok(self.write_frame()?)
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    fn write_frame(&mut self) -> io::Result<()> {
+        let frame = &mut self.frame;
+        let mut flags = 0;
+        if frame.reset {
+            flags |= FLAG_RESET;
+        }
+        if let Some(responded) = frame.probe {
+            flags |= FLAG_PROBED;
+            if responded {
+                flags |= FLAG_RESPONDED;
+            }
+        }
+        self.out.write_all(&[
+            flags,
+            buttons_to_byte(frame.input[0]),
+            buttons_to_byte(frame.input[1]),
+            frame.ppuctrl,
+        ])?;
+        self.out.write_all(&frame.oam)?;
+        self.out
+            .write_all(&(frame.changes.len() as u32).to_le_bytes())?;
+        for change in &frame.changes {
+            self.out
+                .write_all(&[reason_to_byte(change.reason), change.scanline, change.value])?;
+        }
+        let mut delta = vec![0; frame.planes[0].len()];
+        for (plane, prev) in frame.planes.iter().zip(self.prev_planes.iter_mut()) {
+            for ((d, p), q) in delta.iter_mut().zip(plane.iter()).zip(prev.iter()) {
+                *d = p ^ q;
+            }
+            self.out.write_all(&delta)?;
+            prev.copy_from_slice(plane);
+        }
+        frame.reset = false;
+        self.frame_count += 1;
+        Ok(())
+    }
+    /// Flushes the trace and gives back the source and writer.
+    /// # Errors
+    /// Returns an error if the compressed stream can't be finished
+    pub fn finish(self) -> io::Result<(S, W)> {
+        Ok((self.source, self.out.finish()?))
+    }
+}
+
+/// While recording, mappy sees the recorder as its frame source.
+impl<S: FrameSource, W: Write> FrameSource for TraceRecorder<S, W> {
+    fn run(&mut self, inputs: [Buttons; 2]) {
+        self.source.run(inputs);
+    }
+    fn framebuffer_size(&self) -> (usize, usize) {
+        self.source.framebuffer_size()
+    }
+    fn copy_framebuffer_rgb332(&self, buf: &mut [u8]) -> Result<(), MappyError> {
+        self.source.copy_framebuffer_rgb332(buf)
+    }
+    fn copy_framebuffer_rgb888(&self, buf: &mut [u8]) -> Result<(), MappyError> {
+        self.source.copy_framebuffer_rgb888(buf)
+    }
+    fn layers(&self) -> Result<[&[u8]; 3], MappyError> {
+        self.source.layers()
+    }
+    fn scroll_changes(&self, changes: &mut Vec<ScrollChange>) -> Result<(), MappyError> {
+        self.source.scroll_changes(changes)
+    }
+    fn oam(&self) -> &[u8] {
+        self.source.oam()
+    }
+    fn ppuctrl(&self) -> Result<u8, MappyError> {
+        self.source.ppuctrl()
+    }
+    fn ram(&self) -> Result<&[u8], MappyError> {
+        self.source.ram()
+    }
+    fn save_size(&self) -> usize {
+        self.source.save_size()
+    }
+    fn save(&self, buf: &mut [u8]) -> bool {
+        self.source.save(buf)
+    }
+    fn load(&mut self, buf: &[u8]) -> bool {
+        self.source.load(buf)
+    }
+    fn recorded_probe(&self) -> Option<bool> {
+        self.source.recorded_probe()
+    }
+    fn report_probe(&mut self, responded: bool) {
+        self.frame.probe = Some(responded);
+        self.source.report_probe(responded);
+    }
+}
+
+/// Plays back a trace written by a [`TraceRecorder`].
+///
+/// The player can't save or load, so it answers control probes from the
+/// trace; replays should use the same control check settings as the
+/// recording did.
+pub struct TracePlayer<R: Read> {
+    input: ZlibDecoder<R>,
+    w: usize,
+    h: usize,
+    rom: Option<RomHash>,
+    frame: TraceFrame,
+    delta: Vec<u8>,
+    // What went wrong reading the trace in FrameSource::run, which can't
+    // say so itself
+    error: Option<io::Error>,
+}
+
+impl<R: Read> TracePlayer<R> {
+    /// # Errors
+    /// Returns an error if the header can't be read or isn't a supported trace
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut header = [0_u8; 8 + 2 + 4 + 4 + 21];
+        input.read_exact(&mut header)?;
+        if &header[0..8] != TRACE_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a mappy trace",
+            ));
+        }
+        let version = u16::from_le_bytes([header[8], header[9]]);
+        if version != TRACE_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported trace version {version}"),
+            ));
+        }
+        let w = u32::from_le_bytes(header[10..14].try_into().unwrap()) as usize;
+        let h = u32::from_le_bytes(header[14..18].try_into().unwrap()) as usize;
+        let rom = (header[18] != 0).then(|| RomHash {
+            crc32: u32::from_le_bytes(header[19..23].try_into().unwrap()),
+            md5: header[23..39].try_into().unwrap(),
+        });
+        Ok(Self {
+            input: ZlibDecoder::new(input),
+            w,
+            h,
+            rom,
+            frame: TraceFrame::new(w, h),
+            delta: vec![0; w * h],
+            error: None,
+        })
+    }
+    /// The hash of the ROM the trace was recorded from, if the recorder knew it.
+    #[must_use]
+    pub fn rom_hash(&self) -> Option<&RomHash> {
+        self.rom.as_ref()
+    }
+    /// The first error [`FrameSource::run`] ran into reading the trace
+    /// (including running past its end) since the last call, if any.
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+    /// Reads the next frame, returning its input pair and whether a reset
+    /// preceded it, or `None` at the end of the trace.
+    /// # Errors
+    /// Returns an error if the trace is corrupt or truncated
+    pub fn advance(&mut self) -> io::Result<Option<([Buttons; 2], bool)>> {
+        let mut head = [0_u8; 4];
+        match self.input.read_exact(&mut head) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let [flags, p1, p2, ppuctrl] = head;
+        let frame = &mut self.frame;
+        frame.input = [buttons_from_byte(p1), buttons_from_byte(p2)];
+        frame.reset = flags & FLAG_RESET != 0;
+        frame.probe = (flags & FLAG_PROBED != 0).then_some(flags & FLAG_RESPONDED != 0);
+        frame.ppuctrl = ppuctrl;
+        self.input.read_exact(&mut frame.oam)?;
+        let mut count = [0_u8; 4];
+        self.input.read_exact(&mut count)?;
+        frame.changes.clear();
+        for _ in 0..u32::from_le_bytes(count) {
+            let mut change = [0_u8; 3];
+            self.input.read_exact(&mut change)?;
+            frame.changes.push(ScrollChange {
+                reason: reason_from_byte(change[0])?,
+                scanline: change[1],
+                value: change[2],
+            });
+        }
+        for plane in &mut frame.planes {
+            self.input.read_exact(&mut self.delta)?;
+            for (p, d) in plane.iter_mut().zip(self.delta.iter()) {
+                *p ^= d;
+            }
+        }
+        Ok(Some((frame.input, frame.reset)))
+    }
+    /// Feeds every remaining frame of the trace through `mappy`, returning
+    /// the number of frames played.
+    /// # Errors
+    /// Returns an error if the trace is corrupt or truncated
+    pub fn replay(&mut self, mappy: &mut MappyState) -> Result<usize, MappyError> {
+        let mut frames = 0;
+        while let Some((input, reset)) = self.advance()? {
+            if reset {
+                mappy.handle_reset();
+            }
+            mappy.process_screen(self, input)?;
+            if let Some(e) = self.take_error() {
+                return Err(e.into());
+            }
+            frames += 1;
+        }
+        Ok(frames)
+    }
+}
+
+impl<R: Read> FrameSource for TracePlayer<R> {
+    /// Moves on to the next recorded frame; `inputs` are ignored since the
+    /// trace already says what happened.
+    fn run(&mut self, _inputs: [Buttons; 2]) {
+        let error = match self.advance() {
+            Ok(Some(_)) => return,
+            Ok(None) => io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "ran past the end of the trace",
+            ),
+            Err(e) => e,
+        };
+        self.error.get_or_insert(error);
+    }
+    fn framebuffer_size(&self) -> (usize, usize) {
+        (self.w, self.h)
+    }
+    fn copy_framebuffer_rgb332(&self, buf: &mut [u8]) -> Result<(), MappyError> {
+        buf.copy_from_slice(&self.frame.planes[0]);
+        Ok(())
+    }
+    fn layers(&self) -> Result<[&[u8]; 3], MappyError> {
+        Ok([
+            &self.frame.planes[1],
+            &self.frame.planes[2],
+            &self.frame.planes[3],
+        ])
+    }
+    fn scroll_changes(&self, changes: &mut Vec<ScrollChange>) -> Result<(), MappyError> {
+        changes.clear();
+        changes.extend_from_slice(&self.frame.changes);
+        Ok(())
+    }
+    fn oam(&self) -> &[u8] {
+        &self.frame.oam
+    }
+    fn ppuctrl(&self) -> Result<u8, MappyError> {
+        Ok(self.frame.ppuctrl)
+    }
+    fn save_size(&self) -> usize {
+        0
+    }
+    fn save(&self, _buf: &mut [u8]) -> bool {
+        false
+    }
+    fn load(&mut self, _buf: &[u8]) -> bool {
+        false
+    }
+    fn recorded_probe(&self) -> Option<bool> {
+        self.frame.probe
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A "game" whose background scrolls right one pixel per frame and whose
+    /// single sprite follows the input.
+    struct Scroller {
+        t: usize,
+        x: u8,
+        fb: Vec<u8>,
+        oam: [u8; OAM_SIZE],
+    }
+    impl Scroller {
+        fn new() -> Self {
+            let mut s = Self {
+                t: 0,
+                x: 100,
+                fb: vec![0; 256 * 240],
+                oam: [0xFF; OAM_SIZE],
+            };
+            s.draw();
+            s
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        fn draw(&mut self) {
+            for (i, px) in self.fb.iter_mut().enumerate() {
+                let (x, y) = (i % 256 + self.t, i / 256);
+                *px = ((x / 8 + y / 8) % 5) as u8;
+            }
+            self.oam[0..4].copy_from_slice(&[120, 1, 0, self.x]);
+        }
+    }
+    impl FrameSource for Scroller {
+        fn run(&mut self, inputs: [Buttons; 2]) {
+            self.t += 1;
+            if inputs[0].get_right() {
+                self.x = self.x.wrapping_add(1);
+            }
+            if inputs[0].get_left() {
+                self.x = self.x.wrapping_sub(1);
+            }
+            self.draw();
+        }
+        fn framebuffer_size(&self) -> (usize, usize) {
+            (256, 240)
+        }
+        fn copy_framebuffer_rgb332(&self, buf: &mut [u8]) -> Result<(), MappyError> {
+            buf.copy_from_slice(&self.fb);
+            Ok(())
+        }
+        fn layers(&self) -> Result<[&[u8]; 3], MappyError> {
+            Ok([&self.fb, &self.fb, &self.fb])
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        fn scroll_changes(&self, changes: &mut Vec<ScrollChange>) -> Result<(), MappyError> {
+            changes.clear();
+            for value in [(self.t % 256) as u8, 0] {
+                changes.push(ScrollChange {
+                    reason: ScrollChangeReason::Write2005,
+                    scanline: 0,
+                    value,
+                });
+            }
+            Ok(())
+        }
+        fn oam(&self) -> &[u8] {
+            &self.oam
+        }
+        fn ppuctrl(&self) -> Result<u8, MappyError> {
+            Ok(0)
+        }
+        fn save_size(&self) -> usize {
+            2
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        fn save(&self, buf: &mut [u8]) -> bool {
+            buf.copy_from_slice(&[self.x, self.t as u8]);
+            true
+        }
+        fn load(&mut self, buf: &[u8]) -> bool {
+            self.x = buf[0];
+            self.t = usize::from(buf[1]);
+            self.draw();
+            true
+        }
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let mut game = Scroller::new();
+        let mut live = MappyState::new(256, 240);
+        let mut rec = TraceRecorder::new(&mut game, vec![], None).unwrap();
+        let right = Buttons::new().right(true);
+        for i in 0..40 {
+            if i == 20 {
+                rec.handle_reset(&mut live);
+            }
+            rec.step(&mut live, [right, Buttons::new()]).unwrap();
+        }
+        assert_eq!(rec.frame_count(), 40);
+        let (_, bytes) = rec.finish().unwrap();
+
+        let mut replayed = MappyState::new(256, 240);
+        let mut player = TracePlayer::new(&bytes[..]).unwrap();
+        assert_eq!(player.rom_hash(), None);
+        assert_eq!(player.replay(&mut replayed).unwrap(), 40);
+        assert_eq!(live.now, replayed.now);
+        assert_eq!(live.scroll, replayed.scroll);
+        assert_eq!(live.has_control, replayed.has_control);
+        assert_eq!(live.last_control, replayed.last_control);
+        assert!(live.live_sprites == replayed.live_sprites);
+        // Running past the end is reported rather than a panic
+        player.run([right, Buttons::new()]);
+        assert_eq!(
+            player.take_error().map(|e| e.kind()),
+            Some(io::ErrorKind::UnexpectedEof)
+        );
+        assert!(player.take_error().is_none());
+        // And so is a trace cut off partway through a frame
+        let mut player = TracePlayer::new(&bytes[..bytes.len() / 2]).unwrap();
+        assert!(player.replay(&mut MappyState::new(256, 240)).is_err());
+    }
+}
@@ -25,7 +25,9 @@ fn replay(emu: &mut Emulator, mappy: &mut MappyState, inputs: &[[Buttons; 2]]) {
     // let start = Instant::now();
     for inp in inputs {
         emu.run(*inp);
-        mappy.process_screen(emu, *inp);
+        mappy
+            .process_screen(emu, *inp)
+            .expect("Couldn't process frame");
     }
 }
 
@@ -53,6 +55,7 @@ async fn main() {
         .join(Path::new(&romname))
         .join(Path::new(&(date_str + ".csv")));
     let mut csv = std::fs::File::create(csv_path).expect("Couldn't create CSV file");
+    let rom_hash = mappy::profile::RomHash::from_file(romfile).ok();
     let mut emu = Emulator::create(Path::new("cores/fceumm_libretro"), Path::new(romfile));
     // Have to run emu for one frame before we can get the framebuffer size
     emu.run([Buttons::new(), Buttons::new()]);
@@ -79,7 +82,8 @@ async fn main() {
     let args: Vec<_> = env::args().collect();
     let mut mappy = MappyState::new(w, h);
     if args.len() > 1 {
-        mappy::read_fm2(&mut replay_inputs, Path::new(&args[1]));
+        mappy::read_fm2(&mut replay_inputs, Path::new(&args[1]))
+            .expect("Couldn't read replay file");
         replay(&mut emu, &mut mappy, &replay_inputs);
         inputs.append(&mut replay_inputs);
     }
@@ -174,7 +178,14 @@ async fn main() {
                 n
             ));
             if shifted {
-                mappy::write_fm2(&inputs, &path);
+                if let Err(e) = mappy::write_fm2(
+                    &inputs,
+                    romname.to_str().expect("rom name not a valid utf-8 string"),
+                    rom_hash.as_ref(),
+                    &path,
+                ) {
+                    println!("Couldn't dump {n}: {e}");
+                }
             // println!("Dumped {}", n);
             } else {
                 // TODO clear mappy too?
@@ -183,7 +194,9 @@ async fn main() {
                 frame_counter = 0;
                 inputs.clear();
                 replay_inputs.clear();
-                mappy::read_fm2(&mut replay_inputs, &path);
+                if let Err(e) = mappy::read_fm2(&mut replay_inputs, &path) {
+                    println!("Couldn't load {n}: {e}");
+                }
                 replay_index = 0;
             }
         }
@@ -236,7 +249,9 @@ async fn main() {
                 emu.copy_framebuffer_rgba8888(&mut fb)
                     .expect("Couldn't copy emulator framebuffer");
             }
-            mappy.process_screen(&mut emu, inputs.last().copied().unwrap());
+            mappy
+                .process_screen(&mut emu, inputs.last().copied().unwrap())
+                .expect("Couldn't process frame");
             frame_counter += 1;
             if frame_counter % OUTPUT_INTERVAL == 0 {
                 let fb_out = emu.create_imagebuffer();
@@ -376,6 +391,6 @@ async fn main() {
         //     ::std::thread::sleep(frame_interval - elapsed);
         // }
     }
-    mappy.finish();
+    let _map = mappy.finish();
     //mappy.dump_tiles(Path::new("out/"));
 }
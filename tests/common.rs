@@ -1,23 +1,37 @@
-use mappy::MappyState;
+use mappy::config::MappyConfig;
+use mappy::{FinalizedMap, MappyState};
 use retro_rs::Emulator;
 use std::path::Path;
 
 #[allow(unused)]
-pub fn run(rom: &Path, fm2s: &[&Path]) -> MappyState {
-    run_probes(rom, fm2s, &[])
+pub fn run(rom: &Path, fm2s: &[&Path]) -> FinalizedMap {
+    run_probes(rom, fm2s, &[]).finish()
 }
+// Leaves the session unfinished so callers can still look at live state
 pub fn run_probes(rom: &Path, fm2s: &[&Path], probes: &[(usize,Box<dyn Fn(&MappyState) -> ()>)]) -> MappyState {
+    run_session(rom, fm2s, probes, MappyConfig::default())
+}
+#[allow(unused)]
+pub fn run_config(rom: &Path, fm2s: &[&Path], config: MappyConfig) -> FinalizedMap {
+    run_session(rom, fm2s, &[], config).finish()
+}
+fn run_session(
+    rom: &Path,
+    fm2s: &[&Path],
+    probes: &[(usize, Box<dyn Fn(&MappyState) -> ()>)],
+    config: MappyConfig,
+) -> MappyState {
     let mut emu = Emulator::create(Path::new("cores/fceumm_libretro"), rom);
     let mut start_state = vec![0; emu.save_size()];
     emu.save(&mut start_state);
-    let mut mappy = MappyState::new(256, 240);
+    let mut mappy = MappyState::builder(256, 240).config(config).build();
     let mut t = 0;
     let mut probe = 0;
     for fm2 in fm2s {
         emu.load(&start_state);
         mappy.handle_reset();
         let mut inputs = vec![];
-        mappy::read_fm2(&mut inputs, fm2);
+        mappy::read_fm2(&mut inputs, fm2).unwrap();
         for (_i, input_pair) in inputs.iter().enumerate() {
             if probe < probes.len() && probes[probe].0 <= t {
                 dbg!(probes[probe].0, t);
@@ -25,17 +39,16 @@ pub fn run_probes(rom: &Path, fm2s: &[&Path], probes: &[(usize,Box<dyn Fn(&Mappy
                 probe += 1;
             }
             emu.run(*input_pair);
-            mappy.process_screen(&mut emu, *input_pair);
+            mappy.process_screen(&mut emu, *input_pair).unwrap();
             t += 1;
         }
     }
-    mappy.finish();
     mappy
 }
 #[allow(unused)]
-pub fn print_testcase(mappy: &MappyState) {
-    let rooms = mappy.rooms.read().unwrap();
-    let metarooms: Vec<_> = mappy.metarooms.metarooms().collect();
+pub fn print_testcase(map: &FinalizedMap) {
+    let rooms = map.rooms();
+    let metarooms: Vec<_> = map.metarooms().collect();
     println!("assert_eq!(rooms.len(), {:?});", rooms.len());
     println!("assert_eq!(metarooms.len(), {:?});", metarooms.len());
     for (mi, m) in metarooms.iter().enumerate() {
@@ -44,9 +57,9 @@ pub fn print_testcase(mappy: &MappyState) {
             mi, m.registrations
         );
         println!(
-            "assert_eq!(mappy.metaroom_exits(&metarooms[{}]), {:?});",
+            "assert_eq!(map.metaroom_exits(&metarooms[{}]), {:?});",
             mi,
-            mappy.metaroom_exits(m)
+            map.metaroom_exits(m)
         );
     }
 }
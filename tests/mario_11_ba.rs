@@ -3,15 +3,15 @@ mod common;
 use common::run;
 #[test]
 fn test_mario_11_ba() {
-    let mappy = run(
+    let map = run(
         Path::new("roms/mario.nes"),
         &[
             Path::new("tests/data/mario_11_b.fm2"),
             Path::new("tests/data/mario_11_a.fm2"),
         ],
     );
-    let rooms = mappy.rooms.read().unwrap();
-    let metarooms: Vec<_> = mappy.metarooms.metarooms().collect();
+    let rooms = map.rooms();
+    let metarooms: Vec<_> = map.metarooms().collect();
     assert_eq!(rooms.len(), 4);
     assert_eq!(metarooms.len(), 2);
     assert_eq!(metarooms[0].registrations, vec![(2, (0, 0)),]);
@@ -19,8 +19,8 @@ fn test_mario_11_ba() {
         metarooms[1].registrations,
         vec![(3, (0, 0)), (1, (-320, 0)), (0, (-320, 0)),]
     );
-    let exits1 = mappy.metaroom_exits(metarooms[0]);
+    let exits1 = map.metaroom_exits(metarooms[0]);
     assert_eq!(exits1, vec![metarooms[1].id]);
-    let exits2 = mappy.metaroom_exits(metarooms[1]);
+    let exits2 = map.metaroom_exits(metarooms[1]);
     assert_eq!(exits2, vec![metarooms[0].id]);
 }
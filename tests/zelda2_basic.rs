@@ -3,13 +3,13 @@ mod common;
 use common::run;
 #[test]
 fn test_zelda2_basic() {
-    let mappy = run(
+    let map = run(
         Path::new("roms/zelda2.nes"),
         &[Path::new("tests/data/zelda2_basic.fm2")],
     );
-    let rooms = mappy.rooms.read().unwrap();
+    let rooms = map.rooms();
     assert_eq!(rooms.len(), 9);
-    let metarooms: Vec<_> = mappy.metarooms.metarooms().collect();
+    let metarooms: Vec<_> = map.metarooms().collect();
     assert_eq!(metarooms.len(), 8);
     // once it's basically working: common::print_testcase(...); panic!();
 }